@@ -0,0 +1,297 @@
+//! Reassembly of frames from a byte stream.
+//!
+//! UART drivers hand out bytes one at a time or in arbitrary DMA sized chunks.
+//! [`FrameAccumulator`] buffers them until the `0x00` delimiter and decodes the completed frame.
+
+use core::fmt;
+
+use defmt::Format;
+
+use crate::Message;
+use crate::frame::{self, Checksum, EMERGENCY_STOP_FRAME};
+
+#[derive(Clone, Format, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame did not fit in the accumulator buffer and was discarded
+    Oversized,
+    /// The frame was complete but could not be decoded, e.g. garbage or a bad checksum
+    Decode(postcard::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Oversized => write!(f, "frame exceeds the receive buffer"),
+            FrameError::Decode(err) => write!(f, "failed to decode frame: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+/// Counters kept by a [`FrameAccumulator`]
+#[derive(Clone, Copy, Format, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames decoded successfully
+    pub decoded: u32,
+    /// Complete frames that failed to decode
+    pub dropped: u32,
+    /// Frames discarded because they exceeded the buffer
+    pub oversized: u32,
+}
+
+/// Accumulates bytes into frames of at most `N` encoded bytes (excluding the delimiter) and decodes
+/// them into [`Message`]s.
+///
+/// Any frame that fails to decode or overflows the buffer is dropped and counted, the accumulator
+/// picks up again at the next delimiter. This also resynchronises on the first delimiter after
+/// starting to listen mid frame.
+///
+/// The [`EMERGENCY_STOP_FRAME`] is recognised by its last bytes, so an emergency stop that cuts off
+/// a partially received or oversized frame is still yielded as [`Message::EmergencyStop`].
+pub struct FrameAccumulator<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Last bytes received, independent of `buf` so it also works on overflow
+    tail: [u8; EMERGENCY_STOP_FRAME.len() - 1],
+    overflowed: bool,
+    checksum: Checksum,
+    stats: FrameStats,
+}
+
+impl<const N: usize> Default for FrameAccumulator<N> {
+    fn default() -> Self {
+        Self::new(Checksum::None)
+    }
+}
+
+impl<const N: usize> FrameAccumulator<N> {
+    pub const fn new(checksum: Checksum) -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            tail: [0; EMERGENCY_STOP_FRAME.len() - 1],
+            overflowed: false,
+            checksum,
+            stats: FrameStats {
+                decoded: 0,
+                dropped: 0,
+                oversized: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Discard any partially received frame, e.g. after a UART error
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.tail = [0; EMERGENCY_STOP_FRAME.len() - 1];
+        self.overflowed = false;
+    }
+
+    /// Feed a single byte, returns the decoded frame when `byte` completed one
+    pub fn feed_byte(&mut self, byte: u8) -> Option<Result<Message, FrameError>> {
+        if byte != 0 {
+            self.tail.rotate_left(1);
+            self.tail[self.tail.len() - 1] = byte;
+            if self.len < N {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        if frame::is_emergency_stop(&self.tail) {
+            // Whatever preceded the emergency stop in this frame was aborted by the sender
+            if self.overflowed || self.len > self.tail.len() {
+                self.stats.dropped = self.stats.dropped.wrapping_add(1);
+            }
+            self.reset();
+            self.stats.decoded = self.stats.decoded.wrapping_add(1);
+            return Some(Ok(Message::EmergencyStop));
+        }
+        if self.overflowed {
+            self.reset();
+            self.stats.oversized = self.stats.oversized.wrapping_add(1);
+            return Some(Err(FrameError::Oversized));
+        }
+        if self.len == 0 {
+            // Back to back delimiters, nothing to decode
+            return None;
+        }
+
+        let result = frame::decode(&mut self.buf[..self.len], self.checksum);
+        self.reset();
+        match result {
+            Ok(message) => {
+                self.stats.decoded = self.stats.decoded.wrapping_add(1);
+                Some(Ok(message))
+            }
+            Err(err) => {
+                self.stats.dropped = self.stats.dropped.wrapping_add(1);
+                Some(Err(FrameError::Decode(err)))
+            }
+        }
+    }
+
+    /// Feed a chunk of received bytes, the returned iterator yields every frame completed by them.
+    /// Bytes are only consumed as the iterator advances, use `.flatten()` to skip failed frames
+    pub fn feed<'a, 'b>(&'a mut self, bytes: &'b [u8]) -> Frames<'a, 'b, N> {
+        Frames {
+            accumulator: self,
+            bytes: bytes.iter(),
+        }
+    }
+}
+
+/// Iterator over the frames completed by a chunk of bytes, see [`FrameAccumulator::feed`]
+pub struct Frames<'a, 'b, const N: usize> {
+    accumulator: &'a mut FrameAccumulator<N>,
+    bytes: core::slice::Iter<'b, u8>,
+}
+
+impl<const N: usize> Iterator for Frames<'_, '_, N> {
+    type Item = Result<Message, FrameError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.bytes
+            .by_ref()
+            .find_map(|&byte| self.accumulator.feed_byte(byte))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{Heartbeat, MESSAGE_BYTES, serialize_message_checked};
+
+    fn heartbeat(counter: u32, checksum: Checksum) -> Vec<u8> {
+        let mut buf = [0; MESSAGE_BYTES];
+        let message = Message::Heartbeat(Heartbeat {
+            counter,
+            timestamp: 1_000 * counter as u64,
+        });
+        serialize_message_checked(message, checksum, &mut buf)
+            .unwrap()
+            .to_vec()
+    }
+
+    /// Heartbeat counters of the frames, failed frames are errors
+    fn counters(
+        frames: impl Iterator<Item = Result<Message, FrameError>>,
+    ) -> Vec<Result<u32, FrameError>> {
+        frames
+            .map(|frame| match frame? {
+                Message::Heartbeat(heartbeat) => Ok(heartbeat.counter),
+                message => panic!("unexpected {message:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reassembles_byte_by_byte() {
+        let mut accumulator = FrameAccumulator::<MESSAGE_BYTES>::new(Checksum::Crc16);
+        let frame = heartbeat(7, Checksum::Crc16);
+        let (last, rest) = frame.split_last().unwrap();
+        for byte in rest {
+            assert!(accumulator.feed_byte(*byte).is_none());
+        }
+        assert_eq!(counters(accumulator.feed_byte(*last).into_iter()), [Ok(7)]);
+        assert_eq!(accumulator.stats().decoded, 1);
+    }
+
+    #[test]
+    fn yields_every_frame_of_a_chunk() {
+        let mut accumulator = FrameAccumulator::<MESSAGE_BYTES>::default();
+        let stream: Vec<u8> = (1..=3).flat_map(|i| heartbeat(i, Checksum::None)).collect();
+        // Split inside the second frame
+        let (first, second) = stream.split_at(stream.len() / 2);
+        let mut received = counters(accumulator.feed(first));
+        received.extend(counters(accumulator.feed(second)));
+        assert_eq!(received, [Ok(1), Ok(2), Ok(3)]);
+    }
+
+    #[test]
+    fn resynchronises_after_starting_mid_frame() {
+        let mut accumulator = FrameAccumulator::<MESSAGE_BYTES>::new(Checksum::Crc32);
+        let first = heartbeat(1, Checksum::Crc32);
+        let mut stream = first[first.len() / 2..].to_vec();
+        stream.extend(heartbeat(2, Checksum::Crc32));
+        let received = counters(accumulator.feed(&stream));
+        assert!(matches!(received[..], [Err(FrameError::Decode(_)), Ok(2)]));
+        assert_eq!(
+            accumulator.stats(),
+            FrameStats {
+                decoded: 1,
+                dropped: 1,
+                oversized: 0
+            }
+        );
+    }
+
+    #[test]
+    fn drops_corrupted_frame() {
+        let mut accumulator = FrameAccumulator::<MESSAGE_BYTES>::new(Checksum::Crc16);
+        let mut stream = heartbeat(1, Checksum::Crc16);
+        // Counter 1 becomes 3, still a valid varint
+        stream[2] ^= 0x02;
+        stream.extend(heartbeat(2, Checksum::Crc16));
+        assert_eq!(
+            counters(accumulator.feed(&stream)),
+            [
+                Err(FrameError::Decode(postcard::Error::DeserializeBadCrc)),
+                Ok(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn discards_oversized_frame() {
+        let mut accumulator = FrameAccumulator::<8>::default();
+        let mut stream = [0xaa; 20].to_vec();
+        stream.push(0);
+        // Back to back delimiters are no frames
+        stream.extend([0, 0]);
+        assert_eq!(
+            counters(accumulator.feed(&stream)),
+            [Err(FrameError::Oversized)]
+        );
+        assert_eq!(accumulator.stats().oversized, 1);
+    }
+
+    #[test]
+    fn emergency_stop_cuts_off_partial_frame() {
+        let mut accumulator = FrameAccumulator::<MESSAGE_BYTES>::new(Checksum::Crc16);
+        let aborted = heartbeat(1, Checksum::Crc16);
+        let mut stream = aborted[..aborted.len() / 2].to_vec();
+        stream.extend(EMERGENCY_STOP_FRAME);
+        stream.extend(heartbeat(2, Checksum::Crc16));
+        let frames: Vec<_> = accumulator.feed(&stream).collect();
+        assert!(matches!(frames[0], Ok(Message::EmergencyStop)));
+        assert!(matches!(
+            frames[1],
+            Ok(Message::Heartbeat(Heartbeat { counter: 2, .. }))
+        ));
+        assert_eq!(frames.len(), 2);
+        // The aborted frame counts as dropped
+        assert_eq!(accumulator.stats().dropped, 1);
+    }
+
+    #[test]
+    fn emergency_stop_ends_oversized_frame() {
+        let mut accumulator = FrameAccumulator::<8>::default();
+        let mut stream = [0xaa; 20].to_vec();
+        stream.extend(EMERGENCY_STOP_FRAME);
+        let frames: Vec<_> = accumulator.feed(&stream).collect();
+        assert!(matches!(frames[..], [Ok(Message::EmergencyStop)]));
+    }
+}
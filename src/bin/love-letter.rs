@@ -0,0 +1,229 @@
+//! Command line tool to monitor a mockloop and send it setpoints and commands.
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use love_letter::session::{Session, SessionConfig, SessionError};
+use love_letter::units::{ComplianceUnit, ResistanceUnit};
+use love_letter::{
+    AppState, Channel, Checksum, Command, DeliveryError, HeartControllerSetpoint, MockloopSetpoint,
+    Reading, Report, Setpoint, SetpointBuilder, SetpointStatus,
+};
+use uom::si::f32::{Frequency, Pressure};
+use uom::si::frequency::hertz;
+use uom::si::pressure::millibar;
+
+#[derive(Parser)]
+#[command(name = "love-letter", version, about)]
+struct Cli {
+    /// Serial device or pty to connect to, e.g. /dev/ttyUSB0
+    #[arg(short, long, conflicts_with = "tcp", required_unless_present = "tcp")]
+    port: Option<String>,
+    /// Connect to a simulator listening on this address instead, e.g. localhost:5555
+    #[arg(long)]
+    tcp: Option<String>,
+    /// Frame checksum, must match the firmware
+    #[arg(long, value_enum, default_value_t = ChecksumArg::Crc16)]
+    checksum: ChecksumArg,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChecksumArg {
+    None,
+    Crc16,
+    Crc32,
+}
+
+impl From<ChecksumArg> for Checksum {
+    fn from(checksum: ChecksumArg) -> Self {
+        match checksum {
+            ChecksumArg::None => Checksum::None,
+            ChecksumArg::Crc16 => Checksum::Crc16,
+            ChecksumArg::Crc32 => Checksum::Crc32,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Print incoming reports
+    Monitor {
+        /// One JSON object per line, values in SI base units
+        #[arg(long)]
+        json: bool,
+        /// Stop after this many reports
+        #[arg(short = 'n', long)]
+        count: Option<usize>,
+    },
+    /// Send a setpoint and wait for it to be acknowledged
+    Setpoint(SetpointArgs),
+    /// Send an operator command to change the device state
+    Command {
+        #[arg(value_enum)]
+        command: CommandArg,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CommandArg {
+    Start,
+    Stop,
+    EmergencyStop,
+    ClearFault,
+    ResetToDefaults,
+}
+
+impl From<CommandArg> for Command {
+    fn from(command: CommandArg) -> Self {
+        match command {
+            CommandArg::Start => Command::Start,
+            CommandArg::Stop => Command::Stop,
+            CommandArg::EmergencyStop => Command::EmergencyStop,
+            CommandArg::ClearFault => Command::ClearFault,
+            CommandArg::ResetToDefaults => Command::ResetToDefaults,
+        }
+    }
+}
+
+#[derive(Args)]
+struct SetpointArgs {
+    /// JSON file holding a complete setpoint in SI base units, instead of the options below. It is
+    /// validated like the options
+    #[arg(short, long, exclusive = true)]
+    file: Option<PathBuf>,
+
+    /// Heart rate in Hz
+    #[arg(long, requires_all = ["pressure", "systole_ratio"])]
+    heart_rate: Option<f32>,
+    /// Regulator pressure in mbar
+    #[arg(long, requires_all = ["heart_rate", "systole_ratio"])]
+    pressure: Option<f32>,
+    /// Systole duration over the total cardiac phase duration
+    #[arg(long, requires_all = ["heart_rate", "pressure"])]
+    systole_ratio: Option<f32>,
+
+    /// Systemic resistance in Wood units
+    #[arg(long, requires_all = ["pulmonary_resistance", "systemic_compliance", "pulmonary_compliance"])]
+    systemic_resistance: Option<f32>,
+    /// Pulmonary resistance in Wood units
+    #[arg(long, requires = "systemic_resistance")]
+    pulmonary_resistance: Option<f32>,
+    /// Systemic afterload compliance in mL/mmHg
+    #[arg(long, requires = "systemic_resistance")]
+    systemic_compliance: Option<f32>,
+    /// Pulmonary afterload compliance in mL/mmHg
+    #[arg(long, requires = "systemic_resistance")]
+    pulmonary_compliance: Option<f32>,
+}
+
+impl SetpointArgs {
+    fn setpoint(&self) -> Result<Setpoint, Box<dyn Error>> {
+        let mut builder = SetpointBuilder::new();
+        if let Some(path) = &self.file {
+            let setpoint: Setpoint = serde_json::from_str(&fs::read_to_string(path)?)?;
+            if let Some(heart_controller) = setpoint.heart_controller_setpoint {
+                builder = builder.heart_controller(heart_controller);
+            }
+            if let Some(mockloop) = setpoint.mockloop_setpoint {
+                builder = builder.mockloop(mockloop);
+            }
+            return Ok(builder.build()?.into_inner());
+        }
+
+        if let (Some(heart_rate), Some(pressure), Some(systole_ratio)) =
+            (self.heart_rate, self.pressure, self.systole_ratio)
+        {
+            builder = builder.heart_controller(HeartControllerSetpoint {
+                heart_rate: Frequency::new::<hertz>(heart_rate),
+                pressure: Pressure::new::<millibar>(pressure),
+                systole_ratio,
+            });
+        }
+        if let (Some(systemic_r), Some(pulmonary_r), Some(systemic_c), Some(pulmonary_c)) = (
+            self.systemic_resistance,
+            self.pulmonary_resistance,
+            self.systemic_compliance,
+            self.pulmonary_compliance,
+        ) {
+            let compliance = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+            builder = builder.mockloop(MockloopSetpoint {
+                systemic_resistance: ResistanceUnit::WoodUnit.quantity(systemic_r),
+                pulmonary_resistance: ResistanceUnit::WoodUnit.quantity(pulmonary_r),
+                systemic_afterload_compliance: compliance.quantity(systemic_c),
+                pulmonary_afterload_compliance: compliance.quantity(pulmonary_c),
+            });
+        }
+        Ok(builder.build()?.into_inner())
+    }
+}
+
+fn print_report(report: &Report) {
+    let state = match &report.app_state {
+        AppState::StandBy => String::from("standby"),
+        AppState::Running(hz) => format!("running at {hz} Hz"),
+        AppState::Fault(fault) => format!("FAULT: {fault}"),
+    };
+    println!("[{} ms] {state}", report.measurements.timestamp);
+    for channel in Channel::ALL {
+        let value = report.measurements.value(channel);
+        println!("  {}", Reading { channel, value });
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let config = SessionConfig {
+        checksum: cli.checksum.into(),
+        ..SessionConfig::default()
+    };
+    let session = match (&cli.port, cli.tcp) {
+        (Some(port), _) => Session::open(port, config)?,
+        (None, Some(addr)) => Session::open_tcp(addr, config)?,
+        (None, None) => unreachable!("clap requires --port or --tcp"),
+    };
+    if let Some(peer) = session.peer() {
+        eprintln!(
+            "connected to device running love-letter {}",
+            peer.crate_version
+        );
+    }
+
+    match cli.action {
+        Action::Monitor { json, count } => {
+            for report in session.reports().take(count.unwrap_or(usize::MAX)) {
+                match json {
+                    true => println!("{}", serde_json::to_string(&report)?),
+                    false => print_report(&report),
+                }
+            }
+        }
+        Action::Setpoint(args) => {
+            let ack = match session.send_setpoint(args.setpoint()?) {
+                Ok(ack) => ack,
+                Err(SessionError::Delivery(DeliveryError::Rejected { seq, err })) => {
+                    return Err(format!("setpoint #{seq} rejected: {err}").into());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            match ack.status {
+                SetpointStatus::Clamped => eprintln!("setpoint #{} clamped to limits", ack.seq),
+                // A rejection comes back as `DeliveryError::Rejected`
+                _ => eprintln!("setpoint #{} accepted", ack.seq),
+            }
+            println!("{}", serde_json::to_string_pretty(&ack.applied)?);
+        }
+        Action::Command { command } => {
+            let command = Command::from(command);
+            let ack = session.command(command)?;
+            match ack.accepted {
+                true => eprintln!("{command} accepted, state: {:?}", ack.state),
+                false => return Err(format!("{command} refused in state {:?}", ack.state).into()),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! Explicit operator commands, separate from parameter [`Setpoint`](crate::Setpoint)s.
+//!
+//! A `None` field in a setpoint only means "leave this controller unchanged", starting and stopping
+//! is done with a [`Command`]. The device answers every command with a [`CommandAck`].
+
+use core::fmt;
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{Event, StateMachine};
+use crate::{AppState, Fault, FaultKind};
+
+#[derive(PartialEq, Eq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub enum Command {
+    /// Start pumping with the active heart controller setpoint, only allowed in standby
+    Start,
+    /// Stop pumping and go to standby, only allowed while running
+    Stop,
+    /// Stop pumping immediately from any state. Latches a [`FaultKind::EmergencyStop`] fault that
+    /// must be cleared before starting again
+    EmergencyStop,
+    /// Acknowledge a fault and return to standby, only allowed while faulted
+    ClearFault,
+    /// Discard the active setpoints and restore the power-on defaults, only allowed in standby
+    ResetToDefaults,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Command::Start => "start",
+            Command::Stop => "stop",
+            Command::EmergencyStop => "emergency stop",
+            Command::ClearFault => "clear fault",
+            Command::ResetToDefaults => "reset to defaults",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Device answer to a [`Command`]
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub struct CommandAck {
+    pub command: Command,
+    /// Whether the command was executed, rejected commands leave the state untouched
+    pub accepted: bool,
+    /// State after handling the command
+    pub state: AppState,
+}
+
+impl StateMachine {
+    /// Device side: execute `command` and build its acknowledgement.
+    ///
+    /// `heart_rate_hz` is the rate to report in [`AppState::Running`] when starting, `now_ms` the
+    /// milliseconds since boot used to timestamp an emergency stop
+    pub fn handle_command(
+        &mut self,
+        command: Command,
+        heart_rate_hz: u32,
+        now_ms: u64,
+    ) -> CommandAck {
+        let event = match command {
+            Command::Start => Event::Start(heart_rate_hz),
+            Command::Stop => Event::Stop,
+            Command::EmergencyStop => Event::Fault(Fault {
+                kind: FaultKind::EmergencyStop,
+                timestamp: now_ms,
+                reading: None,
+            }),
+            Command::ClearFault => Event::ClearFault,
+            Command::ResetToDefaults => Event::ResetToDefaults,
+        };
+        let accepted = self.handle(event).is_ok();
+
+        CommandAck {
+            command,
+            accepted,
+            state: self.state(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Event;
+
+    const NOW_MS: u64 = 5_000;
+
+    fn fault() -> Fault {
+        Fault {
+            kind: FaultKind::OverPressure,
+            timestamp: 1_000,
+            reading: None,
+        }
+    }
+
+    fn emergency_stop() -> Fault {
+        Fault {
+            kind: FaultKind::EmergencyStop,
+            timestamp: NOW_MS,
+            reading: None,
+        }
+    }
+
+    fn machine_in(state: AppState) -> StateMachine {
+        let mut machine = StateMachine::new();
+        match state {
+            AppState::StandBy => {}
+            AppState::Running(hz) => {
+                machine.handle(Event::Start(hz)).unwrap();
+            }
+            AppState::Fault(fault) => {
+                machine.handle(Event::Fault(fault)).unwrap();
+            }
+        }
+        machine
+    }
+
+    #[test]
+    fn every_command_in_every_state() {
+        let standby = AppState::StandBy;
+        let running = AppState::Running(1);
+        let faulted = AppState::Fault(fault());
+        let stopped = AppState::Fault(emergency_stop());
+
+        // (state, command, accepted, state afterwards)
+        let cases = [
+            (standby, Command::Start, true, AppState::Running(2)),
+            (standby, Command::Stop, false, standby),
+            (standby, Command::EmergencyStop, true, stopped),
+            (standby, Command::ClearFault, false, standby),
+            (standby, Command::ResetToDefaults, true, standby),
+            (running, Command::Start, false, running),
+            (running, Command::Stop, true, standby),
+            (running, Command::EmergencyStop, true, stopped),
+            (running, Command::ClearFault, false, running),
+            (running, Command::ResetToDefaults, false, running),
+            (faulted, Command::Start, false, faulted),
+            (faulted, Command::Stop, false, faulted),
+            // The original fault is kept
+            (faulted, Command::EmergencyStop, true, faulted),
+            (faulted, Command::ClearFault, true, standby),
+            (faulted, Command::ResetToDefaults, false, faulted),
+        ];
+        for (state, command, accepted, after) in cases {
+            let mut machine = machine_in(state);
+            let ack = machine.handle_command(command, 2, NOW_MS);
+            assert_eq!(
+                ack,
+                CommandAck {
+                    command,
+                    accepted,
+                    state: after,
+                },
+                "{command} in {state:?}"
+            );
+            assert_eq!(machine.state(), after);
+        }
+    }
+
+    #[test]
+    fn emergency_stop_latches_until_cleared() {
+        let mut machine = machine_in(AppState::Running(1));
+        let ack = machine.handle_command(Command::EmergencyStop, 1, NOW_MS);
+        assert!(ack.accepted);
+        assert!(matches!(
+            ack.state,
+            AppState::Fault(Fault {
+                kind: FaultKind::EmergencyStop,
+                timestamp: NOW_MS,
+                reading: None,
+            })
+        ));
+
+        assert!(!machine.handle_command(Command::Start, 1, NOW_MS).accepted);
+        assert!(
+            machine
+                .handle_command(Command::ClearFault, 1, NOW_MS)
+                .accepted
+        );
+        assert!(machine.handle_command(Command::Start, 1, NOW_MS).accepted);
+    }
+
+    #[test]
+    fn reset_to_defaults_refused_while_running() {
+        let mut machine = machine_in(AppState::Running(1));
+        let ack = machine.handle_command(Command::ResetToDefaults, 1, NOW_MS);
+        assert!(!ack.accepted);
+        assert_eq!(ack.state, AppState::Running(1));
+    }
+}
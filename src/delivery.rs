@@ -0,0 +1,278 @@
+//! Acknowledged setpoint delivery.
+//!
+//! Every [`Setpoint`] carries a sequence number which the device echoes in a [`SetpointAck`],
+//! together with whether it was applied and the values it actually applied. On the host,
+//! [`SetpointSender`] assigns sequence numbers and retransmits a setpoint until it is acknowledged
+//! or runs out of retries.
+
+use core::fmt;
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::validation::{ValidatedSetpoint, ValidationError};
+use crate::{Message, Setpoint};
+
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub enum SetpointStatus {
+    /// Applied as requested
+    Accepted,
+    /// Applied after pulling out of range fields to their limits
+    Clamped,
+    /// Not applied, the previously active setpoint remains
+    Rejected(ValidationError),
+}
+
+/// Device answer to a [`Setpoint`]
+#[derive(PartialEq, Clone, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub struct SetpointAck {
+    /// Sequence number of the acknowledged setpoint
+    pub seq: u16,
+    pub status: SetpointStatus,
+    /// The setpoint active after handling the request
+    pub applied: Setpoint,
+}
+
+impl SetpointAck {
+    /// Device side: `requested` was applied as `applied`, which may differ when it was clamped
+    pub fn applied(requested: &Setpoint, applied: &ValidatedSetpoint) -> Self {
+        let status = match applied.get() == requested {
+            true => SetpointStatus::Accepted,
+            false => SetpointStatus::Clamped,
+        };
+        SetpointAck {
+            seq: requested.seq,
+            status,
+            applied: applied.get().clone(),
+        }
+    }
+
+    /// Device side: `requested` was refused, `active` remains in effect
+    pub fn rejected(requested: &Setpoint, err: ValidationError, active: Setpoint) -> Self {
+        SetpointAck {
+            seq: requested.seq,
+            status: SetpointStatus::Rejected(err),
+            applied: active,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Format, Debug)]
+pub enum DeliveryError {
+    /// No acknowledgement after all retries
+    TimedOut { seq: u16, attempts: u8 },
+    /// The device refused the setpoint
+    Rejected { seq: u16, err: ValidationError },
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::TimedOut { seq, attempts } => {
+                write!(
+                    f,
+                    "setpoint #{seq} not acknowledged after {attempts} attempts"
+                )
+            }
+            DeliveryError::Rejected { seq, err } => write!(f, "setpoint #{seq} rejected: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for DeliveryError {}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    setpoint: Setpoint,
+    sent_at_ms: u64,
+    attempts: u8,
+}
+
+/// Host side sender that retries a setpoint until it is acknowledged.
+///
+/// Only the most recent setpoint is tracked, sending a new one supersedes an unacknowledged one.
+/// Time is passed in by the caller as milliseconds from any monotonic clock.
+#[derive(Clone, Debug)]
+pub struct SetpointSender {
+    next_seq: u16,
+    pending: Option<Pending>,
+    timeout_ms: u64,
+    max_attempts: u8,
+}
+
+impl SetpointSender {
+    /// Retransmit after `timeout_ms` without acknowledgement, give up after `max_attempts` sends
+    pub const fn new(timeout_ms: u64, max_attempts: u8) -> Self {
+        Self {
+            next_seq: 0,
+            pending: None,
+            timeout_ms,
+            max_attempts,
+        }
+    }
+
+    /// Assign the next sequence number to `setpoint`, returns the message to send now
+    pub fn send(&mut self, mut setpoint: Setpoint, now_ms: u64) -> Message {
+        setpoint.seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.pending = Some(Pending {
+            setpoint: setpoint.clone(),
+            sent_at_ms: now_ms,
+            attempts: 1,
+        });
+        Message::Setpoint(setpoint)
+    }
+
+    /// Call periodically, returns the message to retransmit once the pending setpoint timed out
+    pub fn poll(&mut self, now_ms: u64) -> Result<Option<Message>, DeliveryError> {
+        let Some(pending) = &mut self.pending else {
+            return Ok(None);
+        };
+        if now_ms.saturating_sub(pending.sent_at_ms) < self.timeout_ms {
+            return Ok(None);
+        }
+        if pending.attempts >= self.max_attempts {
+            let err = DeliveryError::TimedOut {
+                seq: pending.setpoint.seq,
+                attempts: pending.attempts,
+            };
+            self.pending = None;
+            return Err(err);
+        }
+
+        pending.attempts += 1;
+        pending.sent_at_ms = now_ms;
+        Ok(Some(Message::Setpoint(pending.setpoint.clone())))
+    }
+
+    /// Process a received message, returns the acknowledgement of the pending setpoint if this was
+    /// it. Acknowledgements of superseded setpoints are ignored
+    pub fn handle(&mut self, message: &Message) -> Option<Result<SetpointAck, DeliveryError>> {
+        let Message::SetpointAck(ack) = message else {
+            return None;
+        };
+        if self.pending.as_ref()?.setpoint.seq != ack.seq {
+            return None;
+        }
+
+        self.pending = None;
+        Some(match ack.status {
+            SetpointStatus::Rejected(err) => Err(DeliveryError::Rejected { seq: ack.seq, err }),
+            SetpointStatus::Accepted | SetpointStatus::Clamped => Ok(ack.clone()),
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::SetpointLimits;
+    use crate::{HeartControllerSetpoint, SYSTOLE_RATIO_DEFAULT};
+    use uom::si::f32::{Frequency, Pressure};
+    use uom::si::frequency::hertz;
+    use uom::si::pressure::millibar;
+
+    fn setpoint(pressure: f32) -> Setpoint {
+        Setpoint {
+            heart_controller_setpoint: Some(HeartControllerSetpoint {
+                heart_rate: Frequency::new::<hertz>(1.0),
+                pressure: Pressure::new::<millibar>(pressure),
+                systole_ratio: SYSTOLE_RATIO_DEFAULT,
+            }),
+            ..Setpoint::default()
+        }
+    }
+
+    fn seq(message: &Message) -> u16 {
+        match message {
+            Message::Setpoint(setpoint) => setpoint.seq,
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    /// What a device applying `limits` answers to `message`
+    fn answer(message: &Message, limits: &SetpointLimits) -> Message {
+        let Message::Setpoint(requested) = message else {
+            panic!("unexpected {message:?}");
+        };
+        Message::SetpointAck(match limits.clamp(requested.clone()) {
+            Ok(applied) => SetpointAck::applied(requested, &applied),
+            Err(err) => SetpointAck::rejected(requested, err, Setpoint::default()),
+        })
+    }
+
+    #[test]
+    fn assigns_sequence_numbers() {
+        let mut sender = SetpointSender::new(100, 3);
+        assert_eq!(seq(&sender.send(setpoint(100.0), 0)), 0);
+        assert_eq!(seq(&sender.send(setpoint(100.0), 0)), 1);
+    }
+
+    #[test]
+    fn retries_until_timed_out() {
+        let mut sender = SetpointSender::new(100, 3);
+        let first = sender.send(setpoint(100.0), 0);
+        assert!(matches!(sender.poll(99), Ok(None)));
+        let retry = sender.poll(100).unwrap().unwrap();
+        assert_eq!(seq(&retry), seq(&first));
+        assert!(sender.poll(200).unwrap().is_some());
+        assert_eq!(
+            sender.poll(300).unwrap_err(),
+            DeliveryError::TimedOut {
+                seq: 0,
+                attempts: 3
+            }
+        );
+        assert!(!sender.is_pending());
+        assert!(matches!(sender.poll(400), Ok(None)));
+    }
+
+    #[test]
+    fn acknowledgement_ends_retries() {
+        let limits = SetpointLimits::default();
+        let mut sender = SetpointSender::new(100, 3);
+        let message = sender.send(setpoint(100.0), 0);
+        let ack = sender.handle(&answer(&message, &limits)).unwrap().unwrap();
+        assert_eq!(ack.status, SetpointStatus::Accepted);
+        assert!(!sender.is_pending());
+        assert!(matches!(sender.poll(1_000), Ok(None)));
+    }
+
+    #[test]
+    fn reports_clamped_and_rejected_setpoints() {
+        let limits = SetpointLimits::default();
+        let mut sender = SetpointSender::new(100, 3);
+
+        let message = sender.send(setpoint(2_000.0), 0);
+        let ack = sender.handle(&answer(&message, &limits)).unwrap().unwrap();
+        assert_eq!(ack.status, SetpointStatus::Clamped);
+        assert_eq!(
+            ack.applied,
+            Setpoint {
+                seq: ack.seq,
+                ..setpoint(1_000.0)
+            }
+        );
+
+        let message = sender.send(setpoint(f32::NAN), 0);
+        assert!(matches!(
+            sender.handle(&answer(&message, &limits)),
+            Some(Err(DeliveryError::Rejected { seq: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn ignores_acknowledgements_of_superseded_setpoints() {
+        let limits = SetpointLimits::default();
+        let mut sender = SetpointSender::new(100, 3);
+        let old = sender.send(setpoint(100.0), 0);
+        sender.send(setpoint(200.0), 0);
+        assert_eq!(sender.handle(&answer(&old, &limits)), None);
+        assert!(sender.is_pending());
+    }
+}
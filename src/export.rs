@@ -0,0 +1,798 @@
+//! Flat CSV and JSON Lines export of [`Report`]s, for spreadsheets and notebooks.
+//!
+//! Every report becomes one row: the time, the [`AppState`], the active [`Setpoint`] and every
+//! [`Measurements`] channel. Column names are stable, new columns are only ever appended, and do
+//! not depend on the selected [`Units`]: ask [`Exporter::columns`] for the unit of each column.
+//! Fields the report does not carry, e.g. a setpoint the device has not received, are empty in CSV
+//! and `null` in JSON, as are readings that are NaN.
+//!
+//! [`EdfWriter`] converts reports to the European Data Format instead, for EDF viewers and
+//! physiological signal toolkits, and [`WfdbWriter`] to a PhysioNet WFDB record.
+//!
+//! [`Measurements`]: crate::Measurements
+
+mod edf;
+mod wfdb;
+
+pub use edf::EdfWriter;
+pub use wfdb::{WfdbConfig, WfdbFiles, WfdbFormat, WfdbWriter};
+
+use std::fmt::Write as _;
+use std::format;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::string::{String, ToString};
+
+use uom::si::f32::{Frequency, Pressure, VolumeRate};
+use uom::si::pressure::{millibar, pascal};
+use uom::si::volume_rate::{cubic_meter_per_second, liter_per_minute};
+
+use crate::recording::{Record, RecordingError, RecordingReader};
+use crate::units::{
+    ComplianceUnit, FlowUnit, FrequencyUnit, HydraulicCompliance, HydraulicResistance,
+    PressureUnit, ResistanceUnit,
+};
+use crate::{AppState, Channel, Measurements, Report, Setpoint};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma separated values with a header row
+    Csv,
+    /// One JSON object per line, see <https://jsonlines.org>
+    JsonLines,
+}
+
+/// Unit every quantity is exported in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Units {
+    pub pressure: PressureUnit,
+    pub flow: FlowUnit,
+    pub heart_rate: FrequencyUnit,
+    pub resistance: ResistanceUnit,
+    pub compliance: ComplianceUnit,
+}
+
+impl Units {
+    /// SI base units, like on the wire
+    pub const SI: Units = Units {
+        pressure: PressureUnit::Pascal,
+        flow: FlowUnit::CubicMeterPerSecond,
+        heart_rate: FrequencyUnit::Hertz,
+        resistance: ResistanceUnit::PascalSecondPerCubicMeter,
+        compliance: ComplianceUnit::CubicMeterPerPascal,
+    };
+
+    /// The units used in the clinic: mmHg, L/min, beats per minute, Wood units and mL/mmHg,
+    /// pressures and flows are displayed like a [`Reading`](crate::Reading)
+    pub const CLINICAL: Units = Units {
+        pressure: PressureUnit::MillimeterOfMercury,
+        flow: FlowUnit::LiterPerMinute,
+        heart_rate: FrequencyUnit::PerMinute,
+        resistance: ResistanceUnit::WoodUnit,
+        compliance: ComplianceUnit::MilliliterPerMillimeterOfMercury,
+    };
+
+    fn abbreviation(&self, quantity: Quantity) -> Option<&'static str> {
+        match quantity {
+            Quantity::None => None,
+            Quantity::Milliseconds => Some("ms"),
+            Quantity::Hertz => Some("Hz"),
+            Quantity::Pressure => Some(self.pressure.abbreviation()),
+            Quantity::Flow => Some(self.flow.abbreviation()),
+            Quantity::HeartRate => Some(self.heart_rate.abbreviation()),
+            Quantity::Resistance => Some(self.resistance.abbreviation()),
+            Quantity::Compliance => Some(self.compliance.abbreviation()),
+        }
+    }
+
+    /// Value of `channel` in the pressure or flow unit
+    fn channel_value(&self, measurements: &Measurements, channel: Channel) -> f32 {
+        let value = measurements.value(channel);
+        match channel.is_flow() {
+            true => self
+                .flow
+                .get(VolumeRate::new::<cubic_meter_per_second>(value)),
+            false => self.pressure.get(Pressure::new::<pascal>(value)),
+        }
+    }
+
+    /// Unit of `channel`, in ASCII for file headers
+    fn channel_dimension(&self, channel: Channel) -> String {
+        match channel.is_flow() {
+            true => self.flow.abbreviation(),
+            false => self.pressure.abbreviation(),
+        }
+        .replace('³', "3")
+    }
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units::CLINICAL
+    }
+}
+
+/// Sampling and scaling of the pressure and flow signals of an [`EdfWriter`] or [`WfdbWriter`].
+///
+/// Reports arrive at irregular intervals, each sample holds the latest report at or before its
+/// time. Time counts from the first report. A device reboot restarts the device clock, the signals
+/// then continue at the next sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SignalConfig {
+    /// Samples per second of every signal, at least the report rate to keep every report
+    pub sample_rate: u16,
+    /// Physical units of the signals and annotations
+    pub units: Units,
+    /// Range of the pressure signals, readings outside are clipped. Must be finite with the maximum
+    /// above the minimum
+    pub pressure_range: (Pressure, Pressure),
+    /// Range of the flow signals, like [`SignalConfig::pressure_range`]
+    pub flow_range: (VolumeRate, VolumeRate),
+}
+
+impl Default for SignalConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 50,
+            units: Units::default(),
+            // Covers the regulator up to the default setpoint limit
+            pressure_range: (
+                Pressure::new::<millibar>(-100.0),
+                Pressure::new::<millibar>(1_000.0),
+            ),
+            // Ejection peaks are far above the cardiac output
+            flow_range: (
+                VolumeRate::new::<liter_per_minute>(-200.0),
+                VolumeRate::new::<liter_per_minute>(200.0),
+            ),
+        }
+    }
+}
+
+impl SignalConfig {
+    /// Fails with [`io::ErrorKind::InvalidInput`] for a sample rate of zero, or a range that is not
+    /// finite with its maximum above its minimum
+    fn check(&self) -> io::Result<()> {
+        if self.sample_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sample rate must be positive",
+            ));
+        }
+        for (name, min, max) in [
+            (
+                "pressure",
+                self.pressure_range.0.value,
+                self.pressure_range.1.value,
+            ),
+            ("flow", self.flow_range.0.value, self.flow_range.1.value),
+        ] {
+            if !(min.is_finite() && max.is_finite() && max > min) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} range must be finite with its maximum above its minimum"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Range of `channel` in the pressure or flow unit
+    fn channel_range(&self, channel: Channel) -> (f64, f64) {
+        let units = &self.units;
+        let (min, max) = match channel.is_flow() {
+            true => (
+                units.flow.get(self.flow_range.0),
+                units.flow.get(self.flow_range.1),
+            ),
+            false => (
+                units.pressure.get(self.pressure_range.0),
+                units.pressure.get(self.pressure_range.1),
+            ),
+        };
+        (min as f64, max as f64)
+    }
+}
+
+/// Places reports on the sample clock of a [`SignalConfig`]. Time counts milliseconds since the
+/// first report, sample `i` is taken at `i / sample_rate` seconds
+struct Resampler {
+    rate: u64,
+    /// Device time of the first report
+    origin: Option<u64>,
+    /// How far the device time is shifted since the device clock restarted
+    shift: u64,
+    /// Time of the last report
+    time: u64,
+    /// The last report, held until the next one
+    last: Option<Report>,
+}
+
+/// Where a report lands on the sample clock
+struct Placement {
+    /// Milliseconds since the first report
+    time: u64,
+    /// The first sample at or after `time`, the samples before it hold the previous report
+    sample: u64,
+    /// Whether the device clock restarted since the previous report
+    restarted: bool,
+}
+
+impl Resampler {
+    fn new(sample_rate: u16) -> Self {
+        Resampler {
+            rate: sample_rate as u64,
+            origin: None,
+            shift: 0,
+            time: 0,
+            last: None,
+        }
+    }
+
+    /// Place the report recorded at device time `timestamp`, it is held once passed to
+    /// [`Resampler::hold`]
+    fn place(&mut self, timestamp: u64) -> Placement {
+        let origin = *self.origin.get_or_insert(timestamp);
+        let mut time = (timestamp + self.shift).saturating_sub(origin);
+        let restarted = time < self.time;
+        if restarted {
+            let next = self.time * self.rate / 1_000 + 1;
+            time = (next * 1_000).div_ceil(self.rate);
+            self.shift = time + origin - timestamp;
+        }
+        Placement {
+            time,
+            sample: self.samples_before(time),
+            restarted,
+        }
+    }
+
+    fn hold(&mut self, placement: &Placement, report: &Report) {
+        self.time = placement.time;
+        self.last = Some(report.clone());
+    }
+
+    /// Number of samples taken before `time`, which is also the first sample at or after it
+    fn samples_before(&self, time: u64) -> u64 {
+        (time * self.rate).div_ceil(1_000)
+    }
+}
+
+/// Linear mapping of a pressure or flow channel onto integer samples,
+/// `digital = physical · gain + baseline`, clipped to the `digital` range
+struct Scale {
+    channel: Channel,
+    gain: f64,
+    baseline: f64,
+    digital: (i16, i16),
+}
+
+impl Scale {
+    /// Digital sample of the channel in `report`, `None` when the reading is NaN
+    fn sample(&self, report: &Report, units: &Units) -> Option<i16> {
+        let value = units.channel_value(&report.measurements, self.channel);
+        if value.is_nan() {
+            return None;
+        }
+        let digital = (value as f64 * self.gain + self.baseline).round();
+        Some(digital.clamp(self.digital.0 as f64, self.digital.1 as f64) as i16)
+    }
+}
+
+/// The most precise rendering of `value` that fits 8 characters, the width of EDF header fields
+fn number(value: f64) -> String {
+    (0..=6)
+        .rev()
+        .map(|precision| {
+            let text = format!("{value:.precision$}");
+            match text.contains('.') {
+                true => text.trim_end_matches('0').trim_end_matches('.').into(),
+                false => text,
+            }
+        })
+        .find(|text| text.len() <= 8)
+        .unwrap_or_else(|| format!("{value:.0}"))
+}
+
+#[derive(Clone, Copy)]
+enum Quantity {
+    None,
+    Milliseconds,
+    /// Always in Hz, whatever the [`Units`]
+    Hertz,
+    Pressure,
+    Flow,
+    HeartRate,
+    Resistance,
+    Compliance,
+}
+
+/// Every column in export order, [`row`] fills them in the same order
+const COLUMNS: [(&str, Quantity); 19] = [
+    ("timestamp", Quantity::Milliseconds),
+    // standby, running or fault
+    ("app_state", Quantity::None),
+    // the integer frequency of AppState::Running
+    ("running_frequency", Quantity::Hertz),
+    ("fault", Quantity::None),
+    ("setpoint_seq", Quantity::None),
+    ("setpoint_systemic_resistance", Quantity::Resistance),
+    ("setpoint_pulmonary_resistance", Quantity::Resistance),
+    (
+        "setpoint_systemic_afterload_compliance",
+        Quantity::Compliance,
+    ),
+    (
+        "setpoint_pulmonary_afterload_compliance",
+        Quantity::Compliance,
+    ),
+    ("setpoint_heart_rate", Quantity::HeartRate),
+    ("setpoint_pressure", Quantity::Pressure),
+    ("setpoint_systole_ratio", Quantity::None),
+    (Channel::RegulatorActualPressure.name(), Quantity::Pressure),
+    (Channel::SystemicFlow.name(), Quantity::Flow),
+    (Channel::PulmonaryFlow.name(), Quantity::Flow),
+    (Channel::SystemicPreloadPressure.name(), Quantity::Pressure),
+    (
+        Channel::SystemicAfterloadPressure.name(),
+        Quantity::Pressure,
+    ),
+    (Channel::PulmonaryPreloadPressure.name(), Quantity::Pressure),
+    (
+        Channel::PulmonaryAfterloadPressure.name(),
+        Quantity::Pressure,
+    ),
+];
+
+enum Value {
+    Empty,
+    Integer(u64),
+    Number(f32),
+    Text(String),
+}
+
+/// The fields of `report` in [`COLUMNS`] order
+fn row(report: &Report, units: &Units) -> [Value; COLUMNS.len()] {
+    let pressure = |pressure: Pressure| Value::Number(units.pressure.get(pressure));
+    let flow = |flow: VolumeRate| Value::Number(units.flow.get(flow));
+    let heart_rate = |rate: Frequency| Value::Number(units.heart_rate.get(rate));
+    let resistance = |r: HydraulicResistance| Value::Number(units.resistance.get(r));
+    let compliance = |c: HydraulicCompliance| Value::Number(units.compliance.get(c));
+    let or_empty = |value: Option<Value>| value.unwrap_or(Value::Empty);
+
+    let (state, running, fault) = match &report.app_state {
+        AppState::StandBy => ("standby", Value::Empty, Value::Empty),
+        AppState::Running(hz) => ("running", Value::Integer(*hz as u64), Value::Empty),
+        AppState::Fault(fault) => ("fault", Value::Empty, Value::Text(fault.to_string())),
+    };
+    let mockloop = report.setpoint.mockloop_setpoint.as_ref();
+    let heart = report.setpoint.heart_controller_setpoint.as_ref();
+    let measurements = &report.measurements;
+
+    [
+        Value::Integer(measurements.timestamp),
+        Value::Text(state.into()),
+        running,
+        fault,
+        Value::Integer(report.setpoint.seq as u64),
+        or_empty(mockloop.map(|m| resistance(m.systemic_resistance))),
+        or_empty(mockloop.map(|m| resistance(m.pulmonary_resistance))),
+        or_empty(mockloop.map(|m| compliance(m.systemic_afterload_compliance))),
+        or_empty(mockloop.map(|m| compliance(m.pulmonary_afterload_compliance))),
+        or_empty(heart.map(|h| heart_rate(h.heart_rate))),
+        or_empty(heart.map(|h| pressure(h.pressure))),
+        or_empty(heart.map(|h| Value::Number(h.systole_ratio))),
+        pressure(measurements.regulator_actual_pressure),
+        flow(measurements.systemic_flow),
+        flow(measurements.pulmonary_flow),
+        pressure(measurements.systemic_preload_pressure),
+        pressure(measurements.systemic_afterload_pressure),
+        pressure(measurements.pulmonary_preload_pressure),
+        pressure(measurements.pulmonary_afterload_pressure),
+    ]
+}
+
+/// Exports reports one at a time, implemented by every export format
+pub trait ReportWriter {
+    fn write_report(&mut self, report: &Report) -> io::Result<()>;
+
+    /// Export every report in `recording`, returns how many were written. Recorded setpoints are
+    /// skipped, every report carries the setpoint active when it was sent
+    fn write_recording<R: BufRead>(
+        &mut self,
+        recording: RecordingReader<R>,
+    ) -> Result<usize, RecordingError> {
+        let mut count = 0;
+        for record in recording {
+            if let Record::Report(report) = record? {
+                self.write_report(&report)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Writes reports as rows, see the [module documentation](self)
+pub struct Exporter<W: Write> {
+    out: W,
+    format: ExportFormat,
+    units: Units,
+    header_written: bool,
+    line: String,
+}
+
+impl Exporter<BufWriter<File>> {
+    /// Create or truncate the file at `path`
+    pub fn create(path: impl AsRef<Path>, format: ExportFormat, units: Units) -> io::Result<Self> {
+        Ok(Self::new(
+            BufWriter::new(File::create(path)?),
+            format,
+            units,
+        ))
+    }
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(out: W, format: ExportFormat, units: Units) -> Self {
+        Exporter {
+            out,
+            format,
+            units,
+            header_written: false,
+            line: String::new(),
+        }
+    }
+
+    /// Name and unit of every column, in order. Quantities are in the selected [`Units`], columns
+    /// without a unit are counts, states or ratios
+    pub fn columns(&self) -> impl Iterator<Item = (&'static str, Option<&'static str>)> + '_ {
+        COLUMNS
+            .iter()
+            .map(|(name, quantity)| (*name, self.units.abbreviation(*quantity)))
+    }
+
+    /// Flush the output and return it. A CSV export without reports still gets its header row
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_header()?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.header_written = true;
+        if self.format == ExportFormat::Csv {
+            self.line.clear();
+            for (i, (name, _)) in COLUMNS.iter().enumerate() {
+                if i > 0 {
+                    self.line.push(',');
+                }
+                self.line.push_str(name);
+            }
+            self.line.push('\n');
+            self.out.write_all(self.line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> ReportWriter for Exporter<W> {
+    fn write_report(&mut self, report: &Report) -> io::Result<()> {
+        self.write_header()?;
+        self.line.clear();
+        let values = row(report, &self.units);
+        match self.format {
+            ExportFormat::Csv => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        self.line.push(',');
+                    }
+                    write_csv(&mut self.line, value);
+                }
+            }
+            ExportFormat::JsonLines => {
+                self.line.push('{');
+                for (i, ((name, _), value)) in COLUMNS.iter().zip(&values).enumerate() {
+                    if i > 0 {
+                        self.line.push(',');
+                    }
+                    write_json_string(&mut self.line, name);
+                    self.line.push(':');
+                    write_json(&mut self.line, value);
+                }
+                self.line.push('}');
+            }
+        }
+        self.line.push('\n');
+        self.out.write_all(self.line.as_bytes())
+    }
+}
+
+fn write_csv(line: &mut String, value: &Value) {
+    match value {
+        Value::Empty => {}
+        Value::Integer(value) => {
+            let _ = write!(line, "{value}");
+        }
+        Value::Number(value) if value.is_nan() => {}
+        Value::Number(value) => {
+            let _ = write!(line, "{value}");
+        }
+        Value::Text(text) if text.contains([',', '"', '\n', '\r']) => {
+            line.push('"');
+            line.push_str(&text.replace('"', "\"\""));
+            line.push('"');
+        }
+        Value::Text(text) => line.push_str(text),
+    }
+}
+
+fn write_json(line: &mut String, value: &Value) {
+    match value {
+        Value::Integer(value) => {
+            let _ = write!(line, "{value}");
+        }
+        // JSON has no NaN or infinity
+        Value::Number(value) if value.is_finite() => {
+            let _ = write!(line, "{value}");
+        }
+        Value::Empty | Value::Number(_) => line.push_str("null"),
+        Value::Text(text) => write_json_string(line, text),
+    }
+}
+
+fn write_json_string(line: &mut String, text: &str) {
+    line.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Follows the state and setpoint through consecutive reports, for formats that annotate changes
+struct Changes {
+    state: Option<AppState>,
+    setpoint: Option<Setpoint>,
+}
+
+impl Changes {
+    const fn new() -> Self {
+        Changes {
+            state: None,
+            setpoint: None,
+        }
+    }
+
+    /// Describe what changed since the previous report, the first report changes everything
+    fn update(&mut self, report: &Report, units: &Units) -> [Option<String>; 2] {
+        let state = (self.state != Some(report.app_state)).then(|| {
+            self.state = Some(report.app_state);
+            describe_state(&report.app_state)
+        });
+        let setpoint = (self.setpoint.as_ref() != Some(&report.setpoint)).then(|| {
+            self.setpoint = Some(report.setpoint.clone());
+            describe_setpoint(&report.setpoint, units)
+        });
+        [state, setpoint]
+    }
+}
+
+fn describe_state(state: &AppState) -> String {
+    match state {
+        AppState::StandBy => String::from("standby"),
+        AppState::Running(hz) => format!("running at {hz} Hz"),
+        AppState::Fault(fault) => format!("fault: {fault}"),
+    }
+}
+
+fn describe_setpoint(setpoint: &Setpoint, units: &Units) -> String {
+    let mut text = format!("setpoint #{}", setpoint.seq);
+    if let Some(heart) = &setpoint.heart_controller_setpoint {
+        let _ = write!(
+            text,
+            ", heart rate {} {}, pressure {} {}, systole ratio {}",
+            units.heart_rate.get(heart.heart_rate),
+            units.heart_rate.abbreviation(),
+            units.pressure.get(heart.pressure),
+            units.pressure.abbreviation(),
+            heart.systole_ratio,
+        );
+    }
+    if let Some(mockloop) = &setpoint.mockloop_setpoint {
+        let resistance = units.resistance.abbreviation();
+        let compliance = units.compliance.abbreviation();
+        let _ = write!(
+            text,
+            ", resistance {} / {} {resistance}, compliance {} / {} {compliance} (systemic / pulmonary)",
+            units.resistance.get(mockloop.systemic_resistance),
+            units.resistance.get(mockloop.pulmonary_resistance),
+            units.compliance.get(mockloop.systemic_afterload_compliance),
+            units
+                .compliance
+                .get(mockloop.pulmonary_afterload_compliance),
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use uom::si::frequency::hertz;
+    use uom::si::pressure::millimeter_of_mercury;
+
+    use super::*;
+    use crate::HeartControllerSetpoint;
+    use crate::fault::{Fault, FaultKind};
+    use crate::testing::report;
+
+    fn export(format: ExportFormat, units: Units, reports: &[Report]) -> String {
+        let mut exporter = Exporter::new(Vec::new(), format, units);
+        for report in reports {
+            exporter.write_report(report).unwrap();
+        }
+        String::from_utf8(exporter.finish().unwrap()).unwrap()
+    }
+
+    /// The CSV row of `report` by column name
+    fn csv_row(report: &Report, units: Units) -> Vec<(String, String)> {
+        let csv = export(ExportFormat::Csv, units, core::slice::from_ref(report));
+        let mut lines = csv.lines();
+        let header = lines.next().unwrap().split(',');
+        let row = lines.next().unwrap().split(',');
+        header.zip(row).map(|(h, v)| (h.into(), v.into())).collect()
+    }
+
+    fn field<'a>(row: &'a [(String, String)], name: &str) -> &'a str {
+        &row.iter().find(|(column, _)| column == name).unwrap().1
+    }
+
+    fn number(row: &[(String, String)], name: &str) -> f32 {
+        field(row, name).parse().unwrap()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1.0e-5,
+            "{actual} != {expected}"
+        );
+    }
+
+    fn heart_setpoint() -> Report {
+        let mut report = report(0);
+        report.setpoint.heart_controller_setpoint = Some(HeartControllerSetpoint {
+            heart_rate: Frequency::new::<hertz>(1.0),
+            pressure: Pressure::new::<millimeter_of_mercury>(100.0),
+            systole_ratio: 0.4,
+        });
+        report.measurements.systemic_flow = VolumeRate::new::<liter_per_minute>(6.0);
+        report
+    }
+
+    #[test]
+    fn header_lists_every_column_in_order() {
+        let csv = export(ExportFormat::Csv, Units::SI, &[]);
+        let names: Vec<_> = COLUMNS.iter().map(|(name, _)| *name).collect();
+        assert_eq!(csv, format!("{}\n", names.join(",")));
+        assert_eq!(
+            names[..4],
+            ["timestamp", "app_state", "running_frequency", "fault"]
+        );
+        assert_eq!(names[12..], Channel::ALL.map(Channel::name));
+
+        let exporter = Exporter::new(Vec::new(), ExportFormat::Csv, Units::SI);
+        assert!(
+            exporter
+                .columns()
+                .map(|(name, _)| name)
+                .eq(names.iter().copied())
+        );
+
+        // JSON Lines has no header and keeps the column order within each object
+        let json = export(ExportFormat::JsonLines, Units::SI, &[report(0)]);
+        let positions: Vec<_> = names
+            .iter()
+            .map(|name| json.find(&format!("\"{name}\":")).unwrap())
+            .collect();
+        assert!(positions.is_sorted());
+        assert_eq!(json.lines().count(), 1);
+    }
+
+    #[test]
+    fn csv_quotes_text_with_commas_or_quotes() {
+        let mut line = String::new();
+        write_csv(
+            &mut line,
+            &Value::Text("over-pressure, \"systemic\"".into()),
+        );
+        assert_eq!(line, "\"over-pressure, \"\"systemic\"\"\"");
+
+        line.clear();
+        write_csv(&mut line, &Value::Text("two\nlines".into()));
+        assert_eq!(line, "\"two\nlines\"");
+
+        let mut fault = report(7);
+        fault.app_state = AppState::Fault(Fault {
+            kind: FaultKind::OverPressure,
+            timestamp: 5,
+            reading: None,
+        });
+        let row = csv_row(&fault, Units::SI);
+        assert_eq!(field(&row, "app_state"), "fault");
+        assert_eq!(field(&row, "fault"), "over-pressure at 5 ms");
+        assert_eq!(field(&row, "running_frequency"), "");
+    }
+
+    #[test]
+    fn missing_setpoints_and_nan_readings_are_empty_or_null() {
+        let mut report = heart_setpoint();
+        report.measurements.regulator_actual_pressure = Pressure::new::<pascal>(f32::NAN);
+        let empty = [
+            "setpoint_systemic_resistance",
+            "setpoint_pulmonary_resistance",
+            "setpoint_systemic_afterload_compliance",
+            "setpoint_pulmonary_afterload_compliance",
+            Channel::RegulatorActualPressure.name(),
+        ];
+
+        let row = csv_row(&report, Units::SI);
+        for name in empty {
+            assert_eq!(field(&row, name), "", "{name}");
+        }
+        assert_eq!(number(&row, "setpoint_heart_rate"), 1.0);
+
+        let json = export(ExportFormat::JsonLines, Units::SI, &[report]);
+        for name in empty {
+            assert!(json.contains(&format!("\"{name}\":null")), "{name}");
+        }
+        assert!(json.contains("\"setpoint_heart_rate\":1,"));
+        assert!(!json.contains("NaN"));
+    }
+
+    #[test]
+    fn units_select_the_exported_values() {
+        let mut report = heart_setpoint();
+        report.app_state = AppState::Running(2);
+        let si = csv_row(&report, Units::SI);
+        let clinical = csv_row(&report, Units::CLINICAL);
+
+        assert_close(number(&si, "setpoint_heart_rate"), 1.0);
+        assert_close(number(&clinical, "setpoint_heart_rate"), 60.0);
+        assert_close(number(&si, "setpoint_pressure"), 13_332.237);
+        assert_close(number(&clinical, "setpoint_pressure"), 100.0);
+        let flow = Channel::SystemicFlow.name();
+        assert_close(number(&si, flow), 1.0e-4);
+        assert_close(number(&clinical, flow), 6.0);
+        // The running frequency is the raw integer from the device in either
+        assert_eq!(field(&si, "running_frequency"), "2");
+        assert_eq!(field(&clinical, "running_frequency"), "2");
+
+        let unit = |units: Units, column: &str| {
+            let exporter = Exporter::new(Vec::new(), ExportFormat::Csv, units);
+            exporter
+                .columns()
+                .find(|(name, _)| *name == column)
+                .unwrap()
+                .1
+        };
+        assert_eq!(unit(Units::SI, "setpoint_heart_rate"), Some("Hz"));
+        assert_eq!(unit(Units::CLINICAL, "setpoint_heart_rate"), Some("1/min"));
+        assert_eq!(unit(Units::SI, "setpoint_pressure"), Some("Pa"));
+        assert_eq!(unit(Units::CLINICAL, "setpoint_pressure"), Some("mmHg"));
+        assert_eq!(unit(Units::CLINICAL, "running_frequency"), Some("Hz"));
+        assert_eq!(unit(Units::CLINICAL, "app_state"), None);
+    }
+}
@@ -0,0 +1,517 @@
+//! European Data Format (EDF+) export.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::string::{String, ToString};
+use std::vec::Vec;
+use std::{format, vec};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+use super::{Changes, ReportWriter, Resampler, Scale, SignalConfig, number};
+use crate::{Channel, Report};
+
+/// Duration of a data record, milliseconds
+const RECORD_MS: u64 = 1_000;
+/// Size of the annotation signal in every data record, annotations that do not fit move on to the
+/// next record
+const ANNOTATION_BYTES: usize = 1_024;
+/// Offset of the number of data records in the header, patched by [`EdfWriter::finish`]
+const RECORD_COUNT_OFFSET: u64 = 236;
+const DIGITAL_MIN: i16 = -32_768;
+const DIGITAL_MAX: i16 = 32_767;
+
+/// Header fields of one signal, scaled from the physical range as written
+struct Signal {
+    label: &'static str,
+    dimension: String,
+    physical_min: String,
+    physical_max: String,
+    scale: Scale,
+}
+
+impl Signal {
+    /// Fails with [`io::ErrorKind::InvalidInput`] when the range is too narrow to be written
+    fn new(channel: Channel, config: &SignalConfig) -> io::Result<Self> {
+        let (min, max) = config.channel_range(channel);
+        // Scale with the values as written, that is what readers will use
+        let physical_min = number(min);
+        let physical_max = number(max);
+        let (min, max): (f64, f64) = (
+            physical_min.parse().unwrap_or(0.0),
+            physical_max.parse().unwrap_or(0.0),
+        );
+        if max <= min {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range of {} is too narrow to write", channel.name()),
+            ));
+        }
+        let gain = (DIGITAL_MAX as f64 - DIGITAL_MIN as f64) / (max - min);
+        Ok(Signal {
+            label: label(channel),
+            dimension: config.units.channel_dimension(channel),
+            physical_min,
+            physical_max,
+            scale: Scale {
+                channel,
+                gain,
+                baseline: DIGITAL_MIN as f64 - min * gain,
+                digital: (DIGITAL_MIN, DIGITAL_MAX),
+            },
+        })
+    }
+
+    /// Digital sample of `report`, NaN readings become the digital minimum
+    fn sample(&self, report: &Report, config: &SignalConfig) -> i16 {
+        self.scale
+            .sample(report, &config.units)
+            .unwrap_or(DIGITAL_MIN)
+    }
+}
+
+/// Signal labels are limited to 16 characters
+fn label(channel: Channel) -> &'static str {
+    match channel {
+        Channel::RegulatorActualPressure => "P regulator",
+        Channel::SystemicFlow => "Q systemic",
+        Channel::PulmonaryFlow => "Q pulmonary",
+        Channel::SystemicPreloadPressure => "P sys preload",
+        Channel::SystemicAfterloadPressure => "P sys afterload",
+        Channel::PulmonaryPreloadPressure => "P pul preload",
+        Channel::PulmonaryAfterloadPressure => "P pul afterload",
+    }
+}
+
+/// The data record being filled
+struct DataRecord {
+    /// Index of the record, its onset in seconds since the first report
+    index: u64,
+    /// Samples written so far, per signal
+    filled: usize,
+    samples: Vec<Vec<i16>>,
+}
+
+/// Writes reports to an EDF+ file, see <https://www.edfplus.info/specs/edfplus.html>.
+///
+/// Every [`Channel`] becomes a signal, sampled as described at [`SignalConfig`]. Data records
+/// last one second and start at whole seconds of device time since the first report. Seconds
+/// without reports are left out, which makes the file discontinuous (EDF+D). Changes of the
+/// [`AppState`](crate::AppState) and of the setpoint are written to the `EDF Annotations` signal
+pub struct EdfWriter<W: Write + Seek> {
+    out: W,
+    config: SignalConfig,
+    signals: Vec<Signal>,
+    records_written: u64,
+    record: Option<DataRecord>,
+    resampler: Resampler,
+    changes: Changes,
+    /// Annotations not written yet, onset in milliseconds since the first report
+    annotations: VecDeque<(u64, String)>,
+}
+
+impl EdfWriter<BufWriter<File>> {
+    /// Create or truncate the file at `path`
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: SignalConfig,
+        start: Option<DateTime<Utc>>,
+    ) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), config, start)
+    }
+}
+
+impl<W: Write + Seek> EdfWriter<W> {
+    /// Write the header to `out`. `start` is the wall clock time of the first report, e.g. the
+    /// [`Metadata::started_at`](crate::recording::Metadata::started_at) of a recording
+    pub fn new(mut out: W, config: SignalConfig, start: Option<DateTime<Utc>>) -> io::Result<Self> {
+        config.check()?;
+        let signals: Vec<Signal> = Channel::ALL
+            .iter()
+            .map(|channel| Signal::new(*channel, &config))
+            .collect::<io::Result<_>>()?;
+        write_header(&mut out, &config, &signals, start)?;
+        Ok(EdfWriter {
+            out,
+            config,
+            signals,
+            records_written: 0,
+            record: None,
+            resampler: Resampler::new(config.sample_rate),
+            changes: Changes::new(),
+            annotations: VecDeque::new(),
+        })
+    }
+
+    /// Write the last data record and the number of records, returns the underlying writer.
+    /// Annotations that did not fit get data records of their own, holding the last report
+    pub fn finish(mut self) -> io::Result<W> {
+        while let Some(record) = &self.record {
+            let next = record.index + 1;
+            self.flush_record()?;
+            if self.annotations.is_empty() {
+                break;
+            }
+            self.start_record(next);
+        }
+        self.out.seek(SeekFrom::Start(RECORD_COUNT_OFFSET))?;
+        self.out
+            .write_all(field(&self.records_written.to_string(), 8).as_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn samples_per_record(&self) -> usize {
+        self.config.sample_rate as usize
+    }
+
+    fn start_record(&mut self, index: u64) {
+        let samples = self.samples_per_record();
+        self.record = Some(DataRecord {
+            index,
+            filled: 0,
+            samples: vec![vec![0; samples]; self.signals.len()],
+        });
+    }
+
+    /// Fill the samples before `sample` into the current record with the last report, or `next`
+    /// when there is none to hold
+    fn fill_until(&mut self, sample: u64, next: &Report) {
+        let samples = self.samples_per_record();
+        let Some(record) = &mut self.record else {
+            return;
+        };
+        let held = self.resampler.last.as_ref().unwrap_or(next);
+        let digital: Vec<i16> = self
+            .signals
+            .iter()
+            .map(|signal| signal.sample(held, &self.config))
+            .collect();
+        let first = record.index * samples as u64;
+        let end = (sample.saturating_sub(first) as usize).min(samples);
+        for (signal, value) in record.samples.iter_mut().zip(&digital) {
+            signal[record.filled..end.max(record.filled)].fill(*value);
+        }
+        record.filled = end.max(record.filled);
+    }
+
+    fn flush_record(&mut self) -> io::Result<()> {
+        let (Some(record), Some(last)) = (&self.record, self.resampler.last.clone()) else {
+            self.record = None;
+            return Ok(());
+        };
+        self.fill_until((record.index + 1) * self.samples_per_record() as u64, &last);
+        let Some(record) = self.record.take() else {
+            return Ok(());
+        };
+
+        let mut bytes = Vec::with_capacity(
+            self.signals.len() * self.samples_per_record() * 2 + ANNOTATION_BYTES,
+        );
+        for signal in &record.samples {
+            for sample in signal {
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        // The first annotation of every record keeps its time
+        let mut annotations = format!("+{}\x14\x14\0", seconds(record.index * RECORD_MS));
+        while let Some((onset, text)) = self.annotations.front() {
+            let tal = format!("+{}\x14{}\x14\0", seconds(*onset), text);
+            if annotations.len() + tal.len() > ANNOTATION_BYTES {
+                break;
+            }
+            annotations.push_str(&tal);
+            self.annotations.pop_front();
+        }
+        bytes.extend_from_slice(annotations.as_bytes());
+        bytes.resize(bytes.len() + ANNOTATION_BYTES - annotations.len(), 0);
+
+        self.out.write_all(&bytes)?;
+        self.records_written += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> ReportWriter for EdfWriter<W> {
+    fn write_report(&mut self, report: &Report) -> io::Result<()> {
+        let placement = self.resampler.place(report.measurements.timestamp);
+        let index = placement.time / RECORD_MS;
+        match &self.record {
+            Some(record) if record.index == index => {}
+            Some(record) => {
+                let next = record.index + 1;
+                self.flush_record()?;
+                // Hold the last report into the next record, but not across a gap
+                if index != next {
+                    self.resampler.last = None;
+                }
+                self.start_record(index);
+            }
+            None => self.start_record(index),
+        }
+
+        // Queued after flushing the previous record, so they go into the record of their onset
+        if placement.restarted {
+            self.annotations
+                .push_back((placement.time, String::from("device clock restarted")));
+        }
+        for text in self
+            .changes
+            .update(report, &self.config.units)
+            .into_iter()
+            .flatten()
+        {
+            self.annotations.push_back((placement.time, text));
+        }
+        self.fill_until(placement.sample, report);
+        self.resampler.hold(&placement, report);
+        Ok(())
+    }
+}
+
+fn write_header(
+    out: &mut impl Write,
+    config: &SignalConfig,
+    signals: &[Signal],
+    start: Option<DateTime<Utc>>,
+) -> io::Result<()> {
+    const MONTHS: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let startdate = match start {
+        Some(start) => format!(
+            "Startdate {:02}-{}-{}",
+            start.day(),
+            MONTHS[start.month0() as usize],
+            start.year()
+        ),
+        None => String::from("Startdate X"),
+    };
+    // Two digit years from 1985, which also stands for unknown
+    let (date, time) = match start {
+        Some(start) => (
+            format!(
+                "{:02}.{:02}.{:02}",
+                start.day(),
+                start.month(),
+                start.year() % 100
+            ),
+            format!(
+                "{:02}.{:02}.{:02}",
+                start.hour(),
+                start.minute(),
+                start.second()
+            ),
+        ),
+        None => (String::from("01.01.85"), String::from("00.00.00")),
+    };
+    let count = signals.len() + 1;
+    let samples = config.sample_rate.to_string();
+
+    let mut header = String::new();
+    header += &field("0", 8);
+    // Patient code, sex, birthdate and name, a mockloop has none
+    header += &field("X X X X", 80);
+    header += &field(
+        &format!("{startdate} X X love-letter_{}", env!("CARGO_PKG_VERSION")),
+        80,
+    );
+    header += &field(&date, 8);
+    header += &field(&time, 8);
+    header += &field(&(256 * (count + 1)).to_string(), 8);
+    header += &field("EDF+D", 44);
+    // Unknown until finished
+    header += &field("-1", 8);
+    header += &field(&(RECORD_MS / 1_000).to_string(), 8);
+    header += &field(&count.to_string(), 4);
+
+    // Every signal field is repeated for all signals, the annotation signal comes last
+    let annotation_samples = (ANNOTATION_BYTES / 2).to_string();
+    let mut signal_field = |width: usize, value: &dyn Fn(&Signal) -> String, annotations: &str| {
+        for signal in signals {
+            header += &field(&value(signal), width);
+        }
+        header += &field(annotations, width);
+    };
+    signal_field(16, &|s| s.label.into(), "EDF Annotations");
+    signal_field(80, &|s| format!("mockloop {}", s.scale.channel.name()), "");
+    signal_field(8, &|s| s.dimension.clone(), "");
+    signal_field(8, &|s| s.physical_min.clone(), "-1");
+    signal_field(8, &|s| s.physical_max.clone(), "1");
+    signal_field(8, &|_| DIGITAL_MIN.to_string(), "-32768");
+    signal_field(8, &|_| DIGITAL_MAX.to_string(), "32767");
+    // Prefiltering
+    signal_field(80, &|_| String::new(), "");
+    signal_field(8, &|_| samples.clone(), &annotation_samples);
+    signal_field(32, &|_| String::new(), "");
+    debug_assert_eq!(header.len(), 256 * (count + 1));
+    out.write_all(header.as_bytes())
+}
+
+/// `value` left aligned in a header field of `width` ASCII characters, cut off when too long
+fn field(value: &str, width: usize) -> String {
+    let mut field: String = value
+        .chars()
+        .map(|c| match c.is_ascii() && !c.is_ascii_control() {
+            true => c,
+            false => '_',
+        })
+        .take(width)
+        .collect();
+    while field.len() < width {
+        field.push(' ');
+    }
+    field
+}
+
+/// `ms` as seconds for an annotation onset, without trailing zeros
+fn seconds(ms: u64) -> String {
+    match ms % 1_000 {
+        0 => (ms / 1_000).to_string(),
+        frac => format!("{}.{:03}", ms / 1_000, frac)
+            .trim_end_matches('0')
+            .into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Units;
+    use crate::testing::report_with_pressure as report;
+    use std::io::Cursor;
+    use uom::si::f32::{Pressure, VolumeRate};
+    use uom::si::pressure::pascal;
+    use uom::si::volume_rate::cubic_meter_per_second;
+
+    fn config() -> SignalConfig {
+        SignalConfig {
+            sample_rate: 10,
+            units: Units::SI,
+            pressure_range: (
+                Pressure::new::<pascal>(0.0),
+                Pressure::new::<pascal>(65_535.0),
+            ),
+            ..SignalConfig::default()
+        }
+    }
+
+    fn export(config: SignalConfig, reports: impl IntoIterator<Item = Report>) -> Vec<u8> {
+        let mut writer = EdfWriter::new(Cursor::new(Vec::new()), config, None).unwrap();
+        for report in reports {
+            writer.write_report(&report).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn header_field(bytes: &[u8], offset: usize, width: usize) -> &str {
+        std::str::from_utf8(&bytes[offset..offset + width])
+            .unwrap()
+            .trim_end()
+    }
+
+    /// Bytes of one data record, the samples of every channel and the annotation signal
+    fn record_len(config: &SignalConfig) -> usize {
+        Channel::ALL.len() * config.sample_rate as usize * 2 + ANNOTATION_BYTES
+    }
+
+    #[test]
+    fn header_has_a_block_per_signal_and_the_record_count() {
+        let config = config();
+        let bytes = export(config, (0..30).map(|n| report(n * 100, 0.0)));
+        let signals = Channel::ALL.len() + 1;
+        let header_len = 256 * (signals + 1);
+
+        assert_eq!(header_field(&bytes, 0, 8), "0");
+        assert_eq!(header_field(&bytes, 184, 8), header_len.to_string());
+        assert_eq!(header_field(&bytes, 192, 44), "EDF+D");
+        assert_eq!(header_field(&bytes, 236, 8), "3");
+        assert_eq!(header_field(&bytes, 252, 4), signals.to_string());
+        assert_eq!(header_field(&bytes, 256, 16), "P regulator");
+        assert_eq!(bytes.len(), header_len + 3 * record_len(&config));
+    }
+
+    #[test]
+    fn samples_map_the_physical_range_onto_the_digital_range() {
+        let config = config();
+        let signal = Signal::new(Channel::RegulatorActualPressure, &config).unwrap();
+        let sample = |pressure| signal.sample(&report(0, pressure), &config);
+        assert_eq!(sample(0.0), DIGITAL_MIN);
+        assert_eq!(sample(100.0), DIGITAL_MIN + 100);
+        assert_eq!(sample(65_535.0), DIGITAL_MAX);
+        assert_eq!(sample(1e9), DIGITAL_MAX);
+        assert_eq!(sample(f32::NAN), DIGITAL_MIN);
+    }
+
+    #[test]
+    fn samples_hold_the_latest_report() {
+        let config = config();
+        let bytes = export(config, [report(0, 100.0), report(450, 200.0)]);
+        let first = 256 * (Channel::ALL.len() + 2);
+        let samples: Vec<i16> = bytes[first..first + 2 * config.sample_rate as usize]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) - DIGITAL_MIN)
+            .collect();
+        assert_eq!(samples, [100, 100, 100, 100, 100, 200, 200, 200, 200, 200]);
+    }
+
+    #[test]
+    fn annotates_changes_and_device_restarts() {
+        let config = config();
+        let before = (0..15).map(|n| report(n * 100, 0.0));
+        let after = (0..10).map(|n| report(n * 100, 0.0));
+        let bytes = export(config, before.chain(after));
+        assert_eq!(header_field(&bytes, 236, 8), "3");
+
+        let first = 256 * (Channel::ALL.len() + 2);
+        let annotations = |record: usize| {
+            let end = first + (record + 1) * record_len(&config);
+            std::string::String::from_utf8_lossy(&bytes[end - ANNOTATION_BYTES..end]).into_owned()
+        };
+        assert!(annotations(0).starts_with("+0\x14\x14\0+0\x14standby\x14\0+0\x14setpoint #0"));
+        // Time goes on at the sample after the last report before the restart
+        assert!(annotations(1).starts_with("+1\x14\x14\0+1.5\x14device clock restarted\x14\0"));
+        assert!(annotations(2).starts_with("+2\x14\x14\0\0"));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let new = |config| EdfWriter::new(Cursor::new(Vec::new()), config, None).map(|_| ());
+        let pressure = |min, max| (Pressure::new::<pascal>(min), Pressure::new::<pascal>(max));
+        for config in [
+            SignalConfig {
+                sample_rate: 0,
+                ..config()
+            },
+            SignalConfig {
+                pressure_range: pressure(1.0, 1.0),
+                ..config()
+            },
+            SignalConfig {
+                pressure_range: pressure(1.0, 0.0),
+                ..config()
+            },
+            SignalConfig {
+                pressure_range: pressure(0.0, f32::INFINITY),
+                ..config()
+            },
+            // Both ends are written as 0
+            SignalConfig {
+                pressure_range: pressure(0.0, 1e-9),
+                ..config()
+            },
+            SignalConfig {
+                flow_range: (
+                    VolumeRate::new::<cubic_meter_per_second>(f32::NAN),
+                    config().flow_range.1,
+                ),
+                ..config()
+            },
+        ] {
+            assert_eq!(new(config).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
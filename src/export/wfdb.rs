@@ -0,0 +1,651 @@
+//! PhysioNet WFDB export.
+
+use std::fmt::Write as _;
+use std::format;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use uom::si::f32::VolumeRate;
+use uom::si::volume_rate::liter_per_minute;
+
+use super::{Changes, ReportWriter, Resampler, Scale, SignalConfig, number};
+use crate::{Channel, Report};
+
+/// How long a report is held, later samples without a newer report are invalid
+const HOLD_MS: u64 = 1_000;
+/// Shortest time between two beats, limits the detection to 300 beats per minute
+const REFRACTORY_MS: u64 = 200;
+
+/// MIT annotation codes, see `ecgcodes.h` of the WFDB library
+const NORMAL: u16 = 1;
+const NOTE: u16 = 22;
+const SKIP: u16 = 59;
+const AUX: u16 = 63;
+/// Longest time difference and auxiliary string an annotation word holds
+const MAX_INTERVAL: u64 = 0x3ff;
+const MAX_AUX: usize = 255;
+
+/// Sample encoding of the signal file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum WfdbFormat {
+    /// 16 bit two's complement, little endian
+    #[default]
+    Format16,
+    /// 12 bit two's complement, two samples packed into three bytes
+    Format212,
+}
+
+impl WfdbFormat {
+    const fn code(self) -> u16 {
+        match self {
+            WfdbFormat::Format16 => 16,
+            WfdbFormat::Format212 => 212,
+        }
+    }
+
+    const fn bits(self) -> u32 {
+        match self {
+            WfdbFormat::Format16 => 16,
+            WfdbFormat::Format212 => 12,
+        }
+    }
+
+    /// The lowest sample value, which WFDB reserves for missing samples
+    const fn invalid(self) -> i16 {
+        i16::MIN >> (16 - self.bits())
+    }
+
+    const fn max(self) -> i16 {
+        i16::MAX >> (16 - self.bits())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WfdbConfig {
+    pub signals: SignalConfig,
+    pub format: WfdbFormat,
+    /// Systemic flow above which a beat is detected, it must drop below half of it before the
+    /// next beat
+    pub beat_threshold: VolumeRate,
+}
+
+impl Default for WfdbConfig {
+    fn default() -> Self {
+        Self {
+            signals: SignalConfig::default(),
+            format: WfdbFormat::default(),
+            beat_threshold: VolumeRate::new::<liter_per_minute>(2.0),
+        }
+    }
+}
+
+/// The three files of a WFDB record
+#[derive(Debug)]
+pub struct WfdbFiles<W> {
+    /// Header, `<record>.hea`, written by [`WfdbWriter::finish`]
+    pub header: W,
+    /// Signal file, `<record>.dat`
+    pub signals: W,
+    /// Annotation file, `<record>.atr`
+    pub annotations: W,
+}
+
+/// Header fields of one signal, scaled with the gain and baseline as written
+struct Signal {
+    units: String,
+    gain: String,
+    baseline: i32,
+    scale: Scale,
+    initial: Option<i16>,
+    checksum: i16,
+}
+
+impl Signal {
+    /// Fails with [`io::ErrorKind::InvalidInput`] when the range is too wide for its gain to be
+    /// written
+    fn new(channel: Channel, config: &WfdbConfig) -> io::Result<Self> {
+        let (min, max) = config.signals.channel_range(channel);
+        // The lowest value is reserved for missing samples
+        let digital = (config.format.invalid() + 1, config.format.max());
+        let gain = number((digital.1 as f64 - digital.0 as f64) / (max - min));
+        let scale: f64 = gain.parse().unwrap_or(0.0);
+        if !(scale > 0.0 && scale.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range of {} is too wide for its gain", channel.name()),
+            ));
+        }
+        let baseline = (digital.0 as f64 - min * scale).round() as i32;
+        Ok(Signal {
+            units: config.signals.units.channel_dimension(channel),
+            gain,
+            baseline,
+            scale: Scale {
+                channel,
+                gain: scale,
+                baseline: baseline as f64,
+                digital,
+            },
+            initial: None,
+            checksum: 0,
+        })
+    }
+
+    /// Digital sample of `report`, NaN readings are invalid
+    fn sample(&self, report: &Report, config: &WfdbConfig) -> i16 {
+        self.scale
+            .sample(report, &config.signals.units)
+            .unwrap_or(config.format.invalid())
+    }
+}
+
+/// Detects beats from the systemic flow crossing a threshold upwards
+struct BeatDetector {
+    threshold: f32,
+    /// Whether the flow dropped below half the threshold since the last beat
+    armed: bool,
+    last: Option<u64>,
+}
+
+impl BeatDetector {
+    /// Whether a beat starts at `time`, the first ejection is only counted when the export starts
+    /// before it
+    fn update(&mut self, flow: f32, time: u64) -> bool {
+        if flow.is_nan() {
+            return false;
+        }
+        if flow < self.threshold / 2.0 {
+            self.armed = true;
+        }
+        let refractory = self.last.is_some_and(|last| time < last + REFRACTORY_MS);
+        if !self.armed || flow < self.threshold || refractory {
+            return false;
+        }
+        self.armed = false;
+        self.last = Some(time);
+        true
+    }
+}
+
+/// Writes reports to a PhysioNet WFDB record, see <https://physionet.org/physiotools/wag/>.
+///
+/// Every [`Channel`] becomes a signal in the signal file, sampled as described at
+/// [`SignalConfig`]. WFDB records are continuous, samples more than a second after the last report
+/// are written as invalid. Gain and baseline map [`SignalConfig::pressure_range`] and
+/// [`SignalConfig::flow_range`] onto the sample range of the [`WfdbFormat`].
+///
+/// The annotation file marks every detected beat as a normal beat (`N`), and changes of the
+/// [`AppState`](crate::AppState) and of the setpoint as comments (`"`) with the change as
+/// auxiliary text
+pub struct WfdbWriter<W: Write> {
+    files: WfdbFiles<W>,
+    record: String,
+    config: WfdbConfig,
+    start: Option<DateTime<Utc>>,
+    signals: Vec<Signal>,
+    /// Frames written so far, frame `i` is sampled at `i / sample_rate` seconds
+    frames: u64,
+    /// Format 212 packs samples in pairs, the first of an incomplete pair
+    pending: Option<i16>,
+    resampler: Resampler,
+    changes: Changes,
+    beats: BeatDetector,
+    /// Sample of the last annotation, annotations store the samples in between
+    annotated: u64,
+}
+
+impl WfdbWriter<BufWriter<File>> {
+    /// Create or truncate `<record>.hea`, `<record>.dat` and `<record>.atr` in `directory`
+    pub fn create(
+        directory: impl AsRef<Path>,
+        record: &str,
+        config: WfdbConfig,
+        start: Option<DateTime<Utc>>,
+    ) -> io::Result<Self> {
+        let directory = directory.as_ref();
+        let file = |extension: &str| -> io::Result<BufWriter<File>> {
+            Ok(BufWriter::new(File::create(
+                directory.join(format!("{record}.{extension}")),
+            )?))
+        };
+        let files = WfdbFiles {
+            header: file("hea")?,
+            signals: file("dat")?,
+            annotations: file("atr")?,
+        };
+        Self::new(record, files, config, start)
+    }
+}
+
+impl<W: Write> WfdbWriter<W> {
+    /// Write a record named `record` to `files`, the header refers to the signal file by the
+    /// record name. `start` is the wall clock time of the first report, e.g. the
+    /// [`Metadata::started_at`](crate::recording::Metadata::started_at) of a recording
+    pub fn new(
+        record: &str,
+        files: WfdbFiles<W>,
+        config: WfdbConfig,
+        start: Option<DateTime<Utc>>,
+    ) -> io::Result<Self> {
+        config.signals.check()?;
+        if record.is_empty()
+            || !record
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record names consist of letters, digits and underscores",
+            ));
+        }
+        let signals = Channel::ALL
+            .iter()
+            .map(|channel| Signal::new(*channel, &config))
+            .collect::<io::Result<_>>()?;
+        Ok(WfdbWriter {
+            files,
+            record: record.into(),
+            start,
+            signals,
+            frames: 0,
+            pending: None,
+            resampler: Resampler::new(config.signals.sample_rate),
+            changes: Changes::new(),
+            beats: BeatDetector {
+                threshold: config.beat_threshold.value,
+                armed: false,
+                last: None,
+            },
+            annotated: 0,
+            config,
+        })
+    }
+
+    /// Write the samples of the last report, the end of the annotations and the header, returns
+    /// the underlying files
+    pub fn finish(mut self) -> io::Result<WfdbFiles<W>> {
+        if self.resampler.last.is_some() {
+            // Up to and including the last frame at or before the last report, and at least up to
+            // the sample of the last annotation, which may be the frame after it
+            let rate = self.config.signals.sample_rate as u64;
+            let end = self.resampler.time * rate / 1_000 + 1;
+            self.write_frames_until(end.max(self.annotated + 1))?;
+        }
+        if let Some(first) = self.pending.take() {
+            // The last sample of an odd count takes two bytes
+            let [low, high] = first.to_le_bytes();
+            self.files.signals.write_all(&[low, high & 0x0f])?;
+        }
+        self.files.annotations.write_all(&[0, 0])?;
+        self.write_header()?;
+        self.files.header.flush()?;
+        self.files.signals.flush()?;
+        self.files.annotations.flush()?;
+        Ok(self.files)
+    }
+
+    /// Write the frames before frame `end`, holding the last report
+    fn write_frames_until(&mut self, end: u64) -> io::Result<()> {
+        let rate = self.config.signals.sample_rate as u64;
+        let hold_until = self.resampler.time + HOLD_MS;
+        let mut bytes = Vec::new();
+        while self.frames < end {
+            let held = self
+                .resampler
+                .last
+                .as_ref()
+                .filter(|_| self.frames * 1_000 <= hold_until * rate);
+            for i in 0..self.signals.len() {
+                let sample = match held {
+                    Some(report) => self.signals[i].sample(report, &self.config),
+                    None => self.config.format.invalid(),
+                };
+                let signal = &mut self.signals[i];
+                signal.initial.get_or_insert(sample);
+                signal.checksum = signal.checksum.wrapping_add(sample);
+                match (self.config.format, self.pending.take()) {
+                    (WfdbFormat::Format16, _) => bytes.extend_from_slice(&sample.to_le_bytes()),
+                    (WfdbFormat::Format212, None) => self.pending = Some(sample),
+                    (WfdbFormat::Format212, Some(first)) => {
+                        let ([low, high], [second_low, second_high]) =
+                            (first.to_le_bytes(), sample.to_le_bytes());
+                        bytes.extend_from_slice(&[
+                            low,
+                            (high & 0x0f) | (second_high << 4),
+                            second_low,
+                        ]);
+                    }
+                }
+            }
+            self.frames += 1;
+        }
+        self.files.signals.write_all(&bytes)
+    }
+
+    /// Append an annotation at `sample`, which must not be before the previous one
+    fn annotate(&mut self, sample: u64, code: u16, aux: Option<&str>) -> io::Result<()> {
+        let out = &mut self.files.annotations;
+        let mut interval = sample - self.annotated;
+        self.annotated = sample;
+        while interval > MAX_INTERVAL {
+            // Long intervals precede the annotation, high half first
+            let skip = interval.min(i32::MAX as u64) as u32;
+            out.write_all(&(SKIP << 10).to_le_bytes())?;
+            out.write_all(&((skip >> 16) as u16).to_le_bytes())?;
+            out.write_all(&(skip as u16).to_le_bytes())?;
+            interval -= skip as u64;
+        }
+        out.write_all(&((code << 10) | interval as u16).to_le_bytes())?;
+        if let Some(aux) = aux {
+            let mut end = aux.len().min(MAX_AUX);
+            while !aux.is_char_boundary(end) {
+                end -= 1;
+            }
+            let mut bytes = aux.as_bytes()[..end].to_vec();
+            let length = bytes.len() as u16;
+            // Auxiliary strings are padded to whole words
+            if bytes.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            out.write_all(&((AUX << 10) | length).to_le_bytes())?;
+            out.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = format!(
+            "{} {} {} {}",
+            self.record,
+            self.signals.len(),
+            self.config.signals.sample_rate,
+            self.frames
+        );
+        if let Some(start) = self.start {
+            let _ = write!(
+                header,
+                " {:02}:{:02}:{:02} {:02}/{:02}/{}",
+                start.hour(),
+                start.minute(),
+                start.second(),
+                start.day(),
+                start.month(),
+                start.year()
+            );
+        }
+        header.push('\n');
+        for signal in &self.signals {
+            let _ = writeln!(
+                header,
+                "{}.dat {} {}({})/{} {} 0 {} {} 0 {}",
+                self.record,
+                self.config.format.code(),
+                signal.gain,
+                signal.baseline,
+                signal.units,
+                self.config.format.bits(),
+                signal.initial.unwrap_or(0),
+                signal.checksum,
+                signal.scale.channel.name(),
+            );
+        }
+        let _ = writeln!(header, "# love-letter {}", env!("CARGO_PKG_VERSION"));
+        self.files.header.write_all(header.as_bytes())
+    }
+}
+
+impl<W: Write> ReportWriter for WfdbWriter<W> {
+    fn write_report(&mut self, report: &Report) -> io::Result<()> {
+        let placement = self.resampler.place(report.measurements.timestamp);
+        // Frames before this report hold the last one
+        self.write_frames_until(placement.sample)?;
+        self.resampler.hold(&placement, report);
+
+        if placement.restarted {
+            self.annotate(placement.sample, NOTE, Some("device clock restarted"))?;
+        }
+        for text in self
+            .changes
+            .update(report, &self.config.signals.units)
+            .into_iter()
+            .flatten()
+        {
+            self.annotate(placement.sample, NOTE, Some(&text))?;
+        }
+        if self
+            .beats
+            .update(report.measurements.systemic_flow.value, placement.time)
+        {
+            self.annotate(placement.sample, NORMAL, None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Units;
+    use crate::testing::report_with_pressure;
+    use std::string::ToString;
+    use std::vec;
+    use uom::si::f32::Pressure;
+    use uom::si::pressure::pascal;
+    use uom::si::volume_rate::cubic_meter_per_second;
+
+    /// A report with every pressure at `pressure` pascal and the systemic flow at `flow` m³/s
+    fn report(timestamp: u64, pressure: f32, flow: f32) -> Report {
+        let mut report = report_with_pressure(timestamp, pressure);
+        report.measurements.set_value(Channel::SystemicFlow, flow);
+        report
+    }
+
+    /// 10 Hz in SI units, pressures in pascal map one to one onto samples
+    fn config(format: WfdbFormat) -> WfdbConfig {
+        let span = format.max() as f32 - (format.invalid() + 1) as f32;
+        WfdbConfig {
+            signals: SignalConfig {
+                sample_rate: 10,
+                units: Units::SI,
+                pressure_range: (Pressure::new::<pascal>(0.0), Pressure::new::<pascal>(span)),
+                ..SignalConfig::default()
+            },
+            format,
+            beat_threshold: VolumeRate::new::<cubic_meter_per_second>(1.0),
+        }
+    }
+
+    fn export(config: WfdbConfig, reports: impl IntoIterator<Item = Report>) -> WfdbFiles<Vec<u8>> {
+        let files = WfdbFiles {
+            header: Vec::new(),
+            signals: Vec::new(),
+            annotations: Vec::new(),
+        };
+        let mut writer = WfdbWriter::new("run", files, config, None).unwrap();
+        for report in reports {
+            writer.write_report(&report).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    /// Unpack format 212, a trailing pair of bytes holds the last sample of an odd count
+    fn unpack_212(bytes: &[u8]) -> Vec<i16> {
+        let extend = |sample: u16| ((sample << 4) as i16) >> 4;
+        let mut samples = Vec::new();
+        for chunk in bytes.chunks(3) {
+            samples.push(extend(chunk[0] as u16 | ((chunk[1] as u16 & 0x0f) << 8)));
+            if let [_, middle, high] = chunk {
+                samples.push(extend(*high as u16 | ((*middle as u16 >> 4) << 8)));
+            }
+        }
+        samples
+    }
+
+    /// Sample, code and auxiliary text of every annotation
+    fn annotations(bytes: &[u8]) -> Vec<(u64, u16, Option<String>)> {
+        let word = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let (mut at, mut sample, mut skip) = (0, 0, 0);
+        let mut annotations: Vec<(u64, u16, Option<String>)> = Vec::new();
+        loop {
+            let (code, value) = (word(at) >> 10, word(at) & 0x3ff);
+            at += 2;
+            match code {
+                0 if value == 0 => return annotations,
+                SKIP => {
+                    skip = ((word(at) as u64) << 16) | word(at + 2) as u64;
+                    at += 4;
+                }
+                AUX => {
+                    let text = &bytes[at..at + value as usize];
+                    annotations.last_mut().unwrap().2 =
+                        Some(String::from_utf8(text.to_vec()).unwrap());
+                    at += value.next_multiple_of(2) as usize;
+                }
+                code => {
+                    sample += skip + value as u64;
+                    skip = 0;
+                    annotations.push((sample, code, None));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn format_212_round_trips() {
+        let config = config(WfdbFormat::Format212);
+        let pressures = [0.0, 2_047.0, 4_094.0, 9_999.0, f32::NAN];
+        let reports = pressures
+            .iter()
+            .enumerate()
+            .map(|(i, pressure)| report(i as u64 * 100, *pressure, 0.0));
+        let files = export(config, reports);
+
+        // Seven signals make 35 samples, the last one is not part of a pair
+        let signals = Channel::ALL.len();
+        assert_eq!(files.signals.len(), 17 * 3 + 2);
+        let samples = unpack_212(&files.signals);
+        let regulator: Vec<i16> = samples.chunks(signals).map(|frame| frame[0]).collect();
+        assert_eq!(regulator, [-2_047, 0, 2_047, 2_047, -2_048]);
+        let systemic_flow: Vec<i16> = samples.chunks(signals).map(|frame| frame[1]).collect();
+        assert_eq!(systemic_flow, vec![0; pressures.len()]);
+    }
+
+    #[test]
+    fn format_16_holds_reports_and_marks_gaps_invalid() {
+        let config = config(WfdbFormat::Format16);
+        let files = export(config, [report(0, 10.0, 0.0), report(2_500, 20.0, 0.0)]);
+        let regulator: Vec<i16> = files
+            .signals
+            .chunks(2 * Channel::ALL.len())
+            .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+            .collect();
+        let baseline = WfdbFormat::Format16.invalid() + 1;
+        let mut expected = vec![baseline + 10; 11];
+        expected.resize(25, i16::MIN);
+        expected.push(baseline + 20);
+        assert_eq!(regulator, expected);
+    }
+
+    #[test]
+    fn header_describes_every_signal() {
+        let config = config(WfdbFormat::Format212);
+        let files = export(config, (0..20).map(|n| report(n * 100, 2_047.0, 0.0)));
+        let header = String::from_utf8(files.header).unwrap();
+        let lines: Vec<&str> = header.lines().collect();
+        assert_eq!(lines[0], "run 7 10 20");
+        // Gain 1 and baseline -2047, initial value and checksum of twenty samples of 0
+        assert_eq!(
+            lines[1],
+            "run.dat 212 1(-2047)/Pa 12 0 0 0 0 regulator_actual_pressure"
+        );
+        assert!(lines[2].starts_with("run.dat 212 614100(0)/m3/s 12 0 0 0 0 "));
+        assert_eq!(lines.len(), Channel::ALL.len() + 2);
+        assert!(lines[8].starts_with("# love-letter "));
+    }
+
+    #[test]
+    fn annotates_beats_changes_and_restarts() {
+        let config = config(WfdbFormat::Format16);
+        let mut reports = vec![
+            report(0, 0.0, 0.0),
+            report(100, 0.0, 2.0),
+            report(300, 0.0, 0.0),
+            report(2_000, 0.0, 2.0),
+        ];
+        // The device restarts, time goes on at the next sample
+        reports.push(report(50, 0.0, 0.0));
+        let files = export(config, reports);
+        let annotations = annotations(&files.annotations);
+        let note = |sample, text: &str| (sample, NOTE, Some(text.to_string()));
+        assert_eq!(
+            annotations,
+            [
+                note(0, "standby"),
+                note(0, "setpoint #0"),
+                (1, NORMAL, None),
+                (20, NORMAL, None),
+                note(21, "device clock restarted"),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_frames_up_to_the_last_annotation() {
+        let config = config(WfdbFormat::Format16);
+        let mut last = report(1_050, 0.0, 0.0);
+        last.setpoint.seq = 1;
+        let files = export(config, [report(0, 0.0, 0.0), last]);
+        // The change is annotated at the first sample after 1.05 s
+        let annotated = annotations(&files.annotations).last().unwrap().0;
+        assert_eq!(annotated, 11);
+        let header = String::from_utf8(files.header).unwrap();
+        assert!(header.starts_with("run 7 10 12\n"));
+        assert_eq!(files.signals.len(), 12 * Channel::ALL.len() * 2);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let new = |record: &str, config| {
+            let files = WfdbFiles {
+                header: Vec::new(),
+                signals: Vec::new(),
+                annotations: Vec::new(),
+            };
+            WfdbWriter::new(record, files, config, None).map(|_| ())
+        };
+        let pressure = |min, max| (Pressure::new::<pascal>(min), Pressure::new::<pascal>(max));
+        let with_signals = |signals| WfdbConfig {
+            signals,
+            ..config(WfdbFormat::Format16)
+        };
+        let signals = config(WfdbFormat::Format16).signals;
+        for config in [
+            with_signals(SignalConfig {
+                sample_rate: 0,
+                ..signals
+            }),
+            with_signals(SignalConfig {
+                pressure_range: pressure(2.0, 1.0),
+                ..signals
+            }),
+            with_signals(SignalConfig {
+                pressure_range: pressure(f32::NEG_INFINITY, 1.0),
+                ..signals
+            }),
+            // The gain is written as 0
+            with_signals(SignalConfig {
+                pressure_range: pressure(0.0, 1e12),
+                ..signals
+            }),
+        ] {
+            let error = new("run", config).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        let error = new("run 1", config(WfdbFormat::Format16)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,95 @@
+//! Structured fault descriptors, reported in [`AppState::Fault`](crate::AppState::Fault).
+
+use core::fmt;
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::Channel;
+use crate::validation::Field;
+
+/// Why the device entered [`AppState::Fault`](crate::AppState::Fault)
+#[derive(PartialEq, Eq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub enum FaultKind {
+    /// A pressure exceeded its safety limit
+    OverPressure,
+    /// A sensor stopped responding or reads an impossible value
+    SensorDisconnected(Channel),
+    /// The pressure regulator did not reach its setpoint in time
+    RegulatorTimeout,
+    /// No valid message was received from the host in time
+    CommunicationLoss,
+    /// A setpoint was rejected, see [`crate::validation`]
+    InvalidSetpoint(Field),
+    /// Firmware specific fault code
+    Other(u16),
+    /// The operator requested an emergency stop
+    EmergencyStop,
+}
+
+/// A single measured value, in SI base units (Pa or m³/s)
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub struct Reading {
+    pub channel: Channel,
+    pub value: f32,
+}
+
+impl fmt::Display for Reading {
+    /// Uses the clinical units: mmHg for pressures and L/min for flows
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use uom::si::f32::{Pressure, VolumeRate};
+        use uom::si::pressure::{millimeter_of_mercury, pascal};
+        use uom::si::volume_rate::{cubic_meter_per_second, liter_per_minute};
+
+        match self.channel.is_flow() {
+            true => write!(
+                f,
+                "{} = {:.2} L/min",
+                self.channel.name(),
+                VolumeRate::new::<cubic_meter_per_second>(self.value).get::<liter_per_minute>(),
+            ),
+            false => write!(
+                f,
+                "{} = {:.1} mmHg",
+                self.channel.name(),
+                Pressure::new::<pascal>(self.value).get::<millimeter_of_mercury>(),
+            ),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Milliseconds since boot of mcu at which the fault occurred
+    pub timestamp: u64,
+    /// The measurement that triggered the fault, if any
+    pub reading: Option<Reading>,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultKind::OverPressure => write!(f, "over-pressure"),
+            FaultKind::SensorDisconnected(channel) => {
+                write!(f, "sensor disconnected ({})", channel.name())
+            }
+            FaultKind::RegulatorTimeout => write!(f, "pressure regulator timed out"),
+            FaultKind::CommunicationLoss => write!(f, "communication with host lost"),
+            FaultKind::InvalidSetpoint(field) => write!(f, "invalid setpoint ({})", field.name()),
+            FaultKind::Other(code) => write!(f, "firmware fault code {code}"),
+            FaultKind::EmergencyStop => write!(f, "emergency stop"),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} ms", self.kind, self.timestamp)?;
+        if let Some(reading) = &self.reading {
+            write!(f, ": {reading}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Frame level encoding: postcard, an optional CRC trailer and COBS.
+//!
+//! A frame on the wire is `cobs(postcard(value) ++ crc_le) ++ 0x00`. The CRC is computed over the
+//! postcard bytes before COBS encoding, so a corrupted byte anywhere in the frame is caught on
+//! decode and reported as [`postcard::Error::DeserializeBadCrc`].
+
+use crc::{CRC_16_IBM_3740, CRC_32_ISO_HDLC, Crc};
+use defmt::Format;
+use postcard::de_flavors::crc as de_crc;
+use postcard::experimental::max_size::MaxSize;
+use postcard::ser_flavors::{Cobs, Slice, crc::CrcModifier};
+use serde::{Deserialize, Serialize};
+
+/// CRC-16/CCITT-FALSE
+pub static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+/// CRC-32 as used by ethernet and zlib
+pub static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Integrity check appended to every frame, both ends of the link must use the same one
+#[derive(PartialEq, Eq, Clone, Copy, Format, Default, Debug)]
+pub enum Checksum {
+    /// COBS framing only
+    #[default]
+    None,
+    Crc16,
+    Crc32,
+}
+
+impl Checksum {
+    /// Number of trailer bytes this checksum adds to a frame
+    pub const fn len(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    pub const fn is_empty(self) -> bool {
+        matches!(self, Checksum::None)
+    }
+}
+
+/// The fixed frame of [`Message::EmergencyStop`](crate::Message::EmergencyStop), including the
+/// delimiter. It is the COBS encoding of the variant tag followed by a marker and carries no
+/// checksum, so it is sent the same way on every link and receivers recognise it by its bytes
+/// alone, even when it cuts off a partially sent frame
+pub const EMERGENCY_STOP_FRAME: [u8; 10] =
+    [0x09, 0x09, b'E', b'-', b'S', b'T', b'O', b'P', b'!', 0x00];
+
+/// Whether `buf` ends with the [`EMERGENCY_STOP_FRAME`], the trailing delimiter is optional.
+/// Anything before it is the remainder of an aborted frame
+pub fn is_emergency_stop(buf: &[u8]) -> bool {
+    let buf = buf.strip_suffix(&[0]).unwrap_or(buf);
+    buf.ends_with(&EMERGENCY_STOP_FRAME[..EMERGENCY_STOP_FRAME.len() - 1])
+}
+
+/// Worst case length of a frame whose postcard encoding is at most `payload` bytes, including the
+/// `checksum` trailer, COBS overhead and the delimiter
+pub const fn max_frame_len(payload: usize, checksum: Checksum) -> usize {
+    cobs::max_encoding_length(payload + checksum.len()) + 1
+}
+
+/// Worst case length of a frame holding a `T` wrapped in its [`crate::Message`] variant, assuming the
+/// largest checksum so the result holds for every link configuration
+pub const fn max_message_len<T: MaxSize>() -> usize {
+    // Variant tags are varints, a single byte as long as there are less than 128 message kinds
+    max_frame_len(1 + T::POSTCARD_MAX_SIZE, Checksum::Crc32)
+}
+
+/// Serialize `value` into `buf` as a complete frame, including the trailing `0x00` delimiter
+pub fn encode<'a, T: Serialize + ?Sized>(
+    value: &T,
+    checksum: Checksum,
+    buf: &'a mut [u8],
+) -> postcard::Result<&'a mut [u8]> {
+    match checksum {
+        Checksum::None => postcard::to_slice_cobs(value, buf),
+        Checksum::Crc16 => postcard::serialize_with_flavor(
+            value,
+            CrcModifier::new(Cobs::try_new(Slice::new(buf))?, CRC16.digest()),
+        ),
+        Checksum::Crc32 => postcard::serialize_with_flavor(
+            value,
+            CrcModifier::new(Cobs::try_new(Slice::new(buf))?, CRC32.digest()),
+        ),
+    }
+}
+
+/// Deserialize a single frame, `buf` is COBS decoded in place.
+/// A trailing `0x00` delimiter is optional
+pub fn decode<'a, T: Deserialize<'a>>(
+    buf: &'a mut [u8],
+    checksum: Checksum,
+) -> postcard::Result<T> {
+    let len = cobs::decode_in_place(buf).map_err(|_| postcard::Error::DeserializeBadEncoding)?;
+    let payload = &buf[..len];
+    match checksum {
+        Checksum::None => postcard::from_bytes(payload),
+        Checksum::Crc16 => de_crc::from_bytes_u16(payload, CRC16.digest()),
+        Checksum::Crc32 => de_crc::from_bytes_u32(payload, CRC32.digest()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUMS: [Checksum; 3] = [Checksum::None, Checksum::Crc16, Checksum::Crc32];
+    const VALUE: (u16, u32, bool) = (0x1234, 0xdead_beef, true);
+
+    #[test]
+    fn round_trips_with_every_checksum() {
+        for checksum in CHECKSUMS {
+            let mut buf = [0; 32];
+            let frame = encode(&VALUE, checksum, &mut buf).unwrap();
+            assert_eq!(frame.last(), Some(&0));
+            assert_eq!(decode::<(u16, u32, bool)>(frame, checksum), Ok(VALUE));
+        }
+    }
+
+    #[test]
+    fn trailer_fits_max_frame_len() {
+        let mut raw = [0; 32];
+        let payload = postcard::to_slice(&VALUE, &mut raw).unwrap().len();
+        for checksum in CHECKSUMS {
+            let mut buf = [0; 32];
+            let len = encode(&VALUE, checksum, &mut buf).unwrap().len();
+            // COBS adds a single code byte to frames this short, plus the delimiter
+            assert_eq!(len, payload + checksum.len() + 2);
+            assert!(len <= max_frame_len(payload, checksum));
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_fails_crc() {
+        for checksum in [Checksum::Crc16, Checksum::Crc32] {
+            let mut buf = [0; 32];
+            let len = encode(&VALUE, checksum, &mut buf).unwrap().len();
+            // Every byte between the first COBS code and the delimiter
+            for i in 1..len - 1 {
+                let mut frame = buf;
+                frame[i] ^= if frame[i] == 0x80 { 0x40 } else { 0x80 };
+                assert!(decode::<(u16, u32, bool)>(&mut frame[..len], checksum).is_err());
+            }
+            // The first payload byte is data, not a COBS code
+            let mut frame = buf;
+            frame[1] ^= 0x01;
+            assert_eq!(
+                decode::<(u16, u32, bool)>(&mut frame[..len], checksum),
+                Err(postcard::Error::DeserializeBadCrc)
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let mut buf = [0; 32];
+        let len = encode(&VALUE, Checksum::Crc32, &mut buf).unwrap().len();
+        assert_eq!(
+            decode::<(u16, u32, bool)>(&mut buf[..len], Checksum::Crc16),
+            Err(postcard::Error::DeserializeBadCrc)
+        );
+    }
+
+    #[test]
+    fn delimiter_is_optional() {
+        let mut buf = [0; 32];
+        let len = encode(&VALUE, Checksum::Crc16, &mut buf).unwrap().len();
+        assert_eq!(
+            decode::<(u16, u32, bool)>(&mut buf[..len - 1], Checksum::Crc16),
+            Ok(VALUE)
+        );
+    }
+
+    #[test]
+    fn emergency_stop_frame_is_recognised_after_garbage() {
+        assert!(is_emergency_stop(&EMERGENCY_STOP_FRAME));
+        assert!(is_emergency_stop(
+            &EMERGENCY_STOP_FRAME[..EMERGENCY_STOP_FRAME.len() - 1]
+        ));
+        let mut buf = [0x42; 20];
+        buf[20 - EMERGENCY_STOP_FRAME.len()..].copy_from_slice(&EMERGENCY_STOP_FRAME);
+        assert!(is_emergency_stop(&buf));
+        assert!(!is_emergency_stop(&EMERGENCY_STOP_FRAME[1..5]));
+    }
+
+    #[test]
+    fn emergency_stop_frame_decodes_with_every_checksum() {
+        for checksum in CHECKSUMS {
+            let mut buf = [0; crate::MESSAGE_BYTES];
+            let frame =
+                crate::serialize_message_checked(crate::Message::EmergencyStop, checksum, &mut buf)
+                    .unwrap();
+            assert_eq!(frame, EMERGENCY_STOP_FRAME);
+            assert!(matches!(
+                crate::deserialize_message_checked(frame, checksum),
+                Ok(crate::Message::EmergencyStop)
+            ));
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Protocol version handshake.
+//!
+//! Before streaming, the host sends a [`Hello`] describing its build and the device answers with a
+//! [`HelloAck`] describing its own. Both ends must agree on [`PROTOCOL_VERSION`] and
+//! [`SCHEMA_HASH`], otherwise postcard would happily decode frames into the wrong fields.
+
+use core::fmt;
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+use crate::schema::SCHEMA_HASH;
+
+/// Version of the protocol semantics, bump this on behavioural changes that do not show up in the
+/// wire types themselves
+pub const PROTOCOL_VERSION: u16 = 1;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, Eq, MaxSize)]
+pub struct CrateVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl CrateVersion {
+    /// Version of the love-letter crate this was built against
+    pub const CURRENT: CrateVersion = CrateVersion {
+        major: parse_u16(env!("CARGO_PKG_VERSION_MAJOR")),
+        minor: parse_u16(env!("CARGO_PKG_VERSION_MINOR")),
+        patch: parse_u16(env!("CARGO_PKG_VERSION_PATCH")),
+    };
+}
+
+impl fmt::Display for CrateVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+const fn parse_u16(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    let mut value = 0u16;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "crate version is not numeric");
+        value = value * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+    value
+}
+
+/// Identity of one end of the link, sent by the host to open a session
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, Eq, MaxSize)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub crate_version: CrateVersion,
+    /// See [`crate::schema`]
+    pub schema_hash: u32,
+}
+
+impl Hello {
+    /// Identity of this build
+    pub const fn local() -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            crate_version: CrateVersion::CURRENT,
+            schema_hash: SCHEMA_HASH,
+        }
+    }
+
+    /// Check whether a peer announcing `remote` can talk to us
+    pub fn check_compatible(&self, remote: &Hello) -> Result<(), HandshakeError> {
+        if self.protocol_version != remote.protocol_version {
+            return Err(HandshakeError::ProtocolMismatch {
+                local: *self,
+                remote: *remote,
+            });
+        }
+        if self.schema_hash != remote.schema_hash {
+            return Err(HandshakeError::SchemaMismatch {
+                local: *self,
+                remote: *remote,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Answer to a [`Hello`], carrying the identity of the responder and whether it accepted the peer
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, Eq, MaxSize)]
+pub struct HelloAck {
+    pub hello: Hello,
+    pub accepted: bool,
+}
+
+impl HelloAck {
+    /// Device side: build the answer to a received [`Hello`]
+    pub fn respond(remote: &Hello) -> Self {
+        let local = Hello::local();
+        HelloAck {
+            hello: local,
+            accepted: local.check_compatible(remote).is_ok(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// Both ends speak a different protocol revision
+    ProtocolMismatch { local: Hello, remote: Hello },
+    /// Both ends were built against different message definitions
+    SchemaMismatch { local: Hello, remote: Hello },
+    /// The peer refused our [`Hello`]
+    Rejected { remote: Hello },
+    /// Attempted to stream before the handshake completed
+    NotEstablished,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::ProtocolMismatch { local, remote } => write!(
+                f,
+                "protocol version mismatch: local v{} (love-letter {}), remote v{} (love-letter {})",
+                local.protocol_version,
+                local.crate_version,
+                remote.protocol_version,
+                remote.crate_version,
+            ),
+            HandshakeError::SchemaMismatch { local, remote } => write!(
+                f,
+                "message schema mismatch: local {:#010x} (love-letter {}), remote {:#010x} (love-letter {}), rebuild both ends against the same love-letter revision",
+                local.schema_hash, local.crate_version, remote.schema_hash, remote.crate_version,
+            ),
+            HandshakeError::Rejected { remote } => write!(
+                f,
+                "peer running love-letter {} (protocol v{}, schema {:#010x}) rejected our hello",
+                remote.crate_version, remote.protocol_version, remote.schema_hash,
+            ),
+            HandshakeError::NotEstablished => write!(f, "handshake not completed"),
+        }
+    }
+}
+
+impl core::error::Error for HandshakeError {}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Idle,
+    AwaitingAck,
+    Established(Hello),
+    Failed(HandshakeError),
+}
+
+/// Host side handshake helper.
+///
+/// Call [`Handshake::start`] and send the returned message, then feed every received message to
+/// [`Handshake::handle`]. Until a compatible [`HelloAck`] arrived, [`Handshake::ensure_established`]
+/// refuses with an error describing why.
+#[derive(Clone, Copy, Format, Debug, Default)]
+pub struct Handshake {
+    state: State,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// (Re)start the handshake, returns the [`Hello`] to send to the device
+    pub fn start(&mut self) -> Message {
+        self.state = State::AwaitingAck;
+        Message::Hello(Hello::local())
+    }
+
+    /// Process a received message. Returns the identity of the device once the handshake completes,
+    /// messages unrelated to the handshake are ignored
+    pub fn handle(&mut self, message: &Message) -> Result<Option<Hello>, HandshakeError> {
+        let Message::HelloAck(ack) = message else {
+            return Ok(None);
+        };
+        if self.state != State::AwaitingAck {
+            return Ok(None);
+        }
+
+        let result = Hello::local()
+            .check_compatible(&ack.hello)
+            .and(match ack.accepted {
+                true => Ok(ack.hello),
+                false => Err(HandshakeError::Rejected { remote: ack.hello }),
+            });
+        self.state = match result {
+            Ok(remote) => State::Established(remote),
+            Err(err) => State::Failed(err),
+        };
+        result.map(Some)
+    }
+
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, State::Established(_))
+    }
+
+    /// Returns the identity of the device, or why streaming is not allowed yet
+    pub fn ensure_established(&self) -> Result<Hello, HandshakeError> {
+        match self.state {
+            State::Established(remote) => Ok(remote),
+            State::Failed(err) => Err(err),
+            State::Idle | State::AwaitingAck => Err(HandshakeError::NotEstablished),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(hello: Hello) -> Message {
+        Message::HelloAck(HelloAck::respond(&hello))
+    }
+
+    #[test]
+    fn completes_with_a_compatible_device() {
+        let mut handshake = Handshake::new();
+        let Message::Hello(hello) = handshake.start() else {
+            panic!("start sends a hello");
+        };
+        assert_eq!(hello, Hello::local());
+        assert_eq!(handshake.handle(&ack(hello)), Ok(Some(Hello::local())));
+        assert!(handshake.is_established());
+        assert_eq!(handshake.ensure_established(), Ok(Hello::local()));
+    }
+
+    #[test]
+    fn not_established_before_the_ack() {
+        let mut handshake = Handshake::new();
+        assert_eq!(
+            handshake.ensure_established(),
+            Err(HandshakeError::NotEstablished)
+        );
+        handshake.start();
+        assert_eq!(handshake.handle(&Message::EmergencyStop), Ok(None));
+        assert!(!handshake.is_established());
+        assert_eq!(
+            handshake.ensure_established(),
+            Err(HandshakeError::NotEstablished)
+        );
+    }
+
+    #[test]
+    fn fails_on_protocol_mismatch() {
+        let device = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::local()
+        };
+        let mut handshake = Handshake::new();
+        handshake.start();
+        let error = HandshakeError::ProtocolMismatch {
+            local: Hello::local(),
+            remote: device,
+        };
+        let ack = Message::HelloAck(HelloAck {
+            hello: device,
+            accepted: true,
+        });
+        assert_eq!(handshake.handle(&ack), Err(error));
+        assert_eq!(handshake.ensure_established(), Err(error));
+    }
+
+    #[test]
+    fn fails_on_schema_mismatch() {
+        let device = Hello {
+            schema_hash: !SCHEMA_HASH,
+            ..Hello::local()
+        };
+        // The device refuses the host just as well
+        assert!(!HelloAck::respond(&device).accepted);
+        let mut handshake = Handshake::new();
+        handshake.start();
+        let error = HandshakeError::SchemaMismatch {
+            local: Hello::local(),
+            remote: device,
+        };
+        let ack = Message::HelloAck(HelloAck {
+            hello: device,
+            accepted: true,
+        });
+        assert_eq!(handshake.handle(&ack), Err(error));
+        assert!(!handshake.is_established());
+    }
+
+    #[test]
+    fn fails_when_the_device_rejects_us() {
+        let mut handshake = Handshake::new();
+        handshake.start();
+        let rejection = Message::HelloAck(HelloAck {
+            hello: Hello::local(),
+            accepted: false,
+        });
+        let error = HandshakeError::Rejected {
+            remote: Hello::local(),
+        };
+        assert_eq!(handshake.handle(&rejection), Err(error));
+        assert_eq!(handshake.ensure_established(), Err(error));
+
+        // Starting over clears the failure
+        handshake.start();
+        assert_eq!(
+            handshake.handle(&ack(Hello::local())),
+            Ok(Some(Hello::local()))
+        );
+    }
+
+    #[test]
+    fn ignores_acks_outside_awaiting_ack() {
+        let mut handshake = Handshake::new();
+        assert_eq!(handshake.handle(&ack(Hello::local())), Ok(None));
+        assert!(!handshake.is_established());
+
+        handshake.start();
+        handshake.handle(&ack(Hello::local())).unwrap();
+        let rejection = Message::HelloAck(HelloAck {
+            hello: Hello::local(),
+            accepted: false,
+        });
+        assert_eq!(handshake.handle(&rejection), Ok(None));
+        assert!(handshake.is_established());
+    }
+}
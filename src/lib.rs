@@ -1,60 +1,205 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod accumulator;
+pub mod command;
+pub mod delivery;
+#[cfg(feature = "std")]
+pub mod export;
+pub mod fault;
+pub mod frame;
+pub mod handshake;
+pub mod link;
+pub mod queue;
+#[cfg(feature = "std")]
+pub mod recording;
+pub mod schema;
+#[cfg(feature = "std")]
+pub mod session;
+pub mod simulator;
+pub mod state;
+pub mod transport;
+pub mod units;
+pub mod validation;
+
+#[cfg(test)]
+mod testing;
+
+pub use accumulator::{FrameAccumulator, FrameError, FrameStats};
+pub use command::{Command, CommandAck};
+pub use delivery::{DeliveryError, SetpointAck, SetpointSender, SetpointStatus};
+pub use fault::{Fault, FaultKind, Reading};
+pub use frame::Checksum;
+pub use handshake::{Handshake, HandshakeError, Hello, HelloAck, PROTOCOL_VERSION};
+pub use link::{FailSafeAction, FailSafePolicy, Heartbeat, Link, LinkConfig, LinkEvent, Watchdog};
+pub use queue::{OutboundQueue, Priority};
+pub use schema::SCHEMA_HASH;
+pub use state::{Event, IllegalTransition, StateMachine};
+pub use transport::{AsyncTransport, Framed, Transport, TransportError};
+pub use validation::{SetpointBuilder, SetpointLimits, ValidatedSetpoint, ValidationError};
+
 use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
+use units::{ComplianceUnit, HydraulicCompliance, HydraulicResistance, ResistanceUnit};
 use uom::si::{
     f32::{Frequency, Pressure, VolumeRate},
     frequency::hertz,
     pressure::millibar,
 };
 
-pub const REPORT_BYTES: usize = core::mem::size_of::<Report>();
-pub const SETPOINT_BYTES: usize = core::mem::size_of::<Setpoint>();
+/// Worst case encoded frame length of a [`Report`], see [`frame::max_message_len`]
+pub const REPORT_BYTES: usize = frame::max_message_len::<Report>();
+/// Worst case encoded frame length of a [`Setpoint`], see [`frame::max_message_len`]
+pub const SETPOINT_BYTES: usize = frame::max_message_len::<Setpoint>();
+/// Worst case encoded frame length of any [`Message`], size receive buffers and
+/// [`FrameAccumulator`]s from this
+pub const MESSAGE_BYTES: usize = frame::max_frame_len(Message::POSTCARD_MAX_SIZE, Checksum::Crc32);
+/// Size of the firmware UART DMA buffers, every frame must fit in a single transfer
+pub const DMA_BUFFER_BYTES: usize = 256;
 pub const BAUDRATE: u32 = 115200;
 
 pub const SYSTOLE_RATIO_DEFAULT: f32 = 3.0 / 7.0;
 
+const _: () = assert!(REPORT_BYTES <= DMA_BUFFER_BYTES);
+const _: () = assert!(SETPOINT_BYTES <= DMA_BUFFER_BYTES);
+const _: () = assert!(MESSAGE_BYTES <= DMA_BUFFER_BYTES);
+
+pub fn serialize_message(message: Message, buf: &mut [u8]) -> postcard::Result<&mut [u8]> {
+    serialize_message_checked(message, Checksum::None, buf)
+}
+
+pub fn deserialize_message(buf: &mut [u8]) -> postcard::Result<Message> {
+    deserialize_message_checked(buf, Checksum::None)
+}
+
+/// Like [`serialize_message`], with a `checksum` trailer appended before COBS encoding
+pub fn serialize_message_checked(
+    message: Message,
+    checksum: Checksum,
+    buf: &mut [u8],
+) -> postcard::Result<&mut [u8]> {
+    if let Message::EmergencyStop = message {
+        let frame = buf
+            .get_mut(..frame::EMERGENCY_STOP_FRAME.len())
+            .ok_or(postcard::Error::SerializeBufferFull)?;
+        frame.copy_from_slice(&frame::EMERGENCY_STOP_FRAME);
+        return Ok(frame);
+    }
+    frame::encode(&message, checksum, buf)
+}
+
+/// Like [`deserialize_message`], verifying the `checksum` trailer.
+/// A corrupted frame fails with [`postcard::Error::DeserializeBadCrc`] and must be dropped
+pub fn deserialize_message_checked(
+    buf: &mut [u8],
+    checksum: Checksum,
+) -> postcard::Result<Message> {
+    if frame::is_emergency_stop(buf) {
+        return Ok(Message::EmergencyStop);
+    }
+    frame::decode(buf, checksum)
+}
+
 pub fn serialize_report(report: Report, buf: &mut [u8]) -> postcard::Result<&mut [u8]> {
-    postcard::to_slice_cobs(&report, buf)
+    serialize_message(Message::Report(report), buf)
 }
 
+/// Deserialize a frame that is expected to hold a [`Report`], any other message kind is
+/// rejected with [`postcard::Error::DeserializeBadEnum`]
 pub fn deserialize_report(buf: &mut [u8]) -> postcard::Result<Report> {
-    postcard::from_bytes_cobs(buf)
+    match deserialize_message(buf)? {
+        Message::Report(report) => Ok(report),
+        _ => Err(postcard::Error::DeserializeBadEnum),
+    }
 }
 
 pub fn serialize_setpoint(setpoint: Setpoint, buf: &mut [u8]) -> postcard::Result<&mut [u8]> {
-    postcard::to_slice_cobs(&setpoint, buf)
+    serialize_message(Message::Setpoint(setpoint), buf)
 }
 
+/// Deserialize a frame that is expected to hold a [`Setpoint`], any other message kind is
+/// rejected with [`postcard::Error::DeserializeBadEnum`]
 pub fn deserialize_setpoint(buf: &mut [u8]) -> postcard::Result<Setpoint> {
-    postcard::from_bytes_cobs(buf)
+    match deserialize_message(buf)? {
+        Message::Setpoint(setpoint) => Ok(setpoint),
+        _ => Err(postcard::Error::DeserializeBadEnum),
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Format, Debug)]
+/// Top level envelope for everything sent over the link, in either direction.
+/// The variant index is the first byte of every frame so receivers can dispatch on it.
+/// NOTE: only ever append new variants, reordering changes the wire tags
+#[derive(Deserialize, Serialize, Clone, Format, Debug, MaxSize)]
+pub enum Message {
+    /// Device -> host status report
+    Report(Report),
+    /// Host -> device controller setpoint
+    Setpoint(Setpoint),
+    /// Host -> device, opens a session, see [`handshake`]
+    Hello(Hello),
+    /// Device -> host, answer to [`Message::Hello`]
+    HelloAck(HelloAck),
+    /// Host -> device operator command, see [`command`]
+    Command(Command),
+    /// Device -> host, answer to [`Message::Command`]
+    CommandAck(CommandAck),
+    /// Device -> host, answer to [`Message::Setpoint`]
+    SetpointAck(SetpointAck),
+    /// Keep-alive in either direction, see [`link`]
+    Heartbeat(Heartbeat),
+    /// Host -> device link supervision settings and fail-safe policy
+    LinkConfig(LinkConfig),
+    /// Host -> device, stop immediately. Always sent as [`frame::EMERGENCY_STOP_FRAME`] and handled
+    /// like [`Command::EmergencyStop`]
+    EmergencyStop,
+}
+
+impl From<Report> for Message {
+    fn from(report: Report) -> Self {
+        Message::Report(report)
+    }
+}
+
+impl From<Setpoint> for Message {
+    fn from(setpoint: Setpoint) -> Self {
+        Message::Setpoint(setpoint)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Format, Debug, MaxSize)]
 pub struct Report {
     pub setpoint: Setpoint,
     pub app_state: AppState,
     pub measurements: Measurements,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+/// Controller parameters. A `None` field leaves that controller's current parameters unchanged,
+/// use a [`Command`] to start or stop
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, MaxSize)]
 pub struct Setpoint {
     // pub current_time: DateTimeWrapper,
+    /// Assigned by the sender and echoed in the [`SetpointAck`], see [`delivery`]. Optional in
+    /// self-describing formats such as JSON setpoint files
+    #[serde(default)]
+    pub seq: u16,
     pub mockloop_setpoint: Option<MockloopSetpoint>,
     pub heart_controller_setpoint: Option<HeartControllerSetpoint>,
 }
 
 /// Setpoint for the mockloop hemodynamics controller
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub struct MockloopSetpoint {
-    pub systemic_resistance: f32,
-    pub pulmonary_resistance: f32,
-    pub systemic_afterload_compliance: f32,
-    pub pulmonary_afterload_compliance: f32,
+    pub systemic_resistance: HydraulicResistance,
+    pub pulmonary_resistance: HydraulicResistance,
+    pub systemic_afterload_compliance: HydraulicCompliance,
+    pub pulmonary_afterload_compliance: HydraulicCompliance,
 }
 
 /// Setpoint for the pneumatic heart prototype controller
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct HeartControllerSetpoint {
     /// Desired heart rate
     pub heart_rate: Frequency,
@@ -78,12 +223,96 @@ pub struct Measurements {
     pub pulmonary_afterload_pressure: Pressure,
 }
 
-#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Default, Debug)]
+impl Measurements {
+    /// Value of `channel` in SI base units (Pa or m³/s)
+    pub fn value(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::RegulatorActualPressure => self.regulator_actual_pressure.value,
+            Channel::SystemicFlow => self.systemic_flow.value,
+            Channel::PulmonaryFlow => self.pulmonary_flow.value,
+            Channel::SystemicPreloadPressure => self.systemic_preload_pressure.value,
+            Channel::SystemicAfterloadPressure => self.systemic_afterload_pressure.value,
+            Channel::PulmonaryPreloadPressure => self.pulmonary_preload_pressure.value,
+            Channel::PulmonaryAfterloadPressure => self.pulmonary_afterload_pressure.value,
+        }
+    }
+
+    /// Set `channel` to `value` in SI base units (Pa or m³/s)
+    pub fn set_value(&mut self, channel: Channel, value: f32) {
+        let field = match channel {
+            Channel::RegulatorActualPressure => &mut self.regulator_actual_pressure.value,
+            Channel::SystemicFlow => &mut self.systemic_flow.value,
+            Channel::PulmonaryFlow => &mut self.pulmonary_flow.value,
+            Channel::SystemicPreloadPressure => &mut self.systemic_preload_pressure.value,
+            Channel::SystemicAfterloadPressure => &mut self.systemic_afterload_pressure.value,
+            Channel::PulmonaryPreloadPressure => &mut self.pulmonary_preload_pressure.value,
+            Channel::PulmonaryAfterloadPressure => &mut self.pulmonary_afterload_pressure.value,
+        };
+        *field = value;
+    }
+}
+
+/// Identifies one of the sensor channels in [`Measurements`]
+#[derive(PartialEq, Eq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub enum Channel {
+    RegulatorActualPressure,
+    SystemicFlow,
+    PulmonaryFlow,
+    SystemicPreloadPressure,
+    SystemicAfterloadPressure,
+    PulmonaryPreloadPressure,
+    PulmonaryAfterloadPressure,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 7] = [
+        Channel::RegulatorActualPressure,
+        Channel::SystemicFlow,
+        Channel::PulmonaryFlow,
+        Channel::SystemicPreloadPressure,
+        Channel::SystemicAfterloadPressure,
+        Channel::PulmonaryPreloadPressure,
+        Channel::PulmonaryAfterloadPressure,
+    ];
+
+    /// Name of the matching [`Measurements`] field
+    pub const fn name(self) -> &'static str {
+        match self {
+            Channel::RegulatorActualPressure => "regulator_actual_pressure",
+            Channel::SystemicFlow => "systemic_flow",
+            Channel::PulmonaryFlow => "pulmonary_flow",
+            Channel::SystemicPreloadPressure => "systemic_preload_pressure",
+            Channel::SystemicAfterloadPressure => "systemic_afterload_pressure",
+            Channel::PulmonaryPreloadPressure => "pulmonary_preload_pressure",
+            Channel::PulmonaryAfterloadPressure => "pulmonary_afterload_pressure",
+        }
+    }
+
+    /// Flow channels hold a [`VolumeRate`], all others a [`Pressure`]
+    pub const fn is_flow(self) -> bool {
+        matches!(self, Channel::SystemicFlow | Channel::PulmonaryFlow)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Default, Debug, MaxSize)]
 pub enum AppState {
     #[default]
     StandBy,
     Running(u32), // Frequency in Hz
-    Fault,
+    Fault(Fault),
+}
+
+// MaxSize impls from here, uom quantities are serialized as their bare f32 value
+impl MaxSize for MockloopSetpoint {
+    const POSTCARD_MAX_SIZE: usize = 4 * f32::POSTCARD_MAX_SIZE;
+}
+
+impl MaxSize for HeartControllerSetpoint {
+    const POSTCARD_MAX_SIZE: usize = 3 * f32::POSTCARD_MAX_SIZE;
+}
+
+impl MaxSize for Measurements {
+    const POSTCARD_MAX_SIZE: usize = u64::POSTCARD_MAX_SIZE + 7 * f32::POSTCARD_MAX_SIZE;
 }
 
 // Format impls from here
@@ -116,7 +345,7 @@ impl Format for Setpoint {
     fn format(&self, fmt: defmt::Formatter) {
         use defmt::write;
 
-        write!(fmt, "Setpoint( Heart: ");
+        write!(fmt, "Setpoint( #{} Heart: ", self.seq);
         match &self.heart_controller_setpoint {
             Some(sp) => write!(
                 fmt,
@@ -125,19 +354,21 @@ impl Format for Setpoint {
                 sp.pressure.get::<millibar>(),
                 sp.systole_ratio,
             ),
-            None => write!(fmt, "DISABLED"),
+            None => write!(fmt, "unchanged"),
         };
         write!(fmt, " - Loop: ");
         match &self.mockloop_setpoint {
             Some(sp) => write!(
                 fmt,
-                "(resistance sys/pul: {}/{}, compliance sys/pul {}/{})",
-                sp.systemic_resistance,
-                sp.pulmonary_resistance,
-                sp.systemic_afterload_compliance,
-                sp.pulmonary_afterload_compliance,
+                "(resistance sys/pul: {}/{}WU, compliance sys/pul {}/{}mL/mmHg)",
+                ResistanceUnit::WoodUnit.get(sp.systemic_resistance),
+                ResistanceUnit::WoodUnit.get(sp.pulmonary_resistance),
+                ComplianceUnit::MilliliterPerMillimeterOfMercury
+                    .get(sp.systemic_afterload_compliance),
+                ComplianceUnit::MilliliterPerMillimeterOfMercury
+                    .get(sp.pulmonary_afterload_compliance),
             ),
-            None => write!(fmt, "DISABLED"),
+            None => write!(fmt, "unchanged"),
         };
 
         write!(fmt, " )");
@@ -0,0 +1,329 @@
+//! Link supervision: heartbeats, a watchdog and the fail-safe policy on link loss.
+//!
+//! Both ends send a [`Heartbeat`] every [`LinkConfig::heartbeat_interval_ms`] and run a [`Link`]
+//! that notices when nothing was received for [`LinkConfig::timeout_ms`]. The host sends its
+//! [`LinkConfig`] to the device after the handshake, so the device knows what to do when the host
+//! disappears while the heart is pumping, see [`FailSafePolicy`].
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+use uom::si::f32::Pressure;
+use uom::si::pressure::millibar;
+
+use crate::Message;
+
+/// Periodic keep-alive, sent in both directions
+#[derive(PartialEq, Eq, Clone, Copy, Deserialize, Serialize, Format, Debug, MaxSize)]
+pub struct Heartbeat {
+    /// Incremented by the sender for every heartbeat, wrapping
+    pub counter: u32,
+    /// Milliseconds since boot (device) or since the session started (host) of the sender
+    pub timestamp: u64,
+}
+
+/// Linear ramp of the regulator pressure towards a safe value
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct Ramp {
+    /// Pressure to ramp to and hold
+    pub pressure: Pressure,
+    /// Time to get from the active pressure to `pressure`
+    pub duration_ms: u32,
+}
+
+/// What the device does when it loses the link to the host while running
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug, Default)]
+pub enum FailSafePolicy {
+    /// Keep pumping with the active setpoint
+    Hold,
+    /// Keep pumping but ramp the pressure down to a safe value
+    RampDown(Ramp),
+    /// Stop pumping and go to standby
+    #[default]
+    StandBy,
+}
+
+/// What the firmware should do right now, see [`FailSafePolicy::action`]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FailSafeAction {
+    /// Leave the heart controller as it is
+    None,
+    /// Drive the regulator to this pressure, keeping the rest of the heart controller setpoint
+    Pressure(Pressure),
+    /// Handle [`Event::Stop`](crate::state::Event::Stop)
+    StandBy,
+}
+
+impl FailSafePolicy {
+    /// Device side: the action to take `since_loss_ms` after the link was lost, with `active` the
+    /// regulator pressure setpoint in effect when it was lost. Call periodically until the link is
+    /// restored to follow a ramp
+    pub fn action(&self, active: Pressure, since_loss_ms: u64) -> FailSafeAction {
+        match self {
+            FailSafePolicy::Hold => FailSafeAction::None,
+            FailSafePolicy::StandBy => FailSafeAction::StandBy,
+            FailSafePolicy::RampDown(ramp) => {
+                let progress = match ramp.duration_ms {
+                    0 => 1.0,
+                    duration => (since_loss_ms as f32 / duration as f32).min(1.0),
+                };
+                // Never ramp up when the active pressure is already below the safe value
+                let target = ramp.pressure.min(active);
+                FailSafeAction::Pressure(active + (target - active) * progress)
+            }
+        }
+    }
+}
+
+/// Link supervision settings, sent host -> device as [`Message::LinkConfig`]
+#[derive(PartialEq, Clone, Copy, Deserialize, Serialize, Format, Debug)]
+pub struct LinkConfig {
+    /// How often to send a [`Heartbeat`]
+    pub heartbeat_interval_ms: u32,
+    /// The link is lost after receiving nothing for this long
+    pub timeout_ms: u32,
+    pub fail_safe: FailSafePolicy,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_ms: 250,
+            timeout_ms: 1000,
+            fail_safe: FailSafePolicy::StandBy,
+        }
+    }
+}
+
+/// Change in link status reported by [`Watchdog::poll`]
+#[derive(PartialEq, Eq, Clone, Copy, Format, Debug)]
+pub enum LinkEvent {
+    /// Nothing was received within the timeout
+    Lost,
+    /// A message arrived after the link was lost
+    Restored,
+}
+
+/// Reports link loss when it is not fed within a timeout.
+///
+/// Time is passed in by the caller as milliseconds from any monotonic clock. The timeout only
+/// starts counting at the first [`Watchdog::feed`], so a link that never came up is not lost.
+#[derive(PartialEq, Eq, Clone, Copy, Format, Debug)]
+pub struct Watchdog {
+    timeout_ms: u64,
+    last_fed_ms: Option<u64>,
+    lost: bool,
+}
+
+impl Watchdog {
+    pub const fn new(timeout_ms: u32) -> Self {
+        Self {
+            timeout_ms: timeout_ms as u64,
+            last_fed_ms: None,
+            lost: false,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms as u64;
+    }
+
+    /// Something was received at `now_ms`, returns [`LinkEvent::Restored`] if the link was lost
+    pub fn feed(&mut self, now_ms: u64) -> Option<LinkEvent> {
+        self.last_fed_ms = Some(now_ms);
+        match core::mem::take(&mut self.lost) {
+            true => Some(LinkEvent::Restored),
+            false => None,
+        }
+    }
+
+    /// Call periodically, returns [`LinkEvent::Lost`] once when the timeout expires
+    pub fn poll(&mut self, now_ms: u64) -> Option<LinkEvent> {
+        let last_fed_ms = self.last_fed_ms?;
+        if self.lost || now_ms.saturating_sub(last_fed_ms) < self.timeout_ms {
+            return None;
+        }
+        self.lost = true;
+        Some(LinkEvent::Lost)
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+}
+
+/// Sends heartbeats and supervises the incoming traffic of one end of the link
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub struct Link {
+    config: LinkConfig,
+    watchdog: Watchdog,
+    counter: u32,
+    last_sent_ms: Option<u64>,
+}
+
+impl Link {
+    pub const fn new(config: LinkConfig) -> Self {
+        Self {
+            config,
+            watchdog: Watchdog::new(config.timeout_ms),
+            counter: 0,
+            last_sent_ms: None,
+        }
+    }
+
+    pub fn config(&self) -> &LinkConfig {
+        &self.config
+    }
+
+    /// Device side: adopt the configuration received in a [`Message::LinkConfig`]
+    pub fn set_config(&mut self, config: LinkConfig) {
+        self.watchdog.set_timeout(config.timeout_ms);
+        self.config = config;
+    }
+
+    /// Returns a [`Message::Heartbeat`] to send when one is due
+    pub fn heartbeat(&mut self, now_ms: u64) -> Option<Message> {
+        let interval_ms = self.config.heartbeat_interval_ms as u64;
+        if let Some(last_sent_ms) = self.last_sent_ms
+            && now_ms.saturating_sub(last_sent_ms) < interval_ms
+        {
+            return None;
+        }
+        self.last_sent_ms = Some(now_ms);
+        let heartbeat = Heartbeat {
+            counter: self.counter,
+            timestamp: now_ms,
+        };
+        self.counter = self.counter.wrapping_add(1);
+        Some(Message::Heartbeat(heartbeat))
+    }
+
+    /// Process a received message, every valid message proves the link is alive. A received
+    /// [`Message::LinkConfig`] is adopted
+    pub fn handle(&mut self, message: &Message, now_ms: u64) -> Option<LinkEvent> {
+        if let Message::LinkConfig(config) = message {
+            self.set_config(*config);
+        }
+        self.watchdog.feed(now_ms)
+    }
+
+    /// Call periodically, returns [`LinkEvent::Lost`] once when the link times out
+    pub fn poll(&mut self, now_ms: u64) -> Option<LinkEvent> {
+        self.watchdog.poll(now_ms)
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.watchdog.is_lost()
+    }
+}
+
+impl Format for Ramp {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "Ramp(to {} mbar in {} ms)",
+            self.pressure.get::<millibar>(),
+            self.duration_ms
+        );
+    }
+}
+
+impl Format for FailSafeAction {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            FailSafeAction::None => defmt::write!(fmt, "None"),
+            FailSafeAction::Pressure(pressure) => {
+                defmt::write!(fmt, "Pressure({} mbar)", pressure.get::<millibar>())
+            }
+            FailSafeAction::StandBy => defmt::write!(fmt, "StandBy"),
+        }
+    }
+}
+
+// MaxSize impls from here, uom quantities are serialized as their bare f32 value
+impl MaxSize for Ramp {
+    const POSTCARD_MAX_SIZE: usize = f32::POSTCARD_MAX_SIZE + u32::POSTCARD_MAX_SIZE;
+}
+
+impl MaxSize for FailSafePolicy {
+    // Variant tag plus the largest variant
+    const POSTCARD_MAX_SIZE: usize = 1 + Ramp::POSTCARD_MAX_SIZE;
+}
+
+impl MaxSize for LinkConfig {
+    const POSTCARD_MAX_SIZE: usize = 2 * u32::POSTCARD_MAX_SIZE + FailSafePolicy::POSTCARD_MAX_SIZE;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_reports_loss_once_and_restore() {
+        let mut watchdog = Watchdog::new(1_000);
+        // Not lost before the link came up
+        assert_eq!(watchdog.poll(10_000), None);
+        assert_eq!(watchdog.feed(10_000), None);
+        assert_eq!(watchdog.poll(10_999), None);
+        assert_eq!(watchdog.poll(11_000), Some(LinkEvent::Lost));
+        assert_eq!(watchdog.poll(12_000), None);
+        assert!(watchdog.is_lost());
+        assert_eq!(watchdog.feed(12_500), Some(LinkEvent::Restored));
+        assert!(!watchdog.is_lost());
+    }
+
+    #[test]
+    fn sends_heartbeats_at_interval() {
+        let mut link = Link::new(LinkConfig::default());
+        let counter = |message: Option<Message>| match message {
+            Some(Message::Heartbeat(heartbeat)) => Some(heartbeat.counter),
+            _ => None,
+        };
+        assert_eq!(counter(link.heartbeat(0)), Some(0));
+        assert_eq!(counter(link.heartbeat(249)), None);
+        assert_eq!(counter(link.heartbeat(250)), Some(1));
+    }
+
+    #[test]
+    fn adopts_received_config() {
+        let mut link = Link::new(LinkConfig::default());
+        let config = LinkConfig {
+            heartbeat_interval_ms: 100,
+            timeout_ms: 300,
+            fail_safe: FailSafePolicy::Hold,
+        };
+        link.handle(&Message::LinkConfig(config), 0);
+        assert_eq!(link.config(), &config);
+        assert_eq!(link.poll(299), None);
+        assert_eq!(link.poll(300), Some(LinkEvent::Lost));
+    }
+
+    #[test]
+    fn fail_safe_actions() {
+        let active = Pressure::new::<millibar>(200.0);
+        assert_eq!(
+            FailSafePolicy::Hold.action(active, 5_000),
+            FailSafeAction::None
+        );
+        assert_eq!(
+            FailSafePolicy::StandBy.action(active, 0),
+            FailSafeAction::StandBy
+        );
+
+        let ramp = FailSafePolicy::RampDown(Ramp {
+            pressure: Pressure::new::<millibar>(100.0),
+            duration_ms: 1_000,
+        });
+        let pressure = |since_loss_ms| match ramp.action(active, since_loss_ms) {
+            FailSafeAction::Pressure(pressure) => pressure.get::<millibar>(),
+            action => panic!("unexpected {action:?}"),
+        };
+        assert_eq!(pressure(0), 200.0);
+        assert_eq!(pressure(500), 150.0);
+        assert_eq!(pressure(1_000), 100.0);
+        assert_eq!(pressure(5_000), 100.0);
+        // Never ramps up
+        let low = Pressure::new::<millibar>(50.0);
+        assert_eq!(ramp.action(low, 500), FailSafeAction::Pressure(low));
+    }
+}
@@ -0,0 +1,294 @@
+//! Prioritised outbound message queue.
+//!
+//! Telemetry is produced continuously and may pile up when the link is slow, commands and
+//! emergency stops must not wait behind it. [`OutboundQueue`] always hands out the most urgent
+//! message first and sheds telemetry when it runs full.
+
+use defmt::Format;
+
+use crate::Message;
+
+/// Transmit priority of a [`Message`], higher is more urgent
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Format, Debug)]
+pub enum Priority {
+    /// Periodic [`Report`](crate::Report)s, a newer one supersedes any that are dropped
+    Telemetry,
+    /// Keep-alives
+    Heartbeat,
+    /// Setpoints, commands, handshake and their acknowledgements
+    Control,
+    /// [`Message::EmergencyStop`], preempts everything including a frame already in transmission
+    Critical,
+}
+
+impl Message {
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::Report(_) => Priority::Telemetry,
+            Message::Heartbeat(_) => Priority::Heartbeat,
+            Message::EmergencyStop | Message::Command(crate::Command::EmergencyStop) => {
+                Priority::Critical
+            }
+            Message::Setpoint(_)
+            | Message::Hello(_)
+            | Message::HelloAck(_)
+            | Message::Command(_)
+            | Message::CommandAck(_)
+            | Message::SetpointAck(_)
+            | Message::LinkConfig(_) => Priority::Control,
+        }
+    }
+}
+
+struct Entry {
+    message: Message,
+    /// Insertion order, keeps messages of equal priority first in first out
+    order: u32,
+}
+
+/// Fixed capacity queue of at most `N` outgoing messages, ordered by [`Priority`] and first in
+/// first out within a priority.
+///
+/// The transmit side should check [`OutboundQueue::peek_priority`] while sending a frame, and
+/// abort the transfer to send the [`EMERGENCY_STOP_FRAME`](crate::frame::EMERGENCY_STOP_FRAME)
+/// right away when a [`Priority::Critical`] message is waiting. Receivers recognise it mid frame.
+pub struct OutboundQueue<const N: usize> {
+    entries: [Option<Entry>; N],
+    next_order: u32,
+}
+
+impl<const N: usize> Default for OutboundQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> OutboundQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { None }; N],
+            next_order: 0,
+        }
+    }
+
+    /// Queue `message`. When the queue is full the oldest message of the lowest priority is
+    /// evicted if it is less urgent than `message`, or if both are [`Priority::Telemetry`] since a
+    /// newer report supersedes an older one. Otherwise `message` itself is refused. Returns the
+    /// message that did not make it, if any
+    #[must_use]
+    pub fn push(&mut self, message: Message) -> Option<Message> {
+        let entry = Entry {
+            message,
+            order: self.next_order,
+        };
+        self.next_order = self.next_order.wrapping_add(1);
+
+        if let Some(slot) = self.entries.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(entry);
+            return None;
+        }
+
+        let priority = entry.message.priority();
+        let victim = self.least_urgent().filter(|&index| {
+            self.entries[index]
+                .as_ref()
+                .is_some_and(|old| match old.message.priority() {
+                    Priority::Telemetry => true,
+                    old => old < priority,
+                })
+        });
+        let Some(index) = victim else {
+            // Nothing to shed for it, or zero capacity
+            return Some(entry.message);
+        };
+        self.entries[index]
+            .replace(entry)
+            .map(|evicted| evicted.message)
+    }
+
+    /// Take the most urgent message, the oldest first among equally urgent ones
+    pub fn pop(&mut self) -> Option<Message> {
+        let index = self.most_urgent()?;
+        self.entries[index].take().map(|entry| entry.message)
+    }
+
+    /// Priority of the message [`OutboundQueue::pop`] would return
+    pub fn peek_priority(&self) -> Option<Priority> {
+        let index = self.most_urgent()?;
+        self.entries[index]
+            .as_ref()
+            .map(|entry| entry.message.priority())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|slot| *slot = None);
+    }
+
+    /// Age of an entry relative to the next insertion, robust against the order counter wrapping
+    fn age(&self, entry: &Entry) -> u32 {
+        self.next_order.wrapping_sub(entry.order)
+    }
+
+    fn most_urgent(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|entry| (index, entry)))
+            .max_by_key(|(_, entry)| (entry.message.priority(), self.age(entry)))
+            .map(|(index, _)| index)
+    }
+
+    fn least_urgent(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|entry| (index, entry)))
+            .min_by_key(|(_, entry)| (entry.message.priority(), u32::MAX - self.age(entry)))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::{Command, Heartbeat, Setpoint};
+
+    fn report(timestamp: u64) -> Message {
+        Message::Report(testing::report(timestamp))
+    }
+
+    fn heartbeat(counter: u32) -> Message {
+        Message::Heartbeat(Heartbeat {
+            counter,
+            timestamp: 0,
+        })
+    }
+
+    fn setpoint(seq: u16) -> Message {
+        Message::Setpoint(Setpoint {
+            seq,
+            ..Setpoint::default()
+        })
+    }
+
+    /// Identifies a message by its priority and sequence, for comparisons
+    fn id(message: Option<Message>) -> Option<(Priority, u64)> {
+        let message = message?;
+        let seq = match &message {
+            Message::Report(report) => report.measurements.timestamp,
+            Message::Heartbeat(heartbeat) => heartbeat.counter as u64,
+            Message::Setpoint(setpoint) => setpoint.seq as u64,
+            _ => 0,
+        };
+        Some((message.priority(), seq))
+    }
+
+    fn drain<const N: usize>(queue: &mut OutboundQueue<N>) -> [Option<(Priority, u64)>; N] {
+        core::array::from_fn(|_| id(queue.pop()))
+    }
+
+    #[test]
+    fn pops_by_priority_then_age() {
+        let mut queue = OutboundQueue::<4>::new();
+        assert!(queue.push(report(1)).is_none());
+        assert!(queue.push(heartbeat(2)).is_none());
+        assert!(queue.push(setpoint(3)).is_none());
+        assert!(queue.push(setpoint(4)).is_none());
+        assert_eq!(queue.peek_priority(), Some(Priority::Control));
+        assert_eq!(
+            drain(&mut queue),
+            [
+                Some((Priority::Control, 3)),
+                Some((Priority::Control, 4)),
+                Some((Priority::Heartbeat, 2)),
+                Some((Priority::Telemetry, 1)),
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn full_of_telemetry_sheds_oldest_report() {
+        let mut queue = OutboundQueue::<2>::new();
+        assert!(queue.push(report(1)).is_none());
+        assert!(queue.push(report(2)).is_none());
+        assert_eq!(id(queue.push(report(3))), Some((Priority::Telemetry, 1)));
+        assert_eq!(
+            drain(&mut queue),
+            [
+                Some((Priority::Telemetry, 2)),
+                Some((Priority::Telemetry, 3))
+            ]
+        );
+    }
+
+    #[test]
+    fn full_queue_sheds_telemetry_for_anything_more_urgent() {
+        let mut queue = OutboundQueue::<2>::new();
+        assert!(queue.push(report(1)).is_none());
+        assert!(queue.push(report(2)).is_none());
+        assert_eq!(id(queue.push(heartbeat(3))), Some((Priority::Telemetry, 1)));
+        assert_eq!(id(queue.push(setpoint(4))), Some((Priority::Telemetry, 2)));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn full_queue_refuses_reports_when_no_telemetry_is_queued() {
+        let mut queue = OutboundQueue::<2>::new();
+        assert!(queue.push(heartbeat(1)).is_none());
+        assert!(queue.push(setpoint(2)).is_none());
+        assert_eq!(id(queue.push(report(3))), Some((Priority::Telemetry, 3)));
+    }
+
+    #[test]
+    fn full_queue_keeps_control_messages_of_equal_priority() {
+        let mut queue = OutboundQueue::<2>::new();
+        assert!(queue.push(heartbeat(1)).is_none());
+        assert!(queue.push(heartbeat(2)).is_none());
+        // Heartbeats are not superseded like reports
+        assert_eq!(id(queue.push(heartbeat(3))), Some((Priority::Heartbeat, 3)));
+        assert_eq!(id(queue.push(setpoint(4))), Some((Priority::Heartbeat, 1)));
+        assert_eq!(id(queue.push(setpoint(5))), Some((Priority::Heartbeat, 2)));
+        assert_eq!(id(queue.push(setpoint(6))), Some((Priority::Control, 6)));
+    }
+
+    #[test]
+    fn emergency_stop_displaces_everything_else() {
+        let mut queue = OutboundQueue::<2>::new();
+        assert!(queue.push(setpoint(1)).is_none());
+        assert!(queue.push(setpoint(2)).is_none());
+        assert_eq!(
+            id(queue.push(Message::Command(Command::EmergencyStop))),
+            Some((Priority::Control, 1))
+        );
+        assert_eq!(
+            id(queue.push(Message::EmergencyStop)),
+            Some((Priority::Control, 2))
+        );
+        assert_eq!(queue.peek_priority(), Some(Priority::Critical));
+        // A full queue of emergency stops refuses another, one is enough
+        assert_eq!(
+            id(queue.push(Message::EmergencyStop)),
+            Some((Priority::Critical, 0))
+        );
+    }
+
+    #[test]
+    fn zero_capacity_refuses_everything() {
+        let mut queue = OutboundQueue::<0>::new();
+        assert_eq!(id(queue.push(report(1))), Some((Priority::Telemetry, 1)));
+        assert_eq!(
+            id(queue.push(Message::EmergencyStop)),
+            Some((Priority::Critical, 0))
+        );
+    }
+}
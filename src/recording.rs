@@ -0,0 +1,616 @@
+//! On-disk recordings of a session.
+//!
+//! A recording is a [`MAGIC`] followed by a stream of frames, each encoded like on the wire
+//! (postcard, CRC-32 and COBS, see [`frame`](crate::frame)):
+//!
+//! ```text
+//! MAGIC | header | record | record | ... | index | index offset (u64 LE) | INDEX_MAGIC
+//! ```
+//!
+//! The [`Header`] carries the [`FORMAT_VERSION`], the [`Hello`] of the host that wrote it, including
+//! protocol version and [`SCHEMA_HASH`], and free-form [`Metadata`]. Every [`Record`] is a
+//! [`Report`] received or a [`Setpoint`] sent, stamped with the device clock of
+//! [`Measurements::timestamp`](crate::Measurements::timestamp).
+//!
+//! [`RecordingWriter::finish`] appends a sparse [`IndexEntry`] list, one entry per
+//! [`INDEX_INTERVAL_MS`] of recorded time, and a fixed size footer pointing at it, so
+//! [`RecordingReader::seek`] jumps close to a timestamp without scanning. A recording cut short,
+//! e.g. by a crash, has no index: it still reads up to the last complete frame and seeking scans it
+//! once.
+//!
+//! [`Replay`] plays a recording back through the [`Transport`](crate::Transport) interface, so
+//! host code runs against it like against the device. [`Player`] runs a replay behind a byte
+//! stream, for host code that opens a serial port or drives a [`Session`](crate::session::Session).
+
+mod replay;
+
+pub use replay::{Playback, Player, Replay};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use chrono::{DateTime, Utc};
+use postcard::ser_flavors::{AllocVec, Cobs, crc::CrcModifier};
+use serde::{Deserialize, Serialize};
+
+use crate::frame::CRC32;
+use crate::handshake::Hello;
+use crate::schema::SCHEMA_HASH;
+use crate::{Message, Report, Setpoint};
+
+/// First bytes of every recording
+pub const MAGIC: [u8; 8] = *b"LOVEREC\n";
+/// Version of the file layout, bumped when it changes. Changes to the recorded messages themselves
+/// show up in the [`SCHEMA_HASH`]
+pub const FORMAT_VERSION: u16 = 1;
+/// Recorded time between two [`IndexEntry`]s, milliseconds
+pub const INDEX_INTERVAL_MS: u64 = 1_000;
+/// Last bytes of a finished recording
+const INDEX_MAGIC: [u8; 8] = *b"LOVEIDX\n";
+/// The index offset and [`INDEX_MAGIC`]
+const FOOTER_LEN: usize = 16;
+
+/// Describes the recording, written once at its start
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Header {
+    pub format_version: u16,
+    /// Identity of the host build that wrote the recording
+    pub host: Hello,
+    /// Identity the device announced in its [`HelloAck`](crate::HelloAck), if it was connected
+    pub device: Option<Hello>,
+    pub metadata: Metadata,
+}
+
+impl Header {
+    /// Header of a recording written by this build
+    pub fn new(device: Option<Hello>, metadata: Metadata) -> Self {
+        Header {
+            format_version: FORMAT_VERSION,
+            host: Hello::local(),
+            device,
+            metadata,
+        }
+    }
+}
+
+/// What was recorded, for the people reading it later
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// Wall clock time the recording started
+    pub started_at: Option<DateTime<Utc>>,
+    /// Where the device was connected, e.g. its serial port
+    pub source: String,
+    /// Free-form description of the experiment
+    pub description: String,
+}
+
+/// One recorded message.
+/// NOTE: only ever append new variants, reordering breaks existing recordings
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum Record {
+    /// A report received from the device
+    Report(Report),
+    /// A setpoint sent to the device, at the device time of the last report before it
+    Setpoint { timestamp: u64, setpoint: Setpoint },
+}
+
+impl Record {
+    /// Device time of the record, milliseconds since boot
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Record::Report(report) => report.measurements.timestamp,
+            Record::Setpoint { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+/// Where the first record at or after `timestamp` starts, counted in bytes from the file start
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub timestamp: u64,
+    pub offset: u64,
+}
+
+/// Every frame after the header
+#[derive(Deserialize, Serialize)]
+enum Chunk {
+    Record(Record),
+    Index(Vec<IndexEntry>),
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    /// A frame failed to encode or decode, e.g. because the file is corrupted
+    Postcard(postcard::Error),
+    /// Not a recording
+    BadMagic,
+    /// Written by a newer build with a file layout this one does not know
+    UnsupportedVersion(u16),
+    /// Written with different message definitions, its records can not be decoded by this build
+    SchemaMismatch {
+        recorded: u32,
+    },
+    /// A [`Playback::Accelerated`] factor that is zero, negative or not finite
+    InvalidPlayback(f32),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(err) => write!(f, "i/o error: {err}"),
+            RecordingError::Postcard(err) => write!(f, "corrupted frame: {err}"),
+            RecordingError::BadMagic => write!(f, "not a love-letter recording"),
+            RecordingError::UnsupportedVersion(version) => {
+                write!(f, "unsupported recording format version {version}")
+            }
+            RecordingError::SchemaMismatch { recorded } => write!(
+                f,
+                "recorded with schema {recorded:#010x}, this build uses {SCHEMA_HASH:#010x}"
+            ),
+            RecordingError::InvalidPlayback(factor) => {
+                write!(f, "playback factor {factor} must be positive and finite")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> Self {
+        RecordingError::Io(err)
+    }
+}
+
+impl From<postcard::Error> for RecordingError {
+    fn from(err: postcard::Error) -> Self {
+        RecordingError::Postcard(err)
+    }
+}
+
+/// Writes a recording, see the [module documentation](self)
+pub struct RecordingWriter<W: Write> {
+    out: W,
+    /// Bytes written so far
+    offset: u64,
+    index: Vec<IndexEntry>,
+    /// Device time of the last record
+    timestamp: u64,
+}
+
+impl RecordingWriter<BufWriter<File>> {
+    /// Create or truncate the recording at `path`
+    pub fn create(path: impl AsRef<Path>, header: &Header) -> Result<Self, RecordingError> {
+        Self::new(BufWriter::new(File::create(path)?), header)
+    }
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Start a recording by writing the [`MAGIC`] and `header` to `out`
+    pub fn new(mut out: W, header: &Header) -> Result<Self, RecordingError> {
+        out.write_all(&MAGIC)?;
+        let mut writer = RecordingWriter {
+            out,
+            offset: MAGIC.len() as u64,
+            index: Vec::new(),
+            timestamp: 0,
+        };
+        writer.write_frame(header)?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), RecordingError> {
+        let timestamp = record.timestamp();
+        if index_due(&self.index, self.timestamp, timestamp) {
+            self.index.push(IndexEntry {
+                timestamp,
+                offset: self.offset,
+            });
+        }
+        self.timestamp = timestamp;
+        self.write_frame(&Chunk::Record(record.clone()))
+    }
+
+    pub fn write_report(&mut self, report: &Report) -> Result<(), RecordingError> {
+        self.write(&Record::Report(report.clone()))
+    }
+
+    /// Record a setpoint sent now, stamped with the time of the last record
+    pub fn write_setpoint(&mut self, setpoint: &Setpoint) -> Result<(), RecordingError> {
+        self.write(&Record::Setpoint {
+            timestamp: self.timestamp,
+            setpoint: setpoint.clone(),
+        })
+    }
+
+    /// Record `message` if it is a [`Report`] or [`Setpoint`], returns whether it was recorded
+    pub fn write_message(&mut self, message: &Message) -> Result<bool, RecordingError> {
+        match message {
+            Message::Report(report) => self.write_report(report)?,
+            Message::Setpoint(setpoint) => self.write_setpoint(setpoint)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecordingError> {
+        Ok(self.out.flush()?)
+    }
+
+    /// Write the index and footer, returns the underlying writer
+    pub fn finish(mut self) -> Result<W, RecordingError> {
+        let index_offset = self.offset;
+        let index = core::mem::take(&mut self.index);
+        self.write_frame(&Chunk::Index(index))?;
+        self.out.write_all(&index_offset.to_le_bytes())?;
+        self.out.write_all(&INDEX_MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_frame<T: Serialize>(&mut self, value: &T) -> Result<(), RecordingError> {
+        let frame = encode(value)?;
+        self.out.write_all(&frame)?;
+        self.offset += frame.len() as u64;
+        Ok(())
+    }
+}
+
+/// Reads a recording front to back as an [`Iterator`] of [`Record`]s, and seeks by timestamp when
+/// the input is [`Seek`]
+pub struct RecordingReader<R: BufRead> {
+    input: R,
+    header: Header,
+    /// Offset of the next frame
+    offset: u64,
+    /// Offset of the first record
+    start: u64,
+    /// Read ahead by [`RecordingReader::seek`]
+    peeked: Option<Record>,
+    /// Reached the index or the end of the file
+    done: bool,
+    index: Option<Vec<IndexEntry>>,
+    buf: Vec<u8>,
+}
+
+impl RecordingReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> RecordingReader<R> {
+    /// Read the [`MAGIC`] and [`Header`] from `input`, fails when this build can not decode the
+    /// recording
+    pub fn new(mut input: R) -> Result<Self, RecordingError> {
+        let mut magic = [0; MAGIC.len()];
+        input
+            .read_exact(&mut magic)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => RecordingError::BadMagic,
+                _ => RecordingError::Io(err),
+            })?;
+        if magic != MAGIC {
+            return Err(RecordingError::BadMagic);
+        }
+
+        let mut reader = RecordingReader {
+            input,
+            header: Header::new(None, Metadata::default()),
+            offset: MAGIC.len() as u64,
+            start: 0,
+            peeked: None,
+            done: false,
+            index: None,
+            buf: Vec::new(),
+        };
+        if !reader.read_frame()? {
+            return Err(RecordingError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        // Decode the version before the rest, a newer header may not decode at all
+        let format_version = postcard::take_from_bytes::<u16>(&reader.buf)?.0;
+        if format_version > FORMAT_VERSION {
+            return Err(RecordingError::UnsupportedVersion(format_version));
+        }
+        reader.header = reader.decode()?;
+        if reader.header.host.schema_hash != SCHEMA_HASH {
+            return Err(RecordingError::SchemaMismatch {
+                recorded: reader.header.host.schema_hash,
+            });
+        }
+        reader.start = reader.offset;
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The next record, `None` at the end of the recording. A frame cut off at the end of the file
+    /// ends the recording, a corrupted frame in the middle fails with
+    /// [`RecordingError::Postcard`] and reading continues after it
+    pub fn next_record(&mut self) -> Result<Option<Record>, RecordingError> {
+        if let Some(record) = self.peeked.take() {
+            return Ok(Some(record));
+        }
+        if self.done {
+            return Ok(None);
+        }
+        if !self.read_frame()? {
+            self.done = true;
+            return Ok(None);
+        }
+        match self.decode()? {
+            Chunk::Record(record) => Ok(Some(record)),
+            Chunk::Index(index) => {
+                self.index.get_or_insert(index);
+                self.done = true;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the next frame and leave its postcard bytes in `buf`, returns false at the end of the
+    /// file. A frame without its delimiter was cut off while writing and is dropped
+    fn read_frame(&mut self) -> Result<bool, RecordingError> {
+        self.buf.clear();
+        let len = self.input.read_until(0, &mut self.buf)?;
+        self.offset += len as u64;
+        if self.buf.last() != Some(&0) {
+            return Ok(false);
+        }
+        let len = cobs::decode_in_place(&mut self.buf)
+            .map_err(|_| postcard::Error::DeserializeBadEncoding)?;
+        let crc_at = len
+            .checked_sub(4)
+            .ok_or(postcard::Error::DeserializeUnexpectedEnd)?;
+        let crc = u32::from_le_bytes(self.buf[crc_at..len].try_into().expect("4 bytes"));
+        self.buf.truncate(crc_at);
+        if CRC32.checksum(&self.buf) != crc {
+            return Err(postcard::Error::DeserializeBadCrc.into());
+        }
+        Ok(true)
+    }
+
+    fn decode<T: for<'de> Deserialize<'de>>(&self) -> Result<T, RecordingError> {
+        Ok(postcard::from_bytes(&self.buf)?)
+    }
+}
+
+impl<R: BufRead + Seek> RecordingReader<R> {
+    /// The sparse index, read from the footer or built by scanning the recording once when it has
+    /// none
+    pub fn index(&mut self) -> Result<&[IndexEntry], RecordingError> {
+        if self.index.is_none() {
+            let (offset, done, peeked) = (self.offset, self.done, self.peeked.take());
+            let index = match self.read_index()? {
+                Some(index) => index,
+                None => self.scan()?,
+            };
+            self.index = Some(index);
+            self.jump(offset)?;
+            self.done = done;
+            self.peeked = peeked;
+        }
+        Ok(self.index.as_deref().unwrap_or_default())
+    }
+
+    /// Continue reading at the first record at or after `timestamp`, or at the end when there is
+    /// none. A device reboot restarts the clock and leaves the index unsorted, seeking then scans
+    /// from the first record and stops at the first match in file order
+    pub fn seek(&mut self, timestamp: u64) -> Result<(), RecordingError> {
+        let index = self.index()?;
+        let offset = match index.is_sorted_by_key(|entry| entry.timestamp) {
+            true => match index.partition_point(|entry| entry.timestamp <= timestamp) {
+                0 => None,
+                n => Some(index[n - 1].offset),
+            },
+            false => None,
+        };
+        self.jump(offset.unwrap_or(self.start))?;
+        while let Some(record) = self.next_record()? {
+            if record.timestamp() >= timestamp {
+                self.peeked = Some(record);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start over at the first record
+    pub fn rewind(&mut self) -> Result<(), RecordingError> {
+        self.jump(self.start)
+    }
+
+    fn jump(&mut self, offset: u64) -> Result<(), RecordingError> {
+        self.input.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.peeked = None;
+        self.done = false;
+        Ok(())
+    }
+
+    /// The index the footer points at, `None` without a footer
+    fn read_index(&mut self) -> Result<Option<Vec<IndexEntry>>, RecordingError> {
+        let len = self.input.seek(SeekFrom::End(0))?;
+        if len < self.start + FOOTER_LEN as u64 {
+            return Ok(None);
+        }
+        let mut footer = [0; FOOTER_LEN];
+        self.input.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        self.input.read_exact(&mut footer)?;
+        let (offset, magic) = footer.split_at(8);
+        let offset = u64::from_le_bytes(offset.try_into().expect("8 bytes"));
+        if magic != INDEX_MAGIC || !(self.start..len).contains(&offset) {
+            return Ok(None);
+        }
+        self.jump(offset)?;
+        if !self.read_frame()? {
+            return Ok(None);
+        }
+        match self.decode()? {
+            Chunk::Index(index) => Ok(Some(index)),
+            Chunk::Record(_) => Ok(None),
+        }
+    }
+
+    /// Build the index the writer would have, reading every record
+    fn scan(&mut self) -> Result<Vec<IndexEntry>, RecordingError> {
+        self.jump(self.start)?;
+        let mut index = Vec::new();
+        let mut previous = 0;
+        loop {
+            let offset = self.offset;
+            let record = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(index),
+                // Skip corrupted frames like a reader would
+                Err(RecordingError::Postcard(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            let timestamp = record.timestamp();
+            if index_due(&index, previous, timestamp) {
+                index.push(IndexEntry { timestamp, offset });
+            }
+            previous = timestamp;
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for RecordingReader<R> {
+    type Item = Result<Record, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Whether a record at `timestamp` after one at `previous` gets an index entry. A device reboot
+/// restarts its clock, that is indexed like a new recording
+fn index_due(index: &[IndexEntry], previous: u64, timestamp: u64) -> bool {
+    timestamp < previous
+        || index
+            .last()
+            .is_none_or(|last| timestamp >= last.timestamp + INDEX_INTERVAL_MS)
+}
+
+/// Encode `value` as a frame with a CRC-32 trailer, like [`crate::frame::encode`] without a size
+/// limit
+fn encode<T: Serialize>(value: &T) -> postcard::Result<Vec<u8>> {
+    postcard::serialize_with_flavor(
+        value,
+        CrcModifier::new(Cobs::try_new(AllocVec::new())?, CRC32.digest()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testing::{recording, report};
+
+    fn timestamps<R: BufRead>(reader: RecordingReader<R>) -> Vec<u64> {
+        reader.map(|record| record.unwrap().timestamp()).collect()
+    }
+
+    #[test]
+    fn round_trips_header_and_records() {
+        let metadata = Metadata {
+            source: "/dev/ttyUSB0".into(),
+            description: "baseline".into(),
+            ..Metadata::default()
+        };
+        let header = Header::new(Some(Hello::local()), metadata);
+        let mut writer = RecordingWriter::new(Vec::new(), &header).unwrap();
+        writer.write_report(&report(10)).unwrap();
+        let setpoint = Setpoint {
+            seq: 7,
+            ..Setpoint::default()
+        };
+        assert!(writer.write_message(&Message::Setpoint(setpoint)).unwrap());
+        assert!(!writer.write_message(&Message::EmergencyStop).unwrap());
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = RecordingReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header(), &header);
+        let records: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert!(
+            matches!(&records[0], Record::Report(report) if report.measurements.timestamp == 10)
+        );
+        assert!(matches!(
+            &records[1],
+            Record::Setpoint { timestamp: 10, setpoint } if setpoint.seq == 7
+        ));
+        assert_eq!(records.len(), 2);
+        assert_eq!(reader.index().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let error = RecordingReader::new(Cursor::new(b"not a recording".to_vec()));
+        assert!(matches!(error, Err(RecordingError::BadMagic)));
+    }
+
+    #[test]
+    fn truncated_recording_reads_to_last_complete_frame() {
+        let mut bytes = recording((0..10).map(|n| n * 100));
+        let footer = bytes.len() - FOOTER_LEN;
+        let index_offset = u64::from_le_bytes(bytes[footer..footer + 8].try_into().unwrap());
+        // Cut the last record in half, the index and footer go with it
+        bytes.truncate(index_offset as usize - 5);
+
+        let reader = RecordingReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(
+            timestamps(reader),
+            (0..9).map(|n| n * 100).collect::<Vec<_>>()
+        );
+
+        let mut reader = RecordingReader::new(Cursor::new(bytes)).unwrap();
+        reader.seek(450).unwrap();
+        assert_eq!(timestamps(reader), [500, 600, 700, 800]);
+    }
+
+    #[test]
+    fn seeks_to_first_record_at_or_after_timestamp() {
+        let bytes = recording((0..50).map(|n| n * 100));
+        let mut reader = RecordingReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.index().unwrap().len(), 5);
+
+        reader.seek(2_550).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap().timestamp(), 2_600);
+        reader.seek(1_000).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap().timestamp(), 1_000);
+        reader.seek(10_000).unwrap();
+        assert!(reader.next_record().unwrap().is_none());
+        reader.rewind().unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap().timestamp(), 0);
+    }
+
+    #[test]
+    fn seeks_in_file_order_after_device_reboot() {
+        let before = (0..30).map(|n| n * 100);
+        let after = (0..20).map(|n| n * 100);
+        let bytes = recording(before.chain(after));
+        let mut reader = RecordingReader::new(Cursor::new(bytes)).unwrap();
+        let index: Vec<_> = reader
+            .index()
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect();
+        assert_eq!(index, [0, 1_000, 2_000, 0, 1_000]);
+
+        reader.seek(2_500).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap().timestamp(), 2_500);
+        reader.seek(500).unwrap();
+        let rest = timestamps(reader);
+        assert_eq!(rest[0], 500);
+        assert_eq!(rest.len(), 25 + 20);
+    }
+}
@@ -0,0 +1,470 @@
+//! Plays a recording back as if the device were connected.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek};
+use std::path::Path;
+use std::string::String;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{Header, Record, RecordingError, RecordingReader};
+use crate::accumulator::FrameError;
+use crate::command::CommandAck;
+use crate::frame::Checksum;
+use crate::handshake::HelloAck;
+use crate::transport::host::{self, Loopback, is_timeout};
+use crate::transport::{Framed, Transport, TransportError};
+use crate::{AppState, Message, Report};
+
+/// How long the player thread waits for a message from the host before playing the next report
+const TICK: Duration = Duration::from_millis(1);
+
+/// How fast a [`Replay`] emits its reports
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Playback {
+    /// With the recorded time between reports
+    RealTime,
+    /// With the recorded time between reports divided by this factor, must be positive and finite
+    Accelerated(f32),
+    /// Every [`Transport::recv`] returns the next report right away, the caller sets the pace
+    Stepwise,
+}
+
+impl Playback {
+    /// Fails with [`RecordingError::InvalidPlayback`] for an acceleration factor that is zero,
+    /// negative or not finite
+    fn check(self) -> Result<Self, RecordingError> {
+        match self {
+            Playback::Accelerated(factor) if !(factor > 0.0 && factor.is_finite()) => {
+                Err(RecordingError::InvalidPlayback(factor))
+            }
+            playback => Ok(playback),
+        }
+    }
+}
+
+/// A recorded device: a [`Transport`] that receives the [`Report`]s of a recording.
+///
+/// It answers the [`Hello`](crate::Hello) with the identity of the recorded device and rejects
+/// every [`Command`](crate::Command). Setpoints can not change a recording, they are not answered
+/// and time out like on a device that does not respond. Recorded setpoints are skipped, they were
+/// sent by the host
+pub struct Replay<R: BufRead + Seek> {
+    reader: RecordingReader<R>,
+    playback: Playback,
+    looping: bool,
+    /// Wall clock and recorded time the playback clock runs from, set by the first report after
+    /// starting, seeking or looping
+    anchor: Option<(Instant, u64)>,
+    /// Answers to messages sent to the replay, received before the next report
+    answers: VecDeque<Message>,
+    /// The last report received
+    last: Option<Report>,
+    /// Whether a report was received since starting over, a looping replay of a recording without
+    /// reports closes instead of spinning
+    lap_has_reports: bool,
+}
+
+impl Replay<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>, playback: Playback) -> Result<Self, RecordingError> {
+        Self::new(RecordingReader::open(path)?, playback)
+    }
+}
+
+impl<R: BufRead + Seek> Replay<R> {
+    /// Replay `reader` from its current position, fails for an invalid [`Playback::Accelerated`]
+    /// factor
+    pub fn new(reader: RecordingReader<R>, playback: Playback) -> Result<Self, RecordingError> {
+        Ok(Replay {
+            reader,
+            playback: playback.check()?,
+            looping: false,
+            anchor: None,
+            answers: VecDeque::new(),
+            last: None,
+            lap_has_reports: true,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        self.reader.header()
+    }
+
+    pub fn playback(&self) -> Playback {
+        self.playback
+    }
+
+    /// Change the playback speed, takes effect from the next report on. Fails for an invalid
+    /// [`Playback::Accelerated`] factor and keeps the current speed
+    pub fn set_playback(&mut self, playback: Playback) -> Result<(), RecordingError> {
+        self.playback = playback.check()?;
+        self.anchor = None;
+        Ok(())
+    }
+
+    /// Start over at the first report after the last one instead of closing
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// Continue at the first report at or after recorded time `timestamp`, see
+    /// [`RecordingReader::seek`]
+    pub fn seek(&mut self, timestamp: u64) -> Result<(), RecordingError> {
+        self.reader.seek(timestamp)?;
+        self.anchor = None;
+        self.lap_has_reports = true;
+        Ok(())
+    }
+
+    /// Recorded time of the last report received, `None` before the first
+    pub fn position(&self) -> Option<u64> {
+        self.last
+            .as_ref()
+            .map(|report| report.measurements.timestamp)
+    }
+
+    /// The next recorded report, starting over at the end when looping
+    fn next_report(&mut self) -> Result<Report, TransportError<RecordingError>> {
+        loop {
+            match self.reader.next_record() {
+                Ok(Some(Record::Report(report))) => {
+                    self.lap_has_reports = true;
+                    return Ok(report);
+                }
+                Ok(Some(Record::Setpoint { .. })) => {}
+                Ok(None) if self.looping && self.lap_has_reports => {
+                    self.reader.rewind().map_err(TransportError::Io)?;
+                    self.anchor = None;
+                    self.lap_has_reports = false;
+                }
+                Ok(None) => return Err(TransportError::Closed),
+                Err(RecordingError::Postcard(err)) => {
+                    return Err(TransportError::Frame(FrameError::Decode(err)));
+                }
+                Err(err) => return Err(TransportError::Io(err)),
+            }
+        }
+    }
+
+    /// Sleep until the report recorded at `timestamp` is due
+    fn wait(&mut self, timestamp: u64) {
+        let speed = match self.playback {
+            Playback::RealTime => 1.0,
+            Playback::Accelerated(speed) => speed as f64,
+            Playback::Stepwise => return,
+        };
+        let (start, recorded_start) = match self.anchor {
+            // A device reboot restarts its clock, play on from there
+            Some((start, recorded_start)) if timestamp >= recorded_start => (start, recorded_start),
+            _ => {
+                self.anchor = Some((Instant::now(), timestamp));
+                return;
+            }
+        };
+        let offset = Duration::from_millis(timestamp - recorded_start).div_f64(speed);
+        if let Some(delay) = (start + offset).checked_duration_since(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl<R: BufRead + Seek> Transport for Replay<R> {
+    type Error = RecordingError;
+
+    fn send(&mut self, message: &Message) -> Result<(), TransportError<RecordingError>> {
+        let header = self.reader.header();
+        let answer = match message {
+            Message::Hello(hello) => {
+                let device = header.device.unwrap_or(header.host);
+                Message::HelloAck(HelloAck {
+                    hello: device,
+                    accepted: device.check_compatible(hello).is_ok(),
+                })
+            }
+            Message::Command(command) => Message::CommandAck(CommandAck {
+                command: *command,
+                accepted: false,
+                state: self
+                    .last
+                    .as_ref()
+                    .map_or(AppState::default(), |report| report.app_state),
+            }),
+            _ => return Ok(()),
+        };
+        self.answers.push_back(answer);
+        Ok(())
+    }
+
+    /// The next answer, or the next recorded report once it is due. Fails with
+    /// [`TransportError::Closed`] at the end of the recording unless looping, and with
+    /// [`TransportError::Frame`] for a corrupted record
+    fn recv(&mut self) -> Result<Message, TransportError<RecordingError>> {
+        if let Some(answer) = self.answers.pop_front() {
+            return Ok(answer);
+        }
+        let report = self.next_report()?;
+        self.wait(report.measurements.timestamp);
+        self.last = Some(report.clone());
+        Ok(Message::Report(report))
+    }
+}
+
+/// A [`Replay`] running on a background thread behind a byte stream, stopped when dropped.
+///
+/// Like the [`Simulator`](crate::simulator::Simulator), [`Player::loopback`] and [`Player::pty`]
+/// hand out the other end, so host applications, e.g. a [`Session`](crate::session::Session),
+/// cannot tell the recording from the device. The thread ends, closing the stream, at the end of a
+/// recording that does not loop
+pub struct Player<R: BufRead + Seek> {
+    replay: Arc<Mutex<Replay<R>>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    /// Host end of [`Player::pty`], kept open so a host closing the tty does not hang up the
+    /// player end
+    #[cfg(unix)]
+    _tty: Option<host::PtyTransport>,
+}
+
+impl<R: BufRead + Seek + Send + 'static> Player<R> {
+    /// Play `replay` over `transport` until the player is stopped, the transport closes or the
+    /// recording ends. Receiving must time out after about a millisecond, reports are played in
+    /// between
+    pub fn spawn<T>(replay: Replay<R>, transport: Framed<T>) -> Player<R>
+    where
+        T: embedded_io::Read + embedded_io::Write<Error = io::Error> + Send + 'static,
+    {
+        let replay = Arc::new(Mutex::new(replay));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let replay = replay.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || run(&replay, transport, &shutdown))
+        };
+        Player {
+            replay,
+            shutdown,
+            thread: Some(thread),
+            #[cfg(unix)]
+            _tty: None,
+        }
+    }
+
+    /// Play `replay` behind an in-memory transport, returns the host end
+    pub fn loopback(replay: Replay<R>, checksum: Checksum) -> (Framed<Loopback>, Player<R>) {
+        let (host, mut player) = host::loopback(checksum);
+        player.get_mut().set_read_timeout(Some(TICK));
+        (host, Self::spawn(replay, player))
+    }
+
+    /// Play `replay` behind a pseudo terminal, returns the tty path the host application should
+    /// open as its serial port. The path stays valid until the player is dropped
+    #[cfg(unix)]
+    pub fn pty(replay: Replay<R>, checksum: Checksum) -> serialport::Result<(String, Player<R>)> {
+        use serialport::SerialPort;
+
+        let (mut player, host) = host::pty(checksum)?;
+        player.get_mut().0.set_timeout(TICK)?;
+        let path = host.get_ref().0.name().ok_or_else(|| {
+            serialport::Error::new(serialport::ErrorKind::NoDevice, "pty has no path")
+        })?;
+        let mut player = Self::spawn(replay, player);
+        player._tty = Some(host);
+        Ok((path, player))
+    }
+
+    /// Lock the replay, e.g. to seek or change the playback speed. Waits for the report being
+    /// played to be due
+    pub fn replay(&self) -> MutexGuard<'_, Replay<R>> {
+        self.replay.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stop the player thread and return the replay at its current position
+    pub fn stop(mut self) -> Replay<R> {
+        self.shutdown.store(true, Ordering::Relaxed);
+        let thread = self.thread.take().expect("only taken when stopping");
+        if let Err(panic) = thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+        let replay = self.replay.clone();
+        drop(self);
+        Arc::into_inner(replay)
+            .expect("the player thread has exited")
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<R: BufRead + Seek> Drop for Player<R> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run<R, T>(replay: &Mutex<Replay<R>>, mut transport: Framed<T>, shutdown: &AtomicBool)
+where
+    R: BufRead + Seek,
+    T: embedded_io::Read + embedded_io::Write<Error = io::Error>,
+{
+    let lock = || replay.lock().unwrap_or_else(|e| e.into_inner());
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match transport.recv() {
+            // Only queues the answer, never fails
+            Ok(message) => {
+                let _ = lock().send(&message);
+            }
+            Err(TransportError::Io(err)) if is_timeout(&err) => {}
+            Err(TransportError::Frame(_)) => {}
+            Err(_) => return,
+        }
+        let message = match lock().recv() {
+            Ok(message) => message,
+            // Skipped like a corrupted frame on the wire
+            Err(TransportError::Frame(_)) => continue,
+            Err(_) => return,
+        };
+        if transport.send(&message).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::command::Command;
+    use crate::handshake::Hello;
+    use crate::session::{Session, SessionConfig};
+    use crate::testing::recording;
+
+    fn replay(
+        timestamps: impl IntoIterator<Item = u64>,
+        playback: Playback,
+    ) -> Replay<Cursor<Vec<u8>>> {
+        let reader = RecordingReader::new(Cursor::new(recording(timestamps))).unwrap();
+        Replay::new(reader, playback).unwrap()
+    }
+
+    /// Recorded time of the next report
+    fn next(replay: &mut Replay<Cursor<Vec<u8>>>) -> u64 {
+        match replay.recv() {
+            Ok(Message::Report(report)) => report.measurements.timestamp,
+            other => panic!("expected a report, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_playback_factor() {
+        let reader = || RecordingReader::new(Cursor::new(recording([0, 100]))).unwrap();
+        for factor in [0.0, -2.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                Replay::new(reader(), Playback::Accelerated(factor)),
+                Err(RecordingError::InvalidPlayback(invalid)) if invalid.to_bits() == factor.to_bits()
+            ));
+        }
+        let mut replay = Replay::new(reader(), Playback::Accelerated(4.0)).unwrap();
+        assert!(matches!(
+            replay.set_playback(Playback::Accelerated(0.0)),
+            Err(RecordingError::InvalidPlayback(0.0))
+        ));
+        assert_eq!(replay.playback(), Playback::Accelerated(4.0));
+        replay.set_playback(Playback::Stepwise).unwrap();
+    }
+
+    #[test]
+    fn plays_in_real_time() {
+        let mut replay = replay([1_000, 1_050, 1_100, 1_150], Playback::RealTime);
+        let started = Instant::now();
+        assert_eq!(next(&mut replay), 1_000);
+        // The first report plays right away
+        assert!(started.elapsed() < Duration::from_millis(40));
+        for timestamp in [1_050, 1_100, 1_150] {
+            assert_eq!(next(&mut replay), timestamp);
+            let elapsed = started.elapsed();
+            assert!(
+                elapsed >= Duration::from_millis(timestamp - 1_000),
+                "{elapsed:?}"
+            );
+        }
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(matches!(replay.recv(), Err(TransportError::Closed)));
+    }
+
+    #[test]
+    fn plays_accelerated() {
+        let mut replay = replay((0..5).map(|n| n * 100), Playback::Accelerated(4.0));
+        let started = Instant::now();
+        for n in 0..5 {
+            assert_eq!(next(&mut replay), n * 100);
+        }
+        // 400 ms recorded, played in a quarter of that
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(350), "{elapsed:?}");
+    }
+
+    #[test]
+    fn loops() {
+        let mut replay = replay([0, 100, 200], Playback::Stepwise);
+        replay.set_looping(true);
+        let played: Vec<_> = (0..7).map(|_| next(&mut replay)).collect();
+        assert_eq!(played, [0, 100, 200, 0, 100, 200, 0]);
+
+        // Nothing to loop over
+        let mut empty = self::replay([], Playback::Stepwise);
+        empty.set_looping(true);
+        assert!(matches!(empty.recv(), Err(TransportError::Closed)));
+    }
+
+    #[test]
+    fn seeks() {
+        let mut replay = replay((0..50).map(|n| n * 100), Playback::Stepwise);
+        assert_eq!(replay.position(), None);
+        replay.seek(2_550).unwrap();
+        assert_eq!(next(&mut replay), 2_600);
+        assert_eq!(replay.position(), Some(2_600));
+        replay.seek(1_000).unwrap();
+        assert_eq!(next(&mut replay), 1_000);
+    }
+
+    #[test]
+    fn seeking_restarts_the_playback_clock() {
+        let mut replay = replay((0..50).map(|n| n * 100), Playback::RealTime);
+        assert_eq!(next(&mut replay), 0);
+        replay.seek(4_000).unwrap();
+        let seeked = Instant::now();
+        assert_eq!(next(&mut replay), 4_000);
+        assert!(seeked.elapsed() < Duration::from_millis(40));
+    }
+
+    #[test]
+    fn drives_a_session() {
+        let mut replay = replay((0..100).map(|n| n * 20), Playback::RealTime);
+        replay.set_looping(true);
+        let (mut host, player) = Player::loopback(replay, Checksum::Crc16);
+        host.get_mut()
+            .set_read_timeout(Some(Duration::from_millis(5)));
+
+        let mut host = Some(host);
+        let connect = move || {
+            host.take()
+                .ok_or_else(|| io::ErrorKind::NotConnected.into())
+        };
+        let session = Session::connect(connect, SessionConfig::default()).unwrap();
+        assert_eq!(session.peer(), Some(Hello::local()));
+        assert!(session.reports().next().is_some());
+        assert!(!session.command(Command::Start).unwrap().accepted);
+
+        drop(session);
+        assert!(player.stop().position().is_some());
+    }
+}
@@ -0,0 +1,497 @@
+//! Compile time fingerprint of the wire format.
+//!
+//! Every type that ends up inside a [`Message`] implements [`Schema`], which folds its name, field
+//! names and the fingerprints of its fields into a single hash. Two builds only agree on
+//! [`SCHEMA_HASH`] when they encode every message the same way, which is what the handshake checks.
+//!
+//! NOTE: when adding or changing a wire type, update its `impl_schema!` entry below as well. Builds
+//! fail when the fields or variants of an entry do not match the type, and the tests fail when
+//! their order does not match
+
+use uom::si::f32::{Frequency, Pressure, VolumeRate};
+
+use crate::command::{Command, CommandAck};
+use crate::delivery::{SetpointAck, SetpointStatus};
+use crate::fault::{Fault, FaultKind, Reading};
+use crate::handshake::{CrateVersion, Hello, HelloAck};
+use crate::link::{FailSafePolicy, Heartbeat, LinkConfig, Ramp};
+use crate::units::{HydraulicCompliance, HydraulicResistance};
+use crate::validation::{Field, Limit, ValidationError, Violation};
+use crate::{
+    AppState, Channel, HeartControllerSetpoint, Measurements, Message, MockloopSetpoint, Report,
+    Setpoint,
+};
+
+/// Fingerprint of the complete wire format of this build
+pub const SCHEMA_HASH: u32 = Message::SCHEMA_HASH;
+
+/// Types with a known postcard wire layout
+pub trait Schema {
+    const SCHEMA_HASH: u32;
+}
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// FNV-1a, continuing from `hash`
+pub const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// Start a fingerprint for a type or field called `name`
+pub const fn tag(name: &str) -> u32 {
+    fnv1a(FNV_OFFSET, name.as_bytes())
+}
+
+/// Fold the fingerprint of a child type into `hash`
+pub const fn mix(hash: u32, child: u32) -> u32 {
+    fnv1a(hash, &child.to_le_bytes())
+}
+
+macro_rules! impl_schema {
+    (primitive $ty:ty) => {
+        impl Schema for $ty {
+            const SCHEMA_HASH: u32 = tag(stringify!($ty));
+        }
+    };
+    (struct $ty:path { $($field:ident: $fty:ty),* $(,)? }) => {
+        impl Schema for $ty {
+            const SCHEMA_HASH: u32 = {
+                #[allow(unused_mut)]
+                let mut hash = tag(concat!("struct ", stringify!($ty)));
+                $(
+                    hash = fnv1a(hash, stringify!($field).as_bytes());
+                    hash = mix(hash, <$fty as Schema>::SCHEMA_HASH);
+                )*
+                hash
+            };
+        }
+
+        #[cfg(test)]
+        impl tests::Members for $ty {
+            const MEMBERS: &'static [&'static str] = &[$(stringify!($field)),*];
+        }
+
+        // Only compiles when the fields are exactly the ones listed, with the listed types
+        const _: fn($ty) = |value| {
+            let $ty { $($field),* } = value;
+            $( let _: $fty = $field; )*
+        };
+    };
+    (enum $ty:ident { $($variant:ident $(($vty:ty))?),* $(,)? }) => {
+        impl Schema for $ty {
+            const SCHEMA_HASH: u32 = {
+                #[allow(unused_mut)]
+                let mut hash = tag(concat!("enum ", stringify!($ty)));
+                $(
+                    hash = fnv1a(hash, stringify!($variant).as_bytes());
+                    $( hash = mix(hash, <$vty as Schema>::SCHEMA_HASH); )?
+                )*
+                hash
+            };
+        }
+
+        #[cfg(test)]
+        impl tests::Members for $ty {
+            const MEMBERS: &'static [&'static str] = &[$(stringify!($variant)),*];
+        }
+
+        // Only compiles when the variants are exactly the ones listed, with the listed payloads
+        const _: fn($ty) = |value| {
+            match value {
+                $( $ty::$variant { .. } => {} )*
+            }
+            $( variant_shape!($ty, $ty::$variant $(, $vty)?); )*
+        };
+    };
+}
+
+/// Checks that a variant is a unit variant, or a tuple variant with a single field of the given type
+macro_rules! variant_shape {
+    ($ty:ident, $variant:path) => {
+        let _: $ty = $variant;
+    };
+    ($ty:ident, $variant:path, $vty:ty) => {
+        let _ = |payload: $vty| -> $ty { $variant(payload) };
+    };
+}
+
+impl_schema!(primitive bool);
+impl_schema!(primitive u8);
+impl_schema!(primitive u16);
+impl_schema!(primitive u32);
+impl_schema!(primitive u64);
+impl_schema!(primitive f32);
+
+// uom quantities are encoded as their value in SI base units
+impl_schema!(primitive Frequency);
+impl_schema!(primitive Pressure);
+impl_schema!(primitive VolumeRate);
+impl_schema!(primitive HydraulicResistance);
+impl_schema!(primitive HydraulicCompliance);
+
+impl<T: Schema> Schema for Option<T> {
+    const SCHEMA_HASH: u32 = mix(tag("Option"), T::SCHEMA_HASH);
+}
+
+impl<T: Schema, const N: usize> Schema for [T; N] {
+    const SCHEMA_HASH: u32 = mix(mix(tag("array"), N as u32), T::SCHEMA_HASH);
+}
+
+impl_schema! {
+    enum Message {
+        Report(Report),
+        Setpoint(Setpoint),
+        Hello(Hello),
+        HelloAck(HelloAck),
+        Command(Command),
+        CommandAck(CommandAck),
+        SetpointAck(SetpointAck),
+        Heartbeat(Heartbeat),
+        LinkConfig(LinkConfig),
+        EmergencyStop,
+    }
+}
+
+impl_schema! {
+    struct Heartbeat {
+        counter: u32,
+        timestamp: u64,
+    }
+}
+
+impl_schema! {
+    struct LinkConfig {
+        heartbeat_interval_ms: u32,
+        timeout_ms: u32,
+        fail_safe: FailSafePolicy,
+    }
+}
+
+impl_schema! {
+    enum FailSafePolicy {
+        Hold,
+        RampDown(Ramp),
+        StandBy,
+    }
+}
+
+impl_schema! {
+    struct Ramp {
+        pressure: Pressure,
+        duration_ms: u32,
+    }
+}
+
+impl_schema! {
+    struct SetpointAck {
+        seq: u16,
+        status: SetpointStatus,
+        applied: Setpoint,
+    }
+}
+
+impl_schema! {
+    enum SetpointStatus {
+        Accepted,
+        Clamped,
+        Rejected(ValidationError),
+    }
+}
+
+impl_schema! {
+    struct ValidationError {
+        violations: [Option<Violation>; Field::COUNT],
+    }
+}
+
+impl_schema! {
+    struct Violation {
+        field: Field,
+        value: f32,
+        limit: Limit<f32>,
+    }
+}
+
+impl_schema! {
+    struct Limit<f32> {
+        min: f32,
+        max: f32,
+    }
+}
+
+impl_schema! {
+    enum Command {
+        Start,
+        Stop,
+        EmergencyStop,
+        ClearFault,
+        ResetToDefaults,
+    }
+}
+
+impl_schema! {
+    struct CommandAck {
+        command: Command,
+        accepted: bool,
+        state: AppState,
+    }
+}
+
+impl_schema! {
+    struct Report {
+        setpoint: Setpoint,
+        app_state: AppState,
+        measurements: Measurements,
+    }
+}
+
+impl_schema! {
+    struct Setpoint {
+        seq: u16,
+        mockloop_setpoint: Option<MockloopSetpoint>,
+        heart_controller_setpoint: Option<HeartControllerSetpoint>,
+    }
+}
+
+impl_schema! {
+    struct MockloopSetpoint {
+        systemic_resistance: HydraulicResistance,
+        pulmonary_resistance: HydraulicResistance,
+        systemic_afterload_compliance: HydraulicCompliance,
+        pulmonary_afterload_compliance: HydraulicCompliance,
+    }
+}
+
+impl_schema! {
+    struct HeartControllerSetpoint {
+        heart_rate: Frequency,
+        pressure: Pressure,
+        systole_ratio: f32,
+    }
+}
+
+impl_schema! {
+    struct Measurements {
+        timestamp: u64,
+        regulator_actual_pressure: Pressure,
+        systemic_flow: VolumeRate,
+        pulmonary_flow: VolumeRate,
+        systemic_preload_pressure: Pressure,
+        systemic_afterload_pressure: Pressure,
+        pulmonary_preload_pressure: Pressure,
+        pulmonary_afterload_pressure: Pressure,
+    }
+}
+
+impl_schema! {
+    enum AppState {
+        StandBy,
+        Running(u32),
+        Fault(Fault),
+    }
+}
+
+impl_schema! {
+    struct Fault {
+        kind: FaultKind,
+        timestamp: u64,
+        reading: Option<Reading>,
+    }
+}
+
+impl_schema! {
+    enum FaultKind {
+        OverPressure,
+        SensorDisconnected(Channel),
+        RegulatorTimeout,
+        CommunicationLoss,
+        InvalidSetpoint(Field),
+        Other(u16),
+        EmergencyStop,
+    }
+}
+
+impl_schema! {
+    struct Reading {
+        channel: Channel,
+        value: f32,
+    }
+}
+
+impl_schema! {
+    enum Channel {
+        RegulatorActualPressure,
+        SystemicFlow,
+        PulmonaryFlow,
+        SystemicPreloadPressure,
+        SystemicAfterloadPressure,
+        PulmonaryPreloadPressure,
+        PulmonaryAfterloadPressure,
+    }
+}
+
+impl_schema! {
+    enum Field {
+        HeartRate,
+        Pressure,
+        SystoleRatio,
+        SystemicResistance,
+        PulmonaryResistance,
+        SystemicAfterloadCompliance,
+        PulmonaryAfterloadCompliance,
+    }
+}
+
+impl_schema! {
+    struct CrateVersion {
+        major: u16,
+        minor: u16,
+        patch: u16,
+    }
+}
+
+impl_schema! {
+    struct Hello {
+        protocol_version: u16,
+        crate_version: CrateVersion,
+        schema_hash: u32,
+    }
+}
+
+impl_schema! {
+    struct HelloAck {
+        hello: Hello,
+        accepted: bool,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt;
+
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+
+    use super::*;
+
+    /// Field or variant names of an `impl_schema!` entry, in the listed order
+    pub(super) trait Members {
+        const MEMBERS: &'static [&'static str];
+    }
+
+    /// Stops deserializing at the first struct or enum, with its members in declaration order
+    #[derive(Debug)]
+    enum Probe {
+        Members(&'static [&'static str]),
+        Other,
+    }
+
+    impl fmt::Display for Probe {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{self:?}")
+        }
+    }
+
+    impl core::error::Error for Probe {}
+
+    impl de::Error for Probe {
+        fn custom<T: fmt::Display>(_: T) -> Self {
+            Probe::Other
+        }
+    }
+
+    struct Prober;
+
+    impl<'de> Deserializer<'de> for Prober {
+        type Error = Probe;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Probe> {
+            Err(Probe::Other)
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Probe> {
+            Err(Probe::Members(fields))
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            variants: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Probe> {
+            Err(Probe::Members(variants))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map identifier
+            ignored_any
+        }
+    }
+
+    fn assert_members<'de, T: Deserialize<'de> + Members>() {
+        let members = match T::deserialize(Prober) {
+            Err(Probe::Members(members)) => Some(members),
+            _ => None,
+        };
+        assert_eq!(
+            members,
+            Some(T::MEMBERS),
+            "impl_schema! entry of {} is out of order",
+            core::any::type_name::<T>()
+        );
+    }
+
+    #[test]
+    fn entries_list_members_in_wire_order() {
+        assert_members::<Message>();
+        assert_members::<Heartbeat>();
+        assert_members::<LinkConfig>();
+        assert_members::<FailSafePolicy>();
+        assert_members::<Ramp>();
+        assert_members::<SetpointAck>();
+        assert_members::<SetpointStatus>();
+        assert_members::<ValidationError>();
+        assert_members::<Violation>();
+        assert_members::<Limit<f32>>();
+        assert_members::<Command>();
+        assert_members::<CommandAck>();
+        assert_members::<Report>();
+        assert_members::<Setpoint>();
+        assert_members::<MockloopSetpoint>();
+        assert_members::<HeartControllerSetpoint>();
+        assert_members::<Measurements>();
+        assert_members::<AppState>();
+        assert_members::<Fault>();
+        assert_members::<FaultKind>();
+        assert_members::<Reading>();
+        assert_members::<Channel>();
+        assert_members::<Field>();
+        assert_members::<CrateVersion>();
+        assert_members::<Hello>();
+        assert_members::<HelloAck>();
+    }
+
+    #[test]
+    fn hash_depends_on_names_and_order() {
+        let ab = fnv1a(fnv1a(tag("struct S"), b"a"), b"b");
+        let ba = fnv1a(fnv1a(tag("struct S"), b"b"), b"a");
+        assert_ne!(ab, ba);
+        assert_ne!(
+            mix(tag("Option"), u32::SCHEMA_HASH),
+            mix(tag("Option"), u16::SCHEMA_HASH)
+        );
+        assert_ne!(Hello::SCHEMA_HASH, HelloAck::SCHEMA_HASH);
+    }
+}
@@ -0,0 +1,697 @@
+//! Host side session with a device.
+//!
+//! A [`Session`] owns the link: a background thread connects, performs the [`Handshake`], sends the
+//! [`LinkConfig`] and heartbeats, and reads every incoming message. The latest [`Report`] is kept,
+//! every message is forwarded to subscribers, and when the connection drops, e.g. because the
+//! USB-serial adapter was unplugged, it reconnects and handshakes again.
+//!
+//! The link is any [`Transport`] over a `std::io` stream, so a session runs against a serial port,
+//! TCP, [`host::loopback`] or a [`Simulator`](crate::simulator::Simulator) alike. The background
+//! thread owns the transport, messages sent through the session are queued for it.
+
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::string::String;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::command::{Command, CommandAck};
+use crate::delivery::{DeliveryError, SetpointAck, SetpointSender};
+use crate::frame::Checksum;
+use crate::handshake::{Handshake, HandshakeError, Hello};
+use crate::link::{Link, LinkConfig, LinkEvent};
+use crate::transport::host::{self, is_timeout};
+use crate::transport::{Transport, TransportError};
+use crate::{Message, Report, Setpoint};
+
+/// How long a receive may block, bounds how quickly the background thread sends queued messages
+/// and reacts to shutdown
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionConfig {
+    /// Frame checksum for [`Session::open`] and [`Session::open_tcp`], transports passed to
+    /// [`Session::connect`] bring their own
+    pub checksum: Checksum,
+    /// Sent to the device after every handshake
+    pub link: LinkConfig,
+    /// Resend the [`Hello`] when the device did not answer within this time
+    pub handshake_timeout: Duration,
+    /// Wait between failed connection attempts
+    pub reconnect_interval: Duration,
+    /// Retransmit a setpoint when it was not acknowledged within this time
+    pub ack_timeout: Duration,
+    /// Give up on a setpoint after this many transmissions
+    pub max_attempts: u8,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            checksum: Checksum::Crc16,
+            link: LinkConfig::default(),
+            handshake_timeout: Duration::from_secs(1),
+            reconnect_interval: Duration::from_millis(500),
+            ack_timeout: Duration::from_millis(200),
+            max_attempts: 3,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SessionStatus {
+    /// Connecting or waiting for the handshake to complete
+    Connecting,
+    /// Handshake completed with the device identifying itself as this
+    Connected(Hello),
+    /// The device is incompatible or refused us, the handshake is retried periodically
+    Failed(HandshakeError),
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    /// Not connected to a compatible device
+    Handshake(HandshakeError),
+    Delivery(DeliveryError),
+    /// No answer from the device in time
+    Timeout,
+    /// The connection dropped, a reconnect is in progress
+    Disconnected,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(err) => write!(f, "i/o error: {err}"),
+            SessionError::Handshake(err) => write!(f, "{err}"),
+            SessionError::Delivery(err) => write!(f, "{err}"),
+            SessionError::Timeout => write!(f, "device did not answer in time"),
+            SessionError::Disconnected => write!(f, "connection to device lost"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        SessionError::Io(err)
+    }
+}
+
+/// State shared between a [`Session`] and its background thread
+struct Shared {
+    config: SessionConfig,
+    started: Instant,
+    status: Mutex<SessionStatus>,
+    status_changed: Condvar,
+    latest_report: Mutex<Option<Report>>,
+    subscribers: Mutex<Vec<Sender<Message>>>,
+    shutdown: AtomicBool,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Shared {
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn set_status(&self, status: SessionStatus) {
+        *lock(&self.status) = status;
+        self.status_changed.notify_all();
+    }
+
+    fn publish(&self, message: &Message) {
+        if let Message::Report(report) = message {
+            *lock(&self.latest_report) = Some(report.clone());
+        }
+        lock(&self.subscribers).retain(|subscriber| subscriber.send(message.clone()).is_ok());
+    }
+}
+
+/// Client side of a device link, see the [module documentation](self)
+pub struct Session {
+    shared: Arc<Shared>,
+    /// Messages for the background thread to send
+    outbox: Sender<Message>,
+    setpoints: Mutex<SetpointSender>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    /// Open the serial port at `path` at [`BAUDRATE`](crate::BAUDRATE), see [`Session::connect`]
+    pub fn open(path: &str, config: SessionConfig) -> Result<Session, SessionError> {
+        let path = String::from(path);
+        Self::connect(
+            move || Ok(host::serial(path.as_str(), READ_TIMEOUT, config.checksum)?),
+            config,
+        )
+    }
+
+    /// Connect to a device or simulator listening on `addr`, see [`Session::connect`]
+    pub fn open_tcp(
+        addr: impl ToSocketAddrs + Send + 'static,
+        config: SessionConfig,
+    ) -> Result<Session, SessionError> {
+        Self::connect(
+            move || {
+                let transport = host::tcp(&addr, config.checksum)?;
+                transport.get_ref().0.set_read_timeout(Some(READ_TIMEOUT))?;
+                Ok(transport)
+            },
+            config,
+        )
+    }
+
+    /// Start a session over the transports returned by `connect`, which is called again to
+    /// reconnect whenever the connection drops. Receiving must fail with a timeout error after a
+    /// short while, otherwise queued messages are not sent until a message arrives, e.g. set
+    /// [`Loopback::set_read_timeout`](host::Loopback::set_read_timeout) on an in-memory transport.
+    ///
+    /// Waits for the first handshake to complete and fails when it does not within
+    /// [`SessionConfig::handshake_timeout`] or the device is incompatible
+    pub fn connect<T>(
+        connect: impl FnMut() -> io::Result<T> + Send + 'static,
+        config: SessionConfig,
+    ) -> Result<Session, SessionError>
+    where
+        T: Transport<Error = io::Error> + Send + 'static,
+    {
+        let session = Self::spawn(connect, config);
+
+        let deadline = Instant::now() + config.handshake_timeout;
+        let mut status = lock(&session.shared.status);
+        loop {
+            match *status {
+                SessionStatus::Connected(_) => break,
+                SessionStatus::Failed(err) => return Err(SessionError::Handshake(err)),
+                SessionStatus::Connecting => {}
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(SessionError::Timeout);
+            }
+            status = session
+                .shared
+                .status_changed
+                .wait_timeout(status, timeout)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        drop(status);
+        Ok(session)
+    }
+
+    /// Like [`Session::connect`], but returns right away and connects in the background
+    pub fn spawn<T>(
+        connect: impl FnMut() -> io::Result<T> + Send + 'static,
+        config: SessionConfig,
+    ) -> Session
+    where
+        T: Transport<Error = io::Error> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            config,
+            started: Instant::now(),
+            status: Mutex::new(SessionStatus::Connecting),
+            status_changed: Condvar::new(),
+            latest_report: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            shutdown: AtomicBool::new(false),
+        });
+        let (outbox, queued) = mpsc::channel();
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || run(&shared, connect, &queued))
+        };
+        Session {
+            shared,
+            outbox,
+            setpoints: Mutex::new(SetpointSender::new(
+                config.ack_timeout.as_millis() as u64,
+                config.max_attempts,
+            )),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        *lock(&self.shared.status)
+    }
+
+    /// Identity of the connected device
+    pub fn peer(&self) -> Option<Hello> {
+        match self.status() {
+            SessionStatus::Connected(hello) => Some(hello),
+            _ => None,
+        }
+    }
+
+    /// The most recent [`Report`], kept across reconnects
+    pub fn latest_report(&self) -> Option<Report> {
+        lock(&self.shared.latest_report).clone()
+    }
+
+    /// Receive a copy of every message from the device from now on
+    pub fn subscribe(&self) -> Receiver<Message> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.shared.subscribers).push(sender);
+        receiver
+    }
+
+    /// Iterate over every [`Report`] received from now on, blocks while waiting for the next one
+    pub fn reports(&self) -> impl Iterator<Item = Report> + use<> {
+        self.subscribe()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Report(report) => Some(report),
+                _ => None,
+            })
+    }
+
+    /// Queue `message` for the device, refused until the handshake completed. Messages still
+    /// queued when the connection drops are discarded
+    pub fn send(&self, message: &Message) -> Result<(), SessionError> {
+        match self.status() {
+            SessionStatus::Connected(_) => self
+                .outbox
+                .send(message.clone())
+                .map_err(|_| SessionError::Disconnected),
+            SessionStatus::Failed(err) => Err(SessionError::Handshake(err)),
+            SessionStatus::Connecting => {
+                Err(SessionError::Handshake(HandshakeError::NotEstablished))
+            }
+        }
+    }
+
+    /// Send `setpoint` and wait for the device to acknowledge it, retransmitting as configured.
+    /// Returns the acknowledgement holding the setpoint the device actually applied
+    pub fn send_setpoint(&self, setpoint: Setpoint) -> Result<SetpointAck, SessionError> {
+        let mut sender = lock(&self.setpoints);
+        let messages = self.subscribe();
+        self.send(&sender.send(setpoint, self.shared.now_ms()))?;
+
+        loop {
+            match messages.recv_timeout(self.shared.config.ack_timeout / 4) {
+                Ok(message) => {
+                    if let Some(result) = sender.handle(&message) {
+                        return result.map_err(SessionError::Delivery);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(SessionError::Disconnected),
+            }
+            match sender.poll(self.shared.now_ms()) {
+                Ok(Some(retransmit)) => self.send(&retransmit)?,
+                Ok(None) => {}
+                Err(err) => return Err(SessionError::Delivery(err)),
+            }
+        }
+    }
+
+    /// Send `command` and wait for its [`CommandAck`], check [`CommandAck::accepted`] for whether
+    /// the device executed it
+    pub fn command(&self, command: Command) -> Result<CommandAck, SessionError> {
+        let messages = self.subscribe();
+        self.send(&Message::Command(command))?;
+
+        let deadline = Instant::now() + self.shared.config.ack_timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match messages.recv_timeout(timeout) {
+                Ok(Message::CommandAck(ack)) if ack.command == command => return Ok(ack),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Err(SessionError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(SessionError::Disconnected),
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// An open connection as seen by the background thread
+struct Connection<T> {
+    transport: T,
+    handshake: Handshake,
+    hello_sent_ms: u64,
+    link: Link,
+}
+
+impl<T: Transport<Error = io::Error>> Connection<T> {
+    /// Send `message`, any error means the connection is unusable
+    fn send(&mut self, message: &Message) -> Result<(), SessionError> {
+        match self.transport.send(message) {
+            Ok(()) => Ok(()),
+            // Every message fits in a frame, not worth dropping the connection over
+            Err(TransportError::Encode(_)) => Ok(()),
+            Err(TransportError::Io(err)) => Err(SessionError::Io(err)),
+            Err(TransportError::Frame(_) | TransportError::Closed) => {
+                Err(SessionError::Disconnected)
+            }
+        }
+    }
+
+    /// The next message, `None` when receiving timed out or a corrupted frame was dropped
+    fn recv(&mut self) -> Result<Option<Message>, SessionError> {
+        match self.transport.recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TransportError::Io(err)) if is_timeout(&err) => Ok(None),
+            Err(TransportError::Frame(_)) => Ok(None),
+            Err(TransportError::Io(err)) => Err(SessionError::Io(err)),
+            Err(TransportError::Encode(_) | TransportError::Closed) => {
+                Err(SessionError::Disconnected)
+            }
+        }
+    }
+}
+
+/// Background thread: (re)connect, handshake, heartbeat, send queued messages and receive until
+/// shut down
+fn run<T: Transport<Error = io::Error>>(
+    shared: &Shared,
+    mut connect: impl FnMut() -> io::Result<T>,
+    queued: &Receiver<Message>,
+) {
+    let config = shared.config;
+    let mut connection: Option<Connection<T>> = None;
+
+    while !shared.shutdown.load(Ordering::Relaxed) {
+        let Some(conn) = &mut connection else {
+            match connect() {
+                Ok(transport) => {
+                    // Anything queued was meant for the previous connection
+                    while queued.try_recv().is_ok() {}
+                    let mut handshake = Handshake::new();
+                    let hello = handshake.start();
+                    let mut conn = Connection {
+                        transport,
+                        handshake,
+                        hello_sent_ms: shared.now_ms(),
+                        link: Link::new(config.link),
+                    };
+                    if conn.send(&hello).is_ok() {
+                        connection = Some(conn);
+                    }
+                }
+                Err(_) => thread::sleep(config.reconnect_interval),
+            }
+            continue;
+        };
+
+        let result = step(shared, conn, queued);
+        if result.is_err() {
+            connection = None;
+            let connected = matches!(*lock(&shared.status), SessionStatus::Connected(_));
+            if connected {
+                shared.set_status(SessionStatus::Connecting);
+            }
+        }
+    }
+}
+
+/// Send the queued messages and handle one receive, or one receive timeout, on an open connection
+fn step<T: Transport<Error = io::Error>>(
+    shared: &Shared,
+    conn: &mut Connection<T>,
+    queued: &Receiver<Message>,
+) -> Result<(), SessionError> {
+    let config = shared.config;
+
+    loop {
+        match queued.try_recv() {
+            Ok(message) => conn.send(&message)?,
+            Err(TryRecvError::Empty) => break,
+            // Only while the session is dropped
+            Err(TryRecvError::Disconnected) => return Err(SessionError::Disconnected),
+        }
+    }
+
+    if let Some(message) = conn.recv()? {
+        let now_ms = shared.now_ms();
+        conn.link.handle(&message, now_ms);
+        if !conn.handshake.is_established() {
+            match conn.handshake.handle(&message) {
+                Ok(Some(hello)) => {
+                    conn.send(&Message::LinkConfig(config.link))?;
+                    shared.set_status(SessionStatus::Connected(hello));
+                }
+                Ok(None) => {}
+                Err(err) => shared.set_status(SessionStatus::Failed(err)),
+            }
+        }
+        shared.publish(&message);
+    }
+
+    let now_ms = shared.now_ms();
+    if conn.handshake.is_established() {
+        if let Some(heartbeat) = conn.link.heartbeat(now_ms) {
+            conn.send(&heartbeat)?;
+        }
+        if let Some(LinkEvent::Lost) = conn.link.poll(now_ms) {
+            // The device went silent without the stream failing, e.g. a replugged adapter
+            return Err(SessionError::Disconnected);
+        }
+    } else if now_ms.saturating_sub(conn.hello_sent_ms)
+        >= config.handshake_timeout.as_millis() as u64
+    {
+        conn.hello_sent_ms = now_ms;
+        let hello = conn.handshake.start();
+        conn.send(&hello)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::f32::{Frequency, Pressure};
+    use uom::si::frequency::hertz;
+    use uom::si::pressure::millibar;
+
+    use super::*;
+    use crate::handshake::{HelloAck, PROTOCOL_VERSION};
+    use crate::simulator::{Injection, ScheduledInjection, Simulator, SimulatorConfig};
+    use crate::transport::Framed;
+    use crate::transport::host::Loopback;
+    use crate::{AppState, HeartControllerSetpoint, SYSTOLE_RATIO_DEFAULT};
+
+    const CHECKSUM: Checksum = Checksum::Crc16;
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            checksum: CHECKSUM,
+            reconnect_interval: Duration::from_millis(20),
+            ..SessionConfig::default()
+        }
+    }
+
+    /// Host end of a transport, receiving times out like a serial port
+    fn host_end(mut transport: Framed<Loopback>) -> Framed<Loopback> {
+        transport
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_millis(5)));
+        transport
+    }
+
+    /// Connect to every transport sent on the returned channel in turn
+    fn connect_to_each() -> (
+        Sender<Framed<Loopback>>,
+        impl FnMut() -> io::Result<Framed<Loopback>> + Send + 'static,
+    ) {
+        let (sender, receiver) = mpsc::channel();
+        let connect = move || match receiver.try_recv() {
+            Ok(transport) => Ok(host_end(transport)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => {
+                Err(io::ErrorKind::NotConnected.into())
+            }
+        };
+        (sender, connect)
+    }
+
+    fn simulated() -> (Session, Simulator) {
+        let (transports, connect) = connect_to_each();
+        let (host, simulator) = Simulator::loopback(SimulatorConfig::default(), CHECKSUM);
+        transports.send(host).unwrap();
+        (Session::connect(connect, config()).unwrap(), simulator)
+    }
+
+    fn wait_for_status(session: &Session, done: impl Fn(SessionStatus) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while !done(session.status()) {
+            assert!(Instant::now() < deadline, "stuck at {:?}", session.status());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Inject `injection` and wait for the device to start it on its next millisecond
+    fn inject(simulator: &Simulator, injection: ScheduledInjection) {
+        let start_ms = {
+            let mut device = simulator.device();
+            device.inject(injection).unwrap();
+            device.now_ms()
+        };
+        while simulator.device().now_ms() <= start_ms {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn setpoint(heart_rate: f32) -> Setpoint {
+        Setpoint {
+            heart_controller_setpoint: Some(HeartControllerSetpoint {
+                heart_rate: Frequency::new::<hertz>(heart_rate),
+                pressure: Pressure::new::<millibar>(200.0),
+                systole_ratio: SYSTOLE_RATIO_DEFAULT,
+            }),
+            ..Setpoint::default()
+        }
+    }
+
+    #[test]
+    fn handshakes_with_the_simulator() {
+        let (session, _simulator) = simulated();
+        assert_eq!(session.peer(), Some(Hello::local()));
+
+        let report = session.reports().next().unwrap();
+        assert_eq!(report.app_state, AppState::StandBy);
+        assert!(session.latest_report().is_some());
+    }
+
+    #[test]
+    fn fails_when_the_device_rejects_the_handshake() {
+        let (host, mut device) = host::loopback(CHECKSUM);
+        let remote = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::local()
+        };
+        let device = thread::spawn(move || {
+            while let Ok(message) = device.recv() {
+                if let Message::Hello(_) = message {
+                    let ack = HelloAck {
+                        hello: remote,
+                        accepted: false,
+                    };
+                    device.send(&Message::HelloAck(ack)).unwrap();
+                }
+            }
+        });
+
+        let mut host = Some(host);
+        let connect = move || {
+            host.take()
+                .map(host_end)
+                .ok_or_else(|| io::ErrorKind::NotConnected.into())
+        };
+        assert!(matches!(
+            Session::connect(connect, config()),
+            Err(SessionError::Handshake(HandshakeError::ProtocolMismatch { remote: r, .. }))
+                if r == remote
+        ));
+        // Dropping the session closes the transport and ends the device
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn setpoint_is_acknowledged() {
+        let (session, simulator) = simulated();
+        let ack = session.send_setpoint(setpoint(1.5)).unwrap();
+        assert_eq!(
+            ack.applied.heart_controller_setpoint,
+            setpoint(1.5).heart_controller_setpoint
+        );
+        assert_eq!(simulator.device().setpoint(), &ack.applied);
+    }
+
+    #[test]
+    fn setpoint_is_rejected() {
+        let (session, simulator) = simulated();
+        let active = simulator.device().setpoint().clone();
+        assert!(matches!(
+            session.send_setpoint(setpoint(10.0)),
+            Err(SessionError::Delivery(DeliveryError::Rejected { .. }))
+        ));
+        assert_eq!(simulator.device().setpoint(), &active);
+    }
+
+    #[test]
+    fn setpoint_is_retransmitted_until_acknowledged() {
+        let (session, simulator) = simulated();
+        let ack_timeout = config().ack_timeout;
+        let lost = ScheduledInjection::now(Injection::DropFrames { every: 1 })
+            .lasting(ack_timeout.as_millis() as u32 / 2);
+        inject(&simulator, lost);
+
+        let sent = Instant::now();
+        let ack = session.send_setpoint(setpoint(1.5)).unwrap();
+        // Only the retransmission got through
+        assert!(sent.elapsed() > ack_timeout / 2);
+        assert_eq!(simulator.device().setpoint(), &ack.applied);
+    }
+
+    #[test]
+    fn setpoint_times_out() {
+        let (session, simulator) = simulated();
+        let mute = ScheduledInjection::now(Injection::DropFrames { every: 1 });
+        inject(&simulator, mute);
+
+        assert!(matches!(
+            session.send_setpoint(setpoint(1.5)),
+            Err(SessionError::Delivery(DeliveryError::TimedOut {
+                attempts: 3,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn command_is_acknowledged() {
+        let (session, simulator) = simulated();
+        let ack = session.command(Command::Start).unwrap();
+        assert!(ack.accepted);
+        assert_eq!(ack.state, AppState::Running(1));
+        assert_eq!(simulator.device().state(), AppState::Running(1));
+
+        let ack = session.command(Command::ClearFault).unwrap();
+        assert!(!ack.accepted);
+        assert_eq!(ack.state, AppState::Running(1));
+    }
+
+    #[test]
+    fn reconnects_after_the_link_drops() {
+        let (transports, connect) = connect_to_each();
+        let (host, first) = Simulator::loopback(SimulatorConfig::default(), CHECKSUM);
+        transports.send(host).unwrap();
+        let session = Session::connect(connect, config()).unwrap();
+        session.command(Command::Start).unwrap();
+
+        // Unplugged: the device end closes
+        drop(first);
+        wait_for_status(&session, |status| status == SessionStatus::Connecting);
+        assert!(session.command(Command::Stop).is_err());
+
+        let (host, second) = Simulator::loopback(SimulatorConfig::default(), CHECKSUM);
+        transports.send(host).unwrap();
+        wait_for_status(&session, |status| {
+            matches!(status, SessionStatus::Connected(_))
+        });
+        // A fresh device, standing by
+        let ack = session.command(Command::Start).unwrap();
+        assert!(ack.accepted);
+        assert_eq!(second.device().state(), AppState::Running(1));
+    }
+}
@@ -0,0 +1,608 @@
+//! Software mockloop: the device side of the protocol on top of a simulated circulation.
+//!
+//! [`SimulatedDevice`] behaves like the firmware: it answers the handshake, executes
+//! [`Command`]s, validates and acknowledges [`Setpoint`]s, supervises the link and applies the
+//! [`FailSafePolicy`](crate::link::FailSafePolicy), and sends [`Report`]s with measurements from a
+//! [`Circulation`] model driven by the active setpoint. Time is passed in by the caller, so it runs
+//! in tests, on a host thread or even on a microcontroller. Faults are scripted with
+//! [`SimulatedDevice::inject`], see [`Injection`] for what can go wrong.
+//!
+//! With the `std` feature, [`Simulator`] runs a device on a background thread over any host
+//! transport. [`Simulator::loopback`] and [`Simulator::pty`] hand out the other end, so host
+//! applications cannot tell it from real hardware:
+//!
+//! ```ignore
+//! let (path, simulator) = Simulator::pty(SimulatorConfig::default(), Checksum::Crc16)?;
+//! let session = Session::open(&path, SessionConfig::default())?;
+//! ```
+
+mod circulation;
+#[cfg(feature = "std")]
+mod host;
+mod inject;
+
+pub use circulation::Circulation;
+#[cfg(feature = "std")]
+pub use host::Simulator;
+pub use inject::{Injection, MAX_INJECTIONS, ScheduledInjection};
+
+use inject::{DelayLine, Injections};
+use uom::si::f32::{Frequency, Pressure};
+use uom::si::frequency::hertz;
+use uom::si::pressure::millibar;
+
+use crate::command::{Command, CommandAck};
+use crate::delivery::SetpointAck;
+use crate::frame::Checksum;
+use crate::handshake::HelloAck;
+use crate::link::{FailSafeAction, Link, LinkConfig, LinkEvent};
+use crate::queue::OutboundQueue;
+use crate::state::{Event, StateMachine};
+use crate::units::{ComplianceUnit, ResistanceUnit};
+use crate::validation::SetpointLimits;
+use crate::{
+    AppState, Fault, HeartControllerSetpoint, MESSAGE_BYTES, Measurements, Message,
+    MockloopSetpoint, Report, SYSTOLE_RATIO_DEFAULT, Setpoint, serialize_message_checked,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimulatorConfig {
+    /// Limits incoming setpoints are checked against
+    pub limits: SetpointLimits,
+    /// Pull out of range setpoints to the limits instead of rejecting them
+    pub clamp: bool,
+    /// How often to send a [`Report`]
+    pub report_interval_ms: u32,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            limits: SetpointLimits::default(),
+            clamp: false,
+            report_interval_ms: 20,
+        }
+    }
+}
+
+/// Power-on setpoint of the simulated device, restored by [`Command::ResetToDefaults`]: 72 bpm,
+/// 250 mbar and a healthy adult circulation
+pub fn default_setpoint() -> Setpoint {
+    let wood_unit = ResistanceUnit::WoodUnit;
+    let ml_per_mmhg = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+    Setpoint {
+        seq: 0,
+        mockloop_setpoint: Some(MockloopSetpoint {
+            systemic_resistance: wood_unit.quantity(18.0),
+            pulmonary_resistance: wood_unit.quantity(2.0),
+            systemic_afterload_compliance: ml_per_mmhg.quantity(1.5),
+            pulmonary_afterload_compliance: ml_per_mmhg.quantity(4.0),
+        }),
+        heart_controller_setpoint: Some(HeartControllerSetpoint {
+            heart_rate: Frequency::new::<hertz>(1.2),
+            pressure: Pressure::new::<millibar>(250.0),
+            systole_ratio: SYSTOLE_RATIO_DEFAULT,
+        }),
+    }
+}
+
+/// Device side of the protocol, see the [module documentation](self).
+///
+/// Feed every received message to [`SimulatedDevice::handle`], call [`SimulatedDevice::poll`]
+/// periodically and send whatever [`SimulatedDevice::transmit_frame`] returns. Time is milliseconds
+/// since boot of the simulated device
+pub struct SimulatedDevice {
+    config: SimulatorConfig,
+    state: StateMachine,
+    /// Active setpoint, both controllers are always set
+    setpoint: Setpoint,
+    link: Link,
+    circulation: Circulation,
+    outbox: OutboundQueue<8>,
+    /// Time the circulation was integrated up to
+    now_ms: u64,
+    last_report_ms: Option<u64>,
+    /// Since when the link to the host is lost
+    lost_since_ms: Option<u64>,
+    injections: Injections,
+    delayed: DelayLine,
+    /// Frames handed out by [`SimulatedDevice::transmit`], including dropped ones
+    frames_sent: u32,
+}
+
+impl SimulatedDevice {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self {
+            config,
+            state: StateMachine::new(),
+            setpoint: default_setpoint(),
+            link: Link::new(LinkConfig::default()),
+            circulation: Circulation::new(),
+            outbox: OutboundQueue::new(),
+            now_ms: 0,
+            last_report_ms: None,
+            lost_since_ms: None,
+            injections: Injections::new(),
+            delayed: DelayLine::new(),
+            frames_sent: 0,
+        }
+    }
+
+    /// Time the simulation has advanced to
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// Schedule a fault, returns it back when [`MAX_INJECTIONS`] are scheduled already
+    pub fn inject(&mut self, injection: ScheduledInjection) -> Result<(), ScheduledInjection> {
+        self.injections.schedule(injection, self.now_ms)
+    }
+
+    /// End every injected fault, a latched [`AppState::Fault`] stays until the host clears it
+    pub fn clear_injections(&mut self) {
+        self.injections.clear();
+        self.circulation.set_regulator_failed(false);
+    }
+
+    pub fn state(&self) -> AppState {
+        self.state.state()
+    }
+
+    /// The active setpoint
+    pub fn setpoint(&self) -> &Setpoint {
+        &self.setpoint
+    }
+
+    pub fn circulation(&self) -> &Circulation {
+        &self.circulation
+    }
+
+    /// What the sensors read, including injected sensor faults
+    pub fn measurements(&self) -> Measurements {
+        let mut measurements = self.circulation.measurements(self.mockloop(), self.now_ms);
+        self.injections.apply(&mut measurements);
+        measurements
+    }
+
+    /// Process a message received at `now_ms`, answers are queued for [`SimulatedDevice::transmit`]
+    pub fn handle(&mut self, message: &Message, now_ms: u64) {
+        self.advance(now_ms);
+        if let Some(LinkEvent::Restored) = self.link.handle(message, now_ms) {
+            self.lost_since_ms = None;
+        }
+
+        let answer = match message {
+            Message::Hello(hello) => Some(Message::HelloAck(HelloAck::respond(hello))),
+            Message::Setpoint(setpoint) => Some(Message::SetpointAck(self.apply(setpoint))),
+            Message::Command(command) => Some(Message::CommandAck(self.execute(*command))),
+            Message::EmergencyStop => {
+                self.execute(Command::EmergencyStop);
+                None
+            }
+            // Adopted by the link, or only a sign of life
+            Message::LinkConfig(_) | Message::Heartbeat(_) => None,
+            // Device to host messages
+            Message::Report(_)
+            | Message::HelloAck(_)
+            | Message::CommandAck(_)
+            | Message::SetpointAck(_) => None,
+        };
+        if let Some(answer) = answer {
+            self.queue(answer);
+        }
+    }
+
+    /// Advance the simulation to `now_ms`, supervise the link and queue heartbeats and reports
+    /// that are due
+    pub fn poll(&mut self, now_ms: u64) {
+        self.advance(now_ms);
+        if let Some(LinkEvent::Lost) = self.link.poll(now_ms) {
+            self.lost_since_ms = Some(now_ms);
+        }
+        if let Some(FailSafeAction::StandBy) = self.fail_safe(now_ms) {
+            // Stop is only refused when not running, which is where the fail-safe leads anyway
+            let _ = self.state.handle(Event::Stop);
+        }
+
+        if let Some(heartbeat) = self.link.heartbeat(now_ms) {
+            self.queue(heartbeat);
+        }
+        let interval_ms = self.config.report_interval_ms as u64;
+        if self
+            .last_report_ms
+            .is_none_or(|last_ms| now_ms.saturating_sub(last_ms) >= interval_ms)
+        {
+            self.last_report_ms = Some(now_ms);
+            let report = Report {
+                setpoint: self.setpoint.clone(),
+                app_state: self.state(),
+                measurements: self.measurements(),
+            };
+            let delay_ms = self
+                .injections
+                .active()
+                .find_map(|injection| match injection {
+                    Injection::DelayReports { delay_ms } => Some(*delay_ms),
+                    _ => None,
+                });
+            match delay_ms {
+                Some(delay_ms) => self.delayed.push(now_ms + delay_ms as u64, report),
+                None => self.queue(Message::Report(report)),
+            }
+        }
+        while let Some(report) = self.delayed.pop_due(now_ms) {
+            self.queue(Message::Report(report));
+        }
+    }
+
+    /// The next message to send, most urgent first. Frames dropped by [`Injection::DropFrames`] are
+    /// skipped, [`Injection::CorruptFrames`] only applies to [`SimulatedDevice::transmit_frame`]
+    pub fn transmit(&mut self) -> Option<Message> {
+        loop {
+            let message = self.outbox.pop()?;
+            self.frames_sent = self.frames_sent.wrapping_add(1);
+            let dropped = self
+                .injections
+                .hits(self.frames_sent, |injection| match injection {
+                    Injection::DropFrames { every } => Some(*every),
+                    _ => None,
+                });
+            if !dropped {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Like [`SimulatedDevice::transmit`], encoded into a frame with `checksum`
+    pub fn transmit_frame<'a>(
+        &mut self,
+        checksum: Checksum,
+        buf: &'a mut [u8; MESSAGE_BYTES],
+    ) -> Option<&'a [u8]> {
+        let message = self.transmit()?;
+        let frame = serialize_message_checked(message, checksum, buf)
+            .expect("every message fits in MESSAGE_BYTES");
+        let corrupted = self
+            .injections
+            .hits(self.frames_sent, |injection| match injection {
+                Injection::CorruptFrames { every } => Some(*every),
+                _ => None,
+            });
+        if corrupted {
+            // Any byte but the delimiter, without introducing a new one
+            let byte = &mut frame[(frame.len() - 1) / 2];
+            *byte = byte.checked_add(1).unwrap_or(1);
+        }
+        Some(frame)
+    }
+
+    fn queue(&mut self, message: Message) {
+        // A full queue sheds the oldest report, the next one supersedes it
+        let _dropped = self.outbox.push(message);
+    }
+
+    fn mockloop(&self) -> &MockloopSetpoint {
+        self.setpoint
+            .mockloop_setpoint
+            .as_ref()
+            .expect("the active setpoint always holds both controllers")
+    }
+
+    fn heart(&self) -> &HeartControllerSetpoint {
+        self.setpoint
+            .heart_controller_setpoint
+            .as_ref()
+            .expect("the active setpoint always holds both controllers")
+    }
+
+    /// What the fail-safe policy asks for at `now_ms`, `None` while the link is up or the heart is
+    /// not pumping
+    fn fail_safe(&self, now_ms: u64) -> Option<FailSafeAction> {
+        let lost_since_ms = self.lost_since_ms?;
+        let AppState::Running(_) = self.state() else {
+            return None;
+        };
+        let action = self
+            .link
+            .config()
+            .fail_safe
+            .action(self.heart().pressure, now_ms.saturating_sub(lost_since_ms));
+        Some(action)
+    }
+
+    /// Integrate the circulation up to `now_ms` in 1 ms steps
+    fn advance(&mut self, now_ms: u64) {
+        while self.now_ms < now_ms {
+            self.now_ms += 1;
+            let measurements = self.circulation.measurements(self.mockloop(), self.now_ms);
+            let state = &mut self.state;
+            self.injections.update(self.now_ms, &measurements, |kind| {
+                let fault = Fault {
+                    kind,
+                    timestamp: measurements.timestamp,
+                    reading: None,
+                };
+                // Faulting is legal from every state
+                let _ = state.handle(Event::Fault(fault));
+            });
+            let regulator_failed = self
+                .injections
+                .active()
+                .any(|injection| *injection == Injection::RegulatorFailure);
+            self.circulation.set_regulator_failed(regulator_failed);
+
+            let mut heart = self.heart().clone();
+            let pumping = match self.state() {
+                AppState::Running(_) => match self.fail_safe(self.now_ms) {
+                    Some(FailSafeAction::Pressure(pressure)) => {
+                        heart.pressure = pressure;
+                        true
+                    }
+                    Some(FailSafeAction::StandBy) => false,
+                    Some(FailSafeAction::None) | None => true,
+                },
+                AppState::StandBy | AppState::Fault(_) => false,
+            };
+            let mockloop = *self.mockloop();
+            self.circulation
+                .advance(pumping.then_some(&heart), &mockloop, 1);
+        }
+    }
+
+    /// Validate `requested` and merge it into the active setpoint
+    fn apply(&mut self, requested: &Setpoint) -> SetpointAck {
+        let limits = &self.config.limits;
+        let validated = match self.config.clamp {
+            true => limits.clamp(requested.clone()),
+            false => limits.validate(requested.clone()),
+        };
+        let validated = match validated {
+            Ok(validated) => validated,
+            Err(err) => return SetpointAck::rejected(requested, err, self.setpoint.clone()),
+        };
+
+        let mut ack = SetpointAck::applied(requested, &validated);
+        let applied = validated.into_inner();
+        self.setpoint.seq = applied.seq;
+        if let Some(mockloop) = applied.mockloop_setpoint {
+            self.setpoint.mockloop_setpoint = Some(mockloop);
+        }
+        if let Some(heart) = applied.heart_controller_setpoint {
+            self.setpoint.heart_controller_setpoint = Some(heart);
+            // Report the new rate while running
+            if let AppState::Running(hz) = self.state()
+                && hz != self.heart_rate_hz()
+            {
+                let _ = self.state.handle(Event::Stop);
+                let _ = self.state.handle(Event::Start(self.heart_rate_hz()));
+            }
+        }
+        ack.applied = self.setpoint.clone();
+        ack
+    }
+
+    fn execute(&mut self, command: Command) -> CommandAck {
+        let ack = self
+            .state
+            .handle_command(command, self.heart_rate_hz(), self.now_ms);
+        if ack.accepted && command == Command::ResetToDefaults {
+            self.setpoint = default_setpoint();
+        }
+        ack
+    }
+
+    /// Active heart rate as reported in [`AppState::Running`]
+    fn heart_rate_hz(&self) -> u32 {
+        (self.heart().heart_rate.get::<hertz>() + 0.5) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::pressure::pascal;
+
+    use super::*;
+    use crate::delivery::SetpointStatus;
+    use crate::handshake::Hello;
+    use crate::link::{FailSafePolicy, Heartbeat, Ramp};
+    use crate::validation::Field;
+
+    /// Handle `message` at `now_ms` and return the answer, skipping reports and heartbeats
+    fn answer(device: &mut SimulatedDevice, message: Message, now_ms: u64) -> Option<Message> {
+        device.handle(&message, now_ms);
+        core::iter::from_fn(|| device.transmit())
+            .find(|message| !matches!(message, Message::Report(_) | Message::Heartbeat(_)))
+    }
+
+    fn heart(heart_rate: f32, pressure: f32) -> HeartControllerSetpoint {
+        HeartControllerSetpoint {
+            heart_rate: Frequency::new::<hertz>(heart_rate),
+            pressure: Pressure::new::<millibar>(pressure),
+            systole_ratio: SYSTOLE_RATIO_DEFAULT,
+        }
+    }
+
+    fn heart_only(seq: u16, heart: HeartControllerSetpoint) -> Message {
+        Message::Setpoint(Setpoint {
+            seq,
+            heart_controller_setpoint: Some(heart),
+            mockloop_setpoint: None,
+        })
+    }
+
+    fn heartbeat(now_ms: u64) -> Message {
+        Message::Heartbeat(Heartbeat {
+            counter: 0,
+            timestamp: now_ms,
+        })
+    }
+
+    /// Poll every millisecond of `from_ms..to_ms`, calling `check` after each
+    fn run(
+        device: &mut SimulatedDevice,
+        from_ms: u64,
+        to_ms: u64,
+        mut check: impl FnMut(&SimulatedDevice),
+    ) {
+        for now_ms in from_ms..to_ms {
+            device.poll(now_ms);
+            while device.transmit().is_some() {}
+            check(device);
+        }
+    }
+
+    fn regulator_mbar(device: &SimulatedDevice) -> f32 {
+        Pressure::new::<pascal>(device.measurements().regulator_actual_pressure.value)
+            .get::<millibar>()
+    }
+
+    /// A device running at the default setpoint since 0 with the host going silent after 1 s
+    fn running_with(fail_safe: FailSafePolicy) -> SimulatedDevice {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let link = LinkConfig {
+            heartbeat_interval_ms: 100,
+            timeout_ms: 500,
+            fail_safe,
+        };
+        device.handle(&Message::LinkConfig(link), 0);
+        answer(&mut device, Message::Command(Command::Start), 0).unwrap();
+        for now_ms in (0..=1_000).step_by(100) {
+            device.handle(&heartbeat(now_ms), now_ms);
+            run(&mut device, now_ms, now_ms + 100, |_| {});
+        }
+        device
+    }
+
+    #[test]
+    fn answers_hello() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let ack = answer(&mut device, Message::Hello(Hello::local()), 0);
+        assert!(matches!(
+            ack,
+            Some(Message::HelloAck(ack)) if ack.accepted && ack.hello == Hello::local()
+        ));
+    }
+
+    #[test]
+    fn merges_an_accepted_setpoint() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let Some(Message::SetpointAck(ack)) =
+            answer(&mut device, heart_only(7, heart(2.0, 300.0)), 0)
+        else {
+            panic!("no setpoint ack");
+        };
+
+        assert_eq!(ack.seq, 7);
+        assert_eq!(ack.status, SetpointStatus::Accepted);
+        assert_eq!(
+            ack.applied.heart_controller_setpoint,
+            Some(heart(2.0, 300.0))
+        );
+        // The mockloop was left unchanged
+        assert_eq!(
+            ack.applied.mockloop_setpoint,
+            default_setpoint().mockloop_setpoint
+        );
+        assert_eq!(device.setpoint(), &ack.applied);
+    }
+
+    #[test]
+    fn clamps_an_out_of_range_setpoint() {
+        let config = SimulatorConfig {
+            clamp: true,
+            ..SimulatorConfig::default()
+        };
+        let mut device = SimulatedDevice::new(config);
+        let Some(Message::SetpointAck(ack)) =
+            answer(&mut device, heart_only(1, heart(10.0, 300.0)), 0)
+        else {
+            panic!("no setpoint ack");
+        };
+
+        assert_eq!(ack.status, SetpointStatus::Clamped);
+        let applied = ack.applied.heart_controller_setpoint.as_ref().unwrap();
+        assert_eq!(applied.heart_rate, config.limits.heart_rate.max);
+        assert_eq!(applied.pressure, Pressure::new::<millibar>(300.0));
+        assert_eq!(device.setpoint(), &ack.applied);
+    }
+
+    #[test]
+    fn rejects_an_out_of_range_setpoint() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let Some(Message::SetpointAck(ack)) =
+            answer(&mut device, heart_only(1, heart(10.0, 300.0)), 0)
+        else {
+            panic!("no setpoint ack");
+        };
+
+        assert!(matches!(
+            ack.status,
+            SetpointStatus::Rejected(err) if err.is_violated(Field::HeartRate)
+        ));
+        assert_eq!(ack.applied, default_setpoint());
+        assert_eq!(device.setpoint(), &default_setpoint());
+    }
+
+    #[test]
+    fn starts_running() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let ack = answer(&mut device, Message::Command(Command::Start), 0);
+        assert!(matches!(
+            ack,
+            Some(Message::CommandAck(ack)) if ack.accepted && ack.state == AppState::Running(1)
+        ));
+
+        let mut peak_mbar = 0.0_f32;
+        run(&mut device, 0, 500, |device| {
+            peak_mbar = peak_mbar.max(regulator_mbar(device))
+        });
+        assert!(peak_mbar > 200.0);
+        // Keep the link up, it is lost after a second of silence
+        device.handle(&heartbeat(500), 500);
+        run(&mut device, 500, 1_000, |_| {});
+
+        device.poll(1_000);
+        let report = core::iter::from_fn(|| device.transmit()).find_map(|message| match message {
+            Message::Report(report) => Some(report),
+            _ => None,
+        });
+        assert_eq!(report.unwrap().app_state, AppState::Running(1));
+    }
+
+    #[test]
+    fn stands_by_after_link_loss() {
+        let mut device = running_with(FailSafePolicy::StandBy);
+        // The last heartbeat arrived at 1000
+        run(&mut device, 1_100, 1_500, |device| {
+            assert_eq!(device.state(), AppState::Running(1));
+        });
+        run(&mut device, 1_500, 1_501, |_| {});
+        assert_eq!(device.state(), AppState::StandBy);
+    }
+
+    #[test]
+    fn ramps_down_after_link_loss() {
+        let ramp = Ramp {
+            pressure: Pressure::new::<millibar>(50.0),
+            duration_ms: 1_000,
+        };
+        let mut device = running_with(FailSafePolicy::RampDown(ramp));
+
+        // Lost at 1500, the ramp ends at 2500
+        run(&mut device, 1_100, 2_600, |_| {});
+        let mut peak_mbar = 0.0_f32;
+        run(&mut device, 2_600, 4_000, |device| {
+            assert_eq!(device.state(), AppState::Running(1));
+            peak_mbar = peak_mbar.max(regulator_mbar(device));
+        });
+        assert!((45.0..51.0).contains(&peak_mbar), "{peak_mbar}");
+
+        // Back to the active setpoint once the host is heard from again
+        device.handle(&heartbeat(4_000), 4_000);
+        let mut peak_mbar = 0.0_f32;
+        run(&mut device, 4_000, 5_000, |device| {
+            peak_mbar = peak_mbar.max(regulator_mbar(device))
+        });
+        assert!(peak_mbar > 200.0);
+    }
+}
@@ -0,0 +1,306 @@
+//! Lumped parameter (Windkessel) model of the mockloop.
+//!
+//! Two pneumatically driven ventricles pump around a closed loop of compliance chambers:
+//!
+//! ```text
+//! systemic preload --> left ventricle --> systemic afterload ---R_s---+
+//!        ^                                                           |
+//!        |                                                           v
+//!        +---R_p--- pulmonary afterload <-- right ventricle <-- pulmonary preload
+//! ```
+//!
+//! Each side is named after the circulation its ventricle feeds: the systemic preload fills the
+//! left ventricle, which ejects into the systemic afterload. The afterload compliances and the
+//! resistances between afterload and preload come from the [`MockloopSetpoint`], the preload
+//! reservoirs, ventricles and valves are fixed. During systole the regulator pressure is applied to
+//! both ventricles on top of their passive filling pressure, during diastole it is vented.
+//!
+//! Internally everything is in mmHg, mL and s, which keeps the f32 state well conditioned.
+
+use defmt::Format;
+use uom::si::f32::{Pressure, VolumeRate};
+use uom::si::frequency::hertz;
+use uom::si::pressure::millimeter_of_mercury;
+use uom::si::volume_rate::milliliter_per_second;
+
+use crate::units::{ComplianceUnit, ResistanceUnit};
+use crate::{HeartControllerSetpoint, Measurements, MockloopSetpoint};
+
+/// Compliance of each preload reservoir, mL/mmHg
+const PRELOAD_COMPLIANCE: f32 = 20.0;
+/// Passive compliance of each ventricle while filling, mL/mmHg
+const VENTRICLE_COMPLIANCE: f32 = 10.0;
+/// Forward resistance of the inflow valves, mmHg·s/mL
+const INFLOW_RESISTANCE: f32 = 0.01;
+/// Forward resistance of the outflow valves and tubing, mmHg·s/mL, sets how long ejection takes
+const OUTFLOW_RESISTANCE: f32 = 0.1;
+/// Smallest afterload compliance and resistance used, keeps the integration stable for the zero
+/// values [`SetpointLimits`](crate::SetpointLimits) allows
+const MIN_COMPLIANCE: f32 = 0.2;
+const MIN_RESISTANCE: f32 = INFLOW_RESISTANCE;
+/// Time constant of the pressure regulator following its setpoint, s
+const REGULATOR_TIME_CONSTANT: f32 = 0.05;
+/// Integration steps per millisecond
+const STEPS_PER_MS: u32 = 4;
+
+/// State of the simulated mockloop, advanced with [`Circulation::advance`]
+#[derive(Clone, Copy, Format, Debug, PartialEq)]
+pub struct Circulation {
+    /// Position within the cardiac cycle, systole starts at 0, wraps at 1
+    phase: f32,
+    /// Actual regulator pressure, mmHg
+    regulator: f32,
+    /// The regulator is stuck at its pressure
+    regulator_failed: bool,
+    // Stressed volume of every chamber, mL
+    systemic_preload: f32,
+    left_ventricle: f32,
+    systemic_afterload: f32,
+    pulmonary_preload: f32,
+    right_ventricle: f32,
+    pulmonary_afterload: f32,
+    // Ventricular outflow during the last step, mL/s
+    systemic_flow: f32,
+    pulmonary_flow: f32,
+}
+
+impl Default for Circulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Circulation {
+    /// A loop at rest, filled to roughly physiological pressures
+    pub const fn new() -> Self {
+        Self {
+            phase: 0.0,
+            regulator: 0.0,
+            regulator_failed: false,
+            systemic_preload: 8.0 * PRELOAD_COMPLIANCE,
+            left_ventricle: 8.0 * VENTRICLE_COMPLIANCE,
+            systemic_afterload: 120.0,
+            pulmonary_preload: 5.0 * PRELOAD_COMPLIANCE,
+            right_ventricle: 5.0 * VENTRICLE_COMPLIANCE,
+            pulmonary_afterload: 60.0,
+            systemic_flow: 0.0,
+            pulmonary_flow: 0.0,
+        }
+    }
+
+    /// Integrate `dt_ms` milliseconds. `heart` is the setpoint the pneumatic heart is driven with,
+    /// `None` while it is not pumping
+    pub fn advance(
+        &mut self,
+        heart: Option<&HeartControllerSetpoint>,
+        mockloop: &MockloopSetpoint,
+        dt_ms: u32,
+    ) {
+        let resistance = ResistanceUnit::MillimeterOfMercurySecondPerMilliliter;
+        let compliance = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+        let afterload = Afterload {
+            systemic_resistance: resistance
+                .get(mockloop.systemic_resistance)
+                .max(MIN_RESISTANCE),
+            pulmonary_resistance: resistance
+                .get(mockloop.pulmonary_resistance)
+                .max(MIN_RESISTANCE),
+            systemic_compliance: compliance
+                .get(mockloop.systemic_afterload_compliance)
+                .max(MIN_COMPLIANCE),
+            pulmonary_compliance: compliance
+                .get(mockloop.pulmonary_afterload_compliance)
+                .max(MIN_COMPLIANCE),
+        };
+
+        let dt = 1.0e-3 / STEPS_PER_MS as f32;
+        for _ in 0..dt_ms * STEPS_PER_MS {
+            let target = match heart {
+                Some(heart) => {
+                    let systole = self.phase < heart.systole_ratio;
+                    self.phase += heart.heart_rate.get::<hertz>() * dt;
+                    if self.phase >= 1.0 {
+                        self.phase -= 1.0;
+                    }
+                    match systole {
+                        true => heart.pressure.get::<millimeter_of_mercury>(),
+                        false => 0.0,
+                    }
+                }
+                None => {
+                    self.phase = 0.0;
+                    0.0
+                }
+            };
+            if !self.regulator_failed {
+                self.regulator += (target - self.regulator) * dt / REGULATOR_TIME_CONSTANT;
+            }
+            self.step(&afterload, dt);
+        }
+    }
+
+    /// A failed regulator stays at its current pressure, whatever the heart controller asks for
+    pub fn set_regulator_failed(&mut self, failed: bool) {
+        self.regulator_failed = failed;
+    }
+
+    /// One explicit Euler step of `dt` seconds
+    fn step(&mut self, afterload: &Afterload, dt: f32) {
+        let systemic_preload = self.systemic_preload / PRELOAD_COMPLIANCE;
+        let left_ventricle = self.regulator + self.left_ventricle / VENTRICLE_COMPLIANCE;
+        let systemic_afterload = self.systemic_afterload / afterload.systemic_compliance;
+        let pulmonary_preload = self.pulmonary_preload / PRELOAD_COMPLIANCE;
+        let right_ventricle = self.regulator + self.right_ventricle / VENTRICLE_COMPLIANCE;
+        let pulmonary_afterload = self.pulmonary_afterload / afterload.pulmonary_compliance;
+
+        let left_filling = valve(systemic_preload, left_ventricle, INFLOW_RESISTANCE);
+        let right_filling = valve(pulmonary_preload, right_ventricle, INFLOW_RESISTANCE);
+        // The regulator pushes on a membrane, an empty ventricle has nothing left to eject
+        let left_ejection = valve(left_ventricle, systemic_afterload, OUTFLOW_RESISTANCE)
+            .min(self.left_ventricle / dt + left_filling);
+        let right_ejection = valve(right_ventricle, pulmonary_afterload, OUTFLOW_RESISTANCE)
+            .min(self.right_ventricle / dt + right_filling);
+        let systemic_runoff =
+            (systemic_afterload - pulmonary_preload) / afterload.systemic_resistance;
+        let pulmonary_runoff =
+            (pulmonary_afterload - systemic_preload) / afterload.pulmonary_resistance;
+
+        self.systemic_preload += (pulmonary_runoff - left_filling) * dt;
+        self.left_ventricle += (left_filling - left_ejection) * dt;
+        self.systemic_afterload += (left_ejection - systemic_runoff) * dt;
+        self.pulmonary_preload += (systemic_runoff - right_filling) * dt;
+        self.right_ventricle += (right_filling - right_ejection) * dt;
+        self.pulmonary_afterload += (right_ejection - pulmonary_runoff) * dt;
+        self.systemic_flow = left_ejection;
+        self.pulmonary_flow = right_ejection;
+    }
+
+    /// What the mockloop sensors read right now, `timestamp` in milliseconds since boot
+    pub fn measurements(&self, mockloop: &MockloopSetpoint, timestamp: u64) -> Measurements {
+        let compliance = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+        let mmhg = Pressure::new::<millimeter_of_mercury>;
+        let ml_per_s = VolumeRate::new::<milliliter_per_second>;
+        let systemic_compliance = compliance
+            .get(mockloop.systemic_afterload_compliance)
+            .max(MIN_COMPLIANCE);
+        let pulmonary_compliance = compliance
+            .get(mockloop.pulmonary_afterload_compliance)
+            .max(MIN_COMPLIANCE);
+
+        Measurements {
+            timestamp,
+            regulator_actual_pressure: mmhg(self.regulator),
+            systemic_flow: ml_per_s(self.systemic_flow),
+            pulmonary_flow: ml_per_s(self.pulmonary_flow),
+            systemic_preload_pressure: mmhg(self.systemic_preload / PRELOAD_COMPLIANCE),
+            systemic_afterload_pressure: mmhg(self.systemic_afterload / systemic_compliance),
+            pulmonary_preload_pressure: mmhg(self.pulmonary_preload / PRELOAD_COMPLIANCE),
+            pulmonary_afterload_pressure: mmhg(self.pulmonary_afterload / pulmonary_compliance),
+        }
+    }
+}
+
+/// [`MockloopSetpoint`] in model units
+struct Afterload {
+    systemic_resistance: f32,
+    pulmonary_resistance: f32,
+    systemic_compliance: f32,
+    pulmonary_compliance: f32,
+}
+
+/// Flow through a valve of forward `resistance` from `upstream` to `downstream` pressure, which
+/// never flows back
+fn valve(upstream: f32, downstream: f32, resistance: f32) -> f32 {
+    ((upstream - downstream) / resistance).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::pressure::pascal;
+
+    use super::*;
+    use crate::Channel;
+    use crate::simulator::default_setpoint;
+
+    /// The pressures in the loop, every channel but the regulator and the flows
+    const LOOP_PRESSURES: [Channel; 4] = [
+        Channel::SystemicPreloadPressure,
+        Channel::SystemicAfterloadPressure,
+        Channel::PulmonaryPreloadPressure,
+        Channel::PulmonaryAfterloadPressure,
+    ];
+
+    fn mmhg(measurements: &Measurements, channel: Channel) -> f32 {
+        Pressure::new::<pascal>(measurements.value(channel)).get::<millimeter_of_mercury>()
+    }
+
+    /// Advance `seconds` in 1 ms steps, checking the measurements after every step
+    fn run(
+        circulation: &mut Circulation,
+        heart: Option<&HeartControllerSetpoint>,
+        seconds: u32,
+        mut check: impl FnMut(&Measurements),
+    ) {
+        let mockloop = default_setpoint().mockloop_setpoint.unwrap();
+        for _ in 0..seconds * 1000 {
+            circulation.advance(heart, &mockloop, 1);
+            let measurements = circulation.measurements(&mockloop, 0);
+            for channel in Channel::ALL {
+                assert!(measurements.value(channel).is_finite(), "{channel:?}");
+            }
+            check(&measurements);
+        }
+    }
+
+    #[test]
+    fn pumping_settles_at_physiological_pressures() {
+        let heart = default_setpoint().heart_controller_setpoint.unwrap();
+        let mut circulation = Circulation::new();
+        run(&mut circulation, Some(&heart), 10, |_| {});
+
+        let mut systemic_afterload = (f32::MAX, f32::MIN);
+        let mut peak_flow = 0.0_f32;
+        run(&mut circulation, Some(&heart), 5, |measurements| {
+            let pressure = mmhg(measurements, Channel::SystemicAfterloadPressure);
+            systemic_afterload = (
+                systemic_afterload.0.min(pressure),
+                systemic_afterload.1.max(pressure),
+            );
+            for channel in [
+                Channel::SystemicPreloadPressure,
+                Channel::PulmonaryPreloadPressure,
+            ] {
+                assert!((0.0..20.0).contains(&mmhg(measurements, channel)));
+            }
+            assert!((5.0..40.0).contains(&mmhg(measurements, Channel::PulmonaryAfterloadPressure)));
+            for channel in [Channel::SystemicFlow, Channel::PulmonaryFlow] {
+                assert!(measurements.value(channel) >= 0.0);
+            }
+            peak_flow = peak_flow.max(measurements.value(Channel::SystemicFlow));
+        });
+
+        // Diastolic and systolic arterial pressure
+        assert!((50.0..90.0).contains(&systemic_afterload.0));
+        assert!((90.0..140.0).contains(&systemic_afterload.1));
+        assert!(peak_flow > 0.0);
+    }
+
+    #[test]
+    fn stopped_loop_settles_at_its_filling_pressure() {
+        let mut circulation = Circulation::new();
+        run(&mut circulation, None, 30, |measurements| {
+            for channel in [Channel::SystemicFlow, Channel::PulmonaryFlow] {
+                assert!(measurements.value(channel) < 1.0e-5);
+            }
+        });
+
+        let mockloop = default_setpoint().mockloop_setpoint.unwrap();
+        let measurements = circulation.measurements(&mockloop, 0);
+        for channel in LOOP_PRESSURES {
+            assert!(
+                (5.0..12.0).contains(&mmhg(&measurements, channel)),
+                "{channel:?}"
+            );
+        }
+    }
+}
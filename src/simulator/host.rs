@@ -0,0 +1,138 @@
+//! Runs a [`SimulatedDevice`] on a host thread.
+
+use std::io;
+use std::string::String;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{SimulatedDevice, SimulatorConfig};
+use crate::MESSAGE_BYTES;
+use crate::frame::Checksum;
+use crate::transport::host::{self, Loopback, is_timeout};
+use crate::transport::{Framed, Transport, TransportError};
+
+/// How long the device thread waits for a message before advancing the simulation
+const TICK: Duration = Duration::from_millis(1);
+
+/// A [`SimulatedDevice`] running on a background thread, stopped when dropped
+pub struct Simulator {
+    device: Arc<Mutex<SimulatedDevice>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    /// Host end of [`Simulator::pty`], kept open so a host closing the tty does not hang up the
+    /// device end
+    #[cfg(unix)]
+    _tty: Option<host::PtyTransport>,
+}
+
+impl Simulator {
+    /// Run `device` over `transport` until the simulator is stopped or the transport closes.
+    /// Receiving must time out after about a millisecond, the device is advanced in between
+    pub fn spawn<T>(device: SimulatedDevice, transport: Framed<T>) -> Simulator
+    where
+        T: embedded_io::Read + embedded_io::Write<Error = io::Error> + Send + 'static,
+    {
+        let device = Arc::new(Mutex::new(device));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let device = device.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || run(&device, transport, &shutdown))
+        };
+        Simulator {
+            device,
+            shutdown,
+            thread: Some(thread),
+            #[cfg(unix)]
+            _tty: None,
+        }
+    }
+
+    /// Simulate a device behind an in-memory transport, returns the host end
+    pub fn loopback(config: SimulatorConfig, checksum: Checksum) -> (Framed<Loopback>, Simulator) {
+        let (host, mut device) = host::loopback(checksum);
+        device.get_mut().set_read_timeout(Some(TICK));
+        (host, Self::spawn(SimulatedDevice::new(config), device))
+    }
+
+    /// Simulate a device behind a pseudo terminal, returns the tty path the host application
+    /// should open as its serial port. The path stays valid until the simulator is dropped, hosts
+    /// may close and reopen it
+    #[cfg(unix)]
+    pub fn pty(
+        config: SimulatorConfig,
+        checksum: Checksum,
+    ) -> serialport::Result<(String, Simulator)> {
+        use serialport::SerialPort;
+
+        let (mut device, host) = host::pty(checksum)?;
+        device.get_mut().0.set_timeout(TICK)?;
+        let path = host.get_ref().0.name().ok_or_else(|| {
+            serialport::Error::new(serialport::ErrorKind::NoDevice, "pty has no path")
+        })?;
+        let mut simulator = Self::spawn(SimulatedDevice::new(config), device);
+        simulator._tty = Some(host);
+        Ok((path, simulator))
+    }
+
+    /// Lock the running device, e.g. to [inject](SimulatedDevice::inject) faults or inspect its
+    /// state. The simulation stands still while locked. Device time counts from the spawn
+    pub fn device(&self) -> MutexGuard<'_, SimulatedDevice> {
+        self.device.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stop the device thread and return the device in its final state
+    pub fn stop(mut self) -> SimulatedDevice {
+        self.shutdown.store(true, Ordering::Relaxed);
+        let thread = self.thread.take().expect("only taken when stopping");
+        if let Err(panic) = thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+        let device = self.device.clone();
+        drop(self);
+        Arc::into_inner(device)
+            .expect("the device thread has exited")
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run<T>(device: &Mutex<SimulatedDevice>, mut transport: Framed<T>, shutdown: &AtomicBool)
+where
+    T: embedded_io::Read + embedded_io::Write<Error = io::Error>,
+{
+    let started = Instant::now();
+    let now_ms = || started.elapsed().as_millis() as u64;
+    let checksum = transport.checksum();
+    let mut buf = [0; MESSAGE_BYTES];
+
+    while !shutdown.load(Ordering::Relaxed) {
+        let received = transport.recv();
+        let mut device = device.lock().unwrap_or_else(|e| e.into_inner());
+        match received {
+            Ok(message) => device.handle(&message, now_ms()),
+            Err(TransportError::Io(err)) if is_timeout(&err) => {}
+            // Corrupted frames are dropped like the firmware does
+            Err(TransportError::Frame(_)) => {}
+            Err(_) => return,
+        }
+        device.poll(now_ms());
+        while let Some(frame) = device.transmit_frame(checksum, &mut buf) {
+            let io = transport.get_mut();
+            if io.write_all(frame).and_then(|()| io.flush()).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Scriptable fault injection for the [`SimulatedDevice`](super::SimulatedDevice).
+//!
+//! A script is a list of [`ScheduledInjection`]s, each starting at a device time and lasting for a
+//! while or until cleared:
+//!
+//! ```ignore
+//! let dropout = Injection::SensorDropout(Channel::SystemicFlow);
+//! device.inject(ScheduledInjection::at(1_000, dropout).lasting(500))?;
+//! device.inject(ScheduledInjection::at(3_000, Injection::Fault(FaultKind::OverPressure)))?;
+//! ```
+
+use defmt::Format;
+
+use crate::{Channel, FaultKind, Measurements, Reading, Report};
+
+/// How many injections can be scheduled at once
+pub const MAX_INJECTIONS: usize = 8;
+/// How many reports [`Injection::DelayReports`] holds back at most, the oldest is dropped beyond
+const DELAY_LINE_LEN: usize = 16;
+
+/// A fault the simulated device exhibits while the injection is active
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub enum Injection {
+    /// The sensor on this channel reads NaN
+    SensorDropout(Channel),
+    /// The sensor on this channel keeps reading the value it had when the injection started
+    SensorFrozen(Channel),
+    /// Add the value, in SI base units, to the reading of its channel, e.g. a pressure spike
+    Spike(Reading),
+    /// The pressure regulator is stuck at its current pressure and ignores the heart controller
+    RegulatorFailure,
+    /// Corrupt one byte of every `every`th frame sent, see
+    /// [`SimulatedDevice::transmit_frame`](super::SimulatedDevice::transmit_frame)
+    CorruptFrames { every: u16 },
+    /// Drop every `every`th frame sent, 1 drops all of them
+    DropFrames { every: u16 },
+    /// Hold reports back for `delay_ms` before sending them
+    DelayReports { delay_ms: u32 },
+    /// Enter [`AppState::Fault`](crate::AppState::Fault) with this cause when the injection
+    /// starts, like the firmware detecting it
+    Fault(FaultKind),
+}
+
+/// An [`Injection`] with the device time it is active
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub struct ScheduledInjection {
+    pub injection: Injection,
+    /// Device time to start at, milliseconds since boot. A start in the past starts right away
+    pub start_ms: u64,
+    /// How long the injection stays active, `None` until cleared. An [`Injection::Fault`] only
+    /// happens once and stays latched until the host clears it
+    pub duration_ms: Option<u32>,
+}
+
+impl ScheduledInjection {
+    /// Start right away and stay active until cleared
+    pub const fn now(injection: Injection) -> Self {
+        Self::at(0, injection)
+    }
+
+    /// Start at device time `start_ms` and stay active until cleared
+    pub const fn at(start_ms: u64, injection: Injection) -> Self {
+        Self {
+            injection,
+            start_ms,
+            duration_ms: None,
+        }
+    }
+
+    /// Stay active for `duration_ms` after starting
+    pub const fn lasting(mut self, duration_ms: u32) -> Self {
+        self.duration_ms = Some(duration_ms);
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    injection: Injection,
+    start_ms: u64,
+    end_ms: Option<u64>,
+    started: bool,
+    /// Reading captured for [`Injection::SensorFrozen`]
+    frozen: f32,
+}
+
+/// The injections scheduled on a device
+pub(super) struct Injections {
+    slots: [Option<Slot>; MAX_INJECTIONS],
+}
+
+impl Injections {
+    pub(super) const fn new() -> Self {
+        Self {
+            slots: [None; MAX_INJECTIONS],
+        }
+    }
+
+    /// Schedule `scheduled` at device time `now_ms`, returns it back when all slots are taken
+    pub(super) fn schedule(
+        &mut self,
+        scheduled: ScheduledInjection,
+        now_ms: u64,
+    ) -> Result<(), ScheduledInjection> {
+        let Some(slot) = self.slots.iter_mut().find(|slot| slot.is_none()) else {
+            return Err(scheduled);
+        };
+        let start_ms = scheduled.start_ms.max(now_ms);
+        *slot = Some(Slot {
+            injection: scheduled.injection,
+            start_ms,
+            end_ms: scheduled
+                .duration_ms
+                .map(|duration_ms| start_ms + duration_ms as u64),
+            started: false,
+            frozen: 0.0,
+        });
+        Ok(())
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.slots = [None; MAX_INJECTIONS];
+    }
+
+    /// Start and expire injections at `now_ms`, with `measurements` the true sensor values.
+    /// `fault` is called for every [`Injection::Fault`] that starts
+    pub(super) fn update(
+        &mut self,
+        now_ms: u64,
+        measurements: &Measurements,
+        mut fault: impl FnMut(FaultKind),
+    ) {
+        for entry in &mut self.slots {
+            let Some(slot) = entry else {
+                continue;
+            };
+            if slot.end_ms.is_some_and(|end_ms| now_ms >= end_ms) {
+                *entry = None;
+                continue;
+            }
+            if slot.started || now_ms < slot.start_ms {
+                continue;
+            }
+            slot.started = true;
+            match slot.injection {
+                Injection::SensorFrozen(channel) => slot.frozen = measurements.value(channel),
+                Injection::Fault(kind) => {
+                    fault(kind);
+                    *entry = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Every injection that is currently active
+    pub(super) fn active(&self) -> impl Iterator<Item = &Injection> {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|slot| slot.started)
+            .map(|slot| &slot.injection)
+    }
+
+    /// Apply the active sensor faults to `measurements`
+    pub(super) fn apply(&self, measurements: &mut Measurements) {
+        for slot in self.slots.iter().flatten().filter(|slot| slot.started) {
+            match slot.injection {
+                Injection::SensorDropout(channel) => measurements.set_value(channel, f32::NAN),
+                Injection::SensorFrozen(channel) => measurements.set_value(channel, slot.frozen),
+                Injection::Spike(reading) => {
+                    let value = measurements.value(reading.channel) + reading.value;
+                    measurements.set_value(reading.channel, value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether the active injections hit frame number `frame`, for the frame injection selected
+    /// by `every`
+    pub(super) fn hits(&self, frame: u32, every: impl Fn(&Injection) -> Option<u16>) -> bool {
+        self.active()
+            .filter_map(every)
+            .any(|every| frame.is_multiple_of(every.max(1) as u32))
+    }
+}
+
+/// Reports held back by [`Injection::DelayReports`] until they are due
+pub(super) struct DelayLine {
+    reports: [Option<(u64, Report)>; DELAY_LINE_LEN],
+}
+
+impl DelayLine {
+    pub(super) const fn new() -> Self {
+        Self {
+            reports: [const { None }; DELAY_LINE_LEN],
+        }
+    }
+
+    /// Hold `report` back until `due_ms`, dropping the report due first when full
+    pub(super) fn push(&mut self, due_ms: u64, report: Report) {
+        let index = self
+            .reports
+            .iter()
+            .position(Option::is_none)
+            .or_else(|| self.next_due())
+            .expect("the delay line is not empty");
+        self.reports[index] = Some((due_ms, report));
+    }
+
+    /// The next report due at `now_ms`, in the order they were due
+    pub(super) fn pop_due(&mut self, now_ms: u64) -> Option<Report> {
+        let index = self.next_due()?;
+        match &self.reports[index] {
+            Some((due_ms, _)) if *due_ms <= now_ms => self.reports[index].take().map(|(_, r)| r),
+            _ => None,
+        }
+    }
+
+    fn next_due(&self) -> Option<usize> {
+        self.reports
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|(due_ms, _)| (index, *due_ms)))
+            .min_by_key(|(_, due_ms)| *due_ms)
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::command::Command;
+    use crate::frame::Checksum;
+    use crate::link::{FailSafePolicy, LinkConfig};
+    use crate::simulator::{SimulatedDevice, SimulatorConfig, default_setpoint};
+    use crate::{AppState, MESSAGE_BYTES, Message, deserialize_message_checked};
+
+    /// A pumping device that keeps pumping without a host
+    fn running() -> SimulatedDevice {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let link = LinkConfig {
+            fail_safe: FailSafePolicy::Hold,
+            ..LinkConfig::default()
+        };
+        device.handle(&Message::LinkConfig(link), 0);
+        device.handle(&Message::Command(Command::Start), 0);
+        device
+    }
+
+    /// Poll every millisecond of `from_ms..to_ms`, returns what was transmitted and when
+    fn run(device: &mut SimulatedDevice, from_ms: u64, to_ms: u64) -> Vec<(u64, Message)> {
+        let mut sent = Vec::new();
+        for now_ms in from_ms..to_ms {
+            device.poll(now_ms);
+            sent.extend(core::iter::from_fn(|| device.transmit()).map(|m| (now_ms, m)));
+        }
+        sent
+    }
+
+    /// Reading of `channel` without injected sensor faults
+    fn true_value(device: &SimulatedDevice, channel: Channel) -> f32 {
+        let mockloop = default_setpoint().mockloop_setpoint.unwrap();
+        let measurements = device
+            .circulation()
+            .measurements(&mockloop, device.now_ms());
+        measurements.value(channel)
+    }
+
+    #[test]
+    fn sensor_dropout() {
+        let mut device = running();
+        let channel = Channel::SystemicFlow;
+        device
+            .inject(ScheduledInjection::at(100, Injection::SensorDropout(channel)).lasting(100))
+            .unwrap();
+
+        run(&mut device, 0, 150);
+        assert!(device.measurements().value(channel).is_nan());
+        run(&mut device, 150, 201);
+        assert!(!device.measurements().value(channel).is_nan());
+    }
+
+    #[test]
+    fn sensor_frozen() {
+        let mut device = running();
+        let channel = Channel::SystemicAfterloadPressure;
+        device
+            .inject(ScheduledInjection::at(100, Injection::SensorFrozen(channel)).lasting(500))
+            .unwrap();
+
+        run(&mut device, 0, 101);
+        let frozen = device.measurements().value(channel);
+        run(&mut device, 101, 600);
+        assert_eq!(device.measurements().value(channel), frozen);
+        assert_ne!(true_value(&device, channel), frozen);
+        run(&mut device, 600, 601);
+        assert_eq!(
+            device.measurements().value(channel),
+            true_value(&device, channel)
+        );
+    }
+
+    #[test]
+    fn spike() {
+        let mut device = running();
+        let spike = Reading {
+            channel: Channel::SystemicPreloadPressure,
+            value: 1_000.0,
+        };
+        device
+            .inject(ScheduledInjection::at(100, Injection::Spike(spike)).lasting(100))
+            .unwrap();
+
+        run(&mut device, 0, 150);
+        let offset =
+            device.measurements().value(spike.channel) - true_value(&device, spike.channel);
+        assert!((offset - spike.value).abs() < 0.01);
+        run(&mut device, 150, 201);
+        assert_eq!(
+            device.measurements().value(spike.channel),
+            true_value(&device, spike.channel)
+        );
+    }
+
+    #[test]
+    fn regulator_failure() {
+        let mut device = running();
+        let channel = Channel::RegulatorActualPressure;
+        // Fails during the first systole, at full pressure
+        device
+            .inject(ScheduledInjection::at(300, Injection::RegulatorFailure).lasting(1_000))
+            .unwrap();
+
+        run(&mut device, 0, 301);
+        let stuck = device.measurements().value(channel);
+        assert!(stuck > 20_000.0);
+        // Stays up through the diastoles
+        for now_ms in 301..1_300 {
+            run(&mut device, now_ms, now_ms + 1);
+            assert_eq!(device.measurements().value(channel), stuck);
+        }
+
+        let mut lowest = f32::MAX;
+        for now_ms in 1_300..2_300 {
+            run(&mut device, now_ms, now_ms + 1);
+            lowest = lowest.min(device.measurements().value(channel));
+        }
+        assert!(lowest < 1_000.0);
+    }
+
+    #[test]
+    fn drop_frames() {
+        let mut reference = running();
+        let mut device = running();
+        device
+            .inject(ScheduledInjection::at(100, Injection::DropFrames { every: 2 }).lasting(200))
+            .unwrap();
+
+        assert_eq!(
+            run(&mut device, 0, 100).len(),
+            run(&mut reference, 0, 100).len()
+        );
+        let sent = run(&mut reference, 100, 300).len();
+        assert_eq!(run(&mut device, 100, 300).len(), sent / 2);
+        assert_eq!(
+            run(&mut device, 300, 500).len(),
+            run(&mut reference, 300, 500).len()
+        );
+    }
+
+    #[test]
+    fn corrupt_frames() {
+        let mut device = running();
+        device
+            .inject(ScheduledInjection::at(100, Injection::CorruptFrames { every: 1 }).lasting(100))
+            .unwrap();
+
+        let mut buf = [0; MESSAGE_BYTES];
+        let mut frames = 0;
+        for now_ms in 0..300 {
+            device.poll(now_ms);
+            while let Some(frame) = device.transmit_frame(Checksum::Crc16, &mut buf) {
+                let mut frame = frame.to_vec();
+                let decoded = deserialize_message_checked(&mut frame, Checksum::Crc16);
+                assert_eq!(
+                    decoded.is_err(),
+                    (100..200).contains(&now_ms),
+                    "at {now_ms}"
+                );
+                frames += 1;
+            }
+        }
+        assert!(frames > 10);
+    }
+
+    #[test]
+    fn delay_reports() {
+        let mut device = running();
+        device
+            .inject(
+                ScheduledInjection::at(100, Injection::DelayReports { delay_ms: 100 }).lasting(200),
+            )
+            .unwrap();
+
+        let reports = run(&mut device, 0, 500)
+            .into_iter()
+            .filter_map(|(sent_ms, message)| match message {
+                Message::Report(report) => Some((sent_ms, report.measurements.timestamp)),
+                _ => None,
+            });
+        for (sent_ms, timestamp) in reports {
+            let delay_ms = match timestamp {
+                100..300 => 100,
+                _ => 0,
+            };
+            assert_eq!(sent_ms, timestamp + delay_ms);
+        }
+    }
+
+    #[test]
+    fn fault() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let fault = Injection::Fault(FaultKind::OverPressure);
+        device
+            .inject(ScheduledInjection::at(100, fault).lasting(200))
+            .unwrap();
+
+        run(&mut device, 0, 150);
+        assert!(matches!(
+            device.state(),
+            AppState::Fault(fault) if fault.kind == FaultKind::OverPressure && fault.timestamp == 100
+        ));
+        // Happens once, clearing it ends the fault for good
+        device.handle(&Message::Command(Command::ClearFault), 150);
+        run(&mut device, 150, 400);
+        assert_eq!(device.state(), AppState::StandBy);
+    }
+
+    #[test]
+    fn refuses_more_than_max_injections() {
+        let mut device = SimulatedDevice::new(SimulatorConfig::default());
+        let injection = ScheduledInjection::now(Injection::RegulatorFailure);
+        for _ in 0..MAX_INJECTIONS {
+            device.inject(injection).unwrap();
+        }
+        let extra = ScheduledInjection::at(50, Injection::DropFrames { every: 3 });
+        assert_eq!(device.inject(extra), Err(extra));
+
+        device.clear_injections();
+        assert_eq!(device.inject(extra), Ok(()));
+    }
+}
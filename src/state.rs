@@ -0,0 +1,172 @@
+//! Shared [`AppState`] state machine, so host and firmware agree on which transitions are legal.
+//!
+//! ```text
+//! StandBy --Start--> Running --Stop--> StandBy
+//! StandBy --ResetToDefaults--> StandBy
+//!    any  --Fault--> Fault   --ClearFault--> StandBy
+//! ```
+//!
+//! A fault can only be left through an explicit [`Event::ClearFault`], starting or stopping while
+//! faulted is refused.
+
+use core::fmt;
+
+use defmt::Format;
+
+use crate::{AppState, Fault};
+
+/// Requests that move an [`AppState`]
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub enum Event {
+    /// Start pumping at the given frequency in Hz
+    Start(u32),
+    Stop,
+    Fault(Fault),
+    ClearFault,
+    /// Restore default setpoints, does not change the state
+    ResetToDefaults,
+}
+
+/// `event` is not allowed in state `from`
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub struct IllegalTransition {
+    pub from: AppState,
+    pub event: Event,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = match self.event {
+            Event::Start(_) => "start",
+            Event::Stop => "stop",
+            Event::Fault(_) => "fault",
+            Event::ClearFault => "clear fault",
+            Event::ResetToDefaults => "reset to defaults",
+        };
+        match &self.from {
+            AppState::StandBy => write!(f, "cannot {event} while in standby"),
+            AppState::Running(hz) => write!(f, "cannot {event} while running at {hz} Hz"),
+            AppState::Fault(fault) => write!(f, "cannot {event} while faulted: {fault}"),
+        }
+    }
+}
+
+impl core::error::Error for IllegalTransition {}
+
+impl AppState {
+    /// The state after applying `event`, or why `event` is not allowed
+    pub fn transition(self, event: Event) -> Result<AppState, IllegalTransition> {
+        match (self, event) {
+            // Keep the original cause when faulting again
+            (AppState::Fault(fault), Event::Fault(_)) => Ok(AppState::Fault(fault)),
+            (_, Event::Fault(fault)) => Ok(AppState::Fault(fault)),
+            (AppState::StandBy, Event::Start(hz)) => Ok(AppState::Running(hz)),
+            (AppState::Running(_), Event::Stop) => Ok(AppState::StandBy),
+            (AppState::Fault(_), Event::ClearFault) => Ok(AppState::StandBy),
+            (AppState::StandBy, Event::ResetToDefaults) => Ok(AppState::StandBy),
+            (from, event) => Err(IllegalTransition { from, event }),
+        }
+    }
+}
+
+/// Owns the current [`AppState`] and only moves it along legal transitions
+#[derive(PartialEq, Clone, Copy, Format, Debug, Default)]
+pub struct StateMachine {
+    state: AppState,
+}
+
+impl StateMachine {
+    pub const fn new() -> Self {
+        Self {
+            state: AppState::StandBy,
+        }
+    }
+
+    pub fn state(&self) -> AppState {
+        self.state
+    }
+
+    /// Apply `event`, the state is left untouched when the transition is illegal
+    pub fn handle(&mut self, event: Event) -> Result<AppState, IllegalTransition> {
+        self.state = self.state.transition(event)?;
+        Ok(self.state)
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        matches!(self.state, AppState::Fault(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FaultKind;
+
+    fn fault(kind: FaultKind) -> Fault {
+        Fault {
+            kind,
+            timestamp: 1_000,
+            reading: None,
+        }
+    }
+
+    #[test]
+    fn runs_and_stops() {
+        let mut machine = StateMachine::new();
+        assert_eq!(machine.handle(Event::Start(2)), Ok(AppState::Running(2)));
+        assert_eq!(machine.handle(Event::Stop), Ok(AppState::StandBy));
+        assert_eq!(
+            machine.handle(Event::ResetToDefaults),
+            Ok(AppState::StandBy)
+        );
+    }
+
+    #[test]
+    fn refuses_illegal_transitions_without_changing_state() {
+        let mut machine = StateMachine::new();
+        assert_eq!(
+            machine.handle(Event::Stop),
+            Err(IllegalTransition {
+                from: AppState::StandBy,
+                event: Event::Stop
+            })
+        );
+        machine.handle(Event::Start(1)).unwrap();
+        assert!(machine.handle(Event::Start(2)).is_err());
+        assert!(machine.handle(Event::ResetToDefaults).is_err());
+        assert!(machine.handle(Event::ClearFault).is_err());
+        assert_eq!(machine.state(), AppState::Running(1));
+    }
+
+    #[test]
+    fn fault_latches_until_cleared() {
+        let mut machine = StateMachine::new();
+        machine.handle(Event::Start(1)).unwrap();
+        let first = fault(FaultKind::OverPressure);
+        machine.handle(Event::Fault(first)).unwrap();
+        assert!(machine.is_faulted());
+
+        // The original cause is kept
+        let second = fault(FaultKind::EmergencyStop);
+        assert_eq!(
+            machine.handle(Event::Fault(second)),
+            Ok(AppState::Fault(first))
+        );
+        for event in [Event::Start(1), Event::Stop, Event::ResetToDefaults] {
+            assert!(machine.handle(event).is_err());
+        }
+        assert_eq!(machine.handle(Event::ClearFault), Ok(AppState::StandBy));
+        assert!(!machine.is_faulted());
+    }
+
+    #[test]
+    fn any_state_can_fault() {
+        let emergency = fault(FaultKind::EmergencyStop);
+        for state in [AppState::StandBy, AppState::Running(1)] {
+            assert_eq!(
+                state.transition(Event::Fault(emergency)),
+                Ok(AppState::Fault(emergency))
+            );
+        }
+    }
+}
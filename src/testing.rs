@@ -0,0 +1,53 @@
+//! Helpers shared by the unit tests.
+
+use uom::si::f32::{Pressure, VolumeRate};
+use uom::si::pressure::pascal;
+use uom::si::volume_rate::cubic_meter_per_second;
+
+use crate::{AppState, Measurements, Report, Setpoint};
+
+/// A standby report at device time `timestamp` with every reading zero
+pub(crate) fn report(timestamp: u64) -> Report {
+    let pressure = Pressure::new::<pascal>(0.0);
+    let flow = VolumeRate::new::<cubic_meter_per_second>(0.0);
+    Report {
+        setpoint: Setpoint::default(),
+        app_state: AppState::StandBy,
+        measurements: Measurements {
+            timestamp,
+            regulator_actual_pressure: pressure,
+            systemic_flow: flow,
+            pulmonary_flow: flow,
+            systemic_preload_pressure: pressure,
+            systemic_afterload_pressure: pressure,
+            pulmonary_preload_pressure: pressure,
+            pulmonary_afterload_pressure: pressure,
+        },
+    }
+}
+
+/// Like [`report`] with every pressure at `pressure` pascal
+#[cfg(feature = "std")]
+pub(crate) fn report_with_pressure(timestamp: u64, pressure: f32) -> Report {
+    let mut report = report(timestamp);
+    for channel in crate::Channel::ALL
+        .into_iter()
+        .filter(|channel| !channel.is_flow())
+    {
+        report.measurements.set_value(channel, pressure);
+    }
+    report
+}
+
+/// A finished recording with a [`report`] at every timestamp
+#[cfg(feature = "std")]
+pub(crate) fn recording(timestamps: impl IntoIterator<Item = u64>) -> std::vec::Vec<u8> {
+    use crate::recording::{Header, Metadata, RecordingWriter};
+
+    let header = Header::new(None, Metadata::default());
+    let mut writer = RecordingWriter::new(std::vec::Vec::new(), &header).unwrap();
+    for timestamp in timestamps {
+        writer.write_report(&report(timestamp)).unwrap();
+    }
+    writer.finish().unwrap()
+}
@@ -0,0 +1,226 @@
+//! Message transports.
+//!
+//! A [`Transport`] sends and receives whole [`Message`]s. [`Framed`] implements it on top of any
+//! [`embedded_io`] byte stream, e.g. a firmware UART, and [`AsyncTransport`] on top of any
+//! [`embedded_io_async`] one. With the `std` feature, [`host`] provides an in-memory loopback pair,
+//! serial port, TCP and pty streams so the same host logic runs against hardware, a simulator or a
+//! test double.
+
+use core::fmt;
+
+use defmt::Format;
+
+use crate::accumulator::{FrameAccumulator, FrameError};
+use crate::frame::Checksum;
+use crate::{MESSAGE_BYTES, Message, serialize_message_checked};
+
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+#[cfg(feature = "std")]
+pub mod host;
+
+#[derive(Clone, Format, Debug, PartialEq, Eq)]
+pub enum TransportError<E> {
+    /// The underlying byte stream failed
+    Io(E),
+    /// The message does not fit in the transmit buffer
+    Encode(postcard::Error),
+    /// A received frame was dropped, the transport stays usable
+    Frame(FrameError),
+    /// The other end closed the stream
+    Closed,
+}
+
+impl<E: fmt::Debug> fmt::Display for TransportError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(err) => write!(f, "i/o error: {err:?}"),
+            TransportError::Encode(err) => write!(f, "failed to encode message: {err}"),
+            TransportError::Frame(err) => write!(f, "{err}"),
+            TransportError::Closed => write!(f, "stream closed"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for TransportError<E> {}
+
+/// Blocking message transport
+pub trait Transport {
+    type Error: fmt::Debug;
+
+    /// Send `message` as a single frame
+    fn send(&mut self, message: &Message) -> Result<(), TransportError<Self::Error>>;
+
+    /// Block until the next frame is received. A [`TransportError::Frame`] only means one frame was
+    /// dropped, keep receiving
+    fn recv(&mut self) -> Result<Message, TransportError<Self::Error>>;
+}
+
+/// Async message transport, see [`Transport`]
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport {
+    type Error: fmt::Debug;
+
+    /// Send `message` as a single frame
+    async fn send(&mut self, message: &Message) -> Result<(), TransportError<Self::Error>>;
+
+    /// Wait for the next frame. A [`TransportError::Frame`] only means one frame was dropped, keep
+    /// receiving
+    async fn recv(&mut self) -> Result<Message, TransportError<Self::Error>>;
+}
+
+/// Size of the chunks read from the underlying stream
+const RX_CHUNK_BYTES: usize = 64;
+
+/// Receive side state shared by the transports: the last chunk read from the stream and the
+/// accumulator it is fed to
+pub(crate) struct RxBuffer<const N: usize> {
+    accumulator: FrameAccumulator<N>,
+    chunk: [u8; RX_CHUNK_BYTES],
+    pos: usize,
+    len: usize,
+}
+
+impl<const N: usize> RxBuffer<N> {
+    pub(crate) const fn new(checksum: Checksum) -> Self {
+        Self {
+            accumulator: FrameAccumulator::new(checksum),
+            chunk: [0; RX_CHUNK_BYTES],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn accumulator(&self) -> &FrameAccumulator<N> {
+        &self.accumulator
+    }
+
+    /// Feed buffered bytes to the accumulator until a frame completes
+    pub(crate) fn next_frame<E>(&mut self) -> Option<Result<Message, TransportError<E>>> {
+        while self.pos < self.len {
+            let byte = self.chunk[self.pos];
+            self.pos += 1;
+            if let Some(frame) = self.accumulator.feed_byte(byte) {
+                return Some(frame.map_err(TransportError::Frame));
+            }
+        }
+        None
+    }
+
+    /// Buffer to read the next chunk into, once all buffered bytes are consumed
+    pub(crate) fn chunk_mut(&mut self) -> &mut [u8] {
+        &mut self.chunk
+    }
+
+    /// `len` bytes were read into [`RxBuffer::chunk_mut`], zero meaning end of stream
+    pub(crate) fn filled<E>(&mut self, len: usize) -> Result<(), TransportError<E>> {
+        if len == 0 {
+            return Err(TransportError::Closed);
+        }
+        self.pos = 0;
+        self.len = len;
+        Ok(())
+    }
+}
+
+/// Encode `message` into a frame in `buf`
+pub(crate) fn encode<'a, E>(
+    message: &Message,
+    checksum: Checksum,
+    buf: &'a mut [u8; MESSAGE_BYTES],
+) -> Result<&'a mut [u8], TransportError<E>> {
+    serialize_message_checked(message.clone(), checksum, buf).map_err(TransportError::Encode)
+}
+
+/// Frames [`Message`]s over a byte stream `T`, receiving frames of at most `N` bytes.
+///
+/// Implements [`Transport`] when `T` implements the blocking [`embedded_io`] traits and
+/// [`AsyncTransport`] when it implements the [`embedded_io_async`] ones
+pub struct Framed<T, const N: usize = MESSAGE_BYTES> {
+    io: T,
+    checksum: Checksum,
+    rx: RxBuffer<N>,
+}
+
+impl<T, const N: usize> Framed<T, N> {
+    pub const fn new(io: T, checksum: Checksum) -> Self {
+        Self {
+            io,
+            checksum,
+            rx: RxBuffer::new(checksum),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    pub fn accumulator(&self) -> &FrameAccumulator<N> {
+        self.rx.accumulator()
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+}
+
+impl<T: embedded_io::Read + embedded_io::Write, const N: usize> Transport for Framed<T, N> {
+    type Error = T::Error;
+
+    fn send(&mut self, message: &Message) -> Result<(), TransportError<T::Error>> {
+        let mut buf = [0; MESSAGE_BYTES];
+        let frame = encode(message, self.checksum, &mut buf)?;
+        self.io.write_all(frame).map_err(TransportError::Io)?;
+        self.io.flush().map_err(TransportError::Io)
+    }
+
+    fn recv(&mut self) -> Result<Message, TransportError<T::Error>> {
+        loop {
+            if let Some(frame) = self.rx.next_frame() {
+                return frame;
+            }
+            let len = self
+                .io
+                .read(self.rx.chunk_mut())
+                .map_err(TransportError::Io)?;
+            self.rx.filled(len)?;
+        }
+    }
+}
+
+impl<T: embedded_io_async::Read + embedded_io_async::Write, const N: usize> AsyncTransport
+    for Framed<T, N>
+{
+    type Error = T::Error;
+
+    async fn send(&mut self, message: &Message) -> Result<(), TransportError<T::Error>> {
+        let mut buf = [0; MESSAGE_BYTES];
+        let frame = encode(message, self.checksum, &mut buf)?;
+        self.io.write_all(frame).await.map_err(TransportError::Io)?;
+        self.io.flush().await.map_err(TransportError::Io)
+    }
+
+    async fn recv(&mut self) -> Result<Message, TransportError<T::Error>> {
+        loop {
+            if let Some(frame) = self.rx.next_frame() {
+                return frame;
+            }
+            let len = self
+                .io
+                .read(self.rx.chunk_mut())
+                .await
+                .map_err(TransportError::Io)?;
+            self.rx.filled(len)?;
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! [`tokio_util::codec`] support for host side async streaming.
+//!
+//! Wrap any `AsyncRead + AsyncWrite` in a `tokio_util::codec::Framed` with [`MessageCodec`] to get
+//! a `Stream` of [`Message`]s and a `Sink` accepting anything that converts into one, or with
+//! [`ReportCodec`] for a `Stream` of [`Report`]s and a `Sink` of [`Setpoint`]s:
+//!
+//! ```ignore
+//! let (mut sink, mut stream) = Framed::new(port, ReportCodec::new(Checksum::Crc16)).split();
+//! sink.send(setpoint).await?;
+//! loop {
+//!     match stream.next().await {
+//!         Some(Ok(report)) => handle(report),
+//!         Some(Err(CodecError::Io(err))) => return Err(err.into()),
+//!         Some(Err(err)) => {
+//!             log::warn!("dropped frame: {err}");
+//!             // Skip the `None` that follows every decoder error
+//!             stream.next().await;
+//!         }
+//!         None => break,
+//!     }
+//! }
+//! ```
+//!
+//! Like every `tokio_util` decoder error, a dropped frame ends the stream with `None` once. Polling
+//! it again resumes at the next frame, so a `while let Some(Ok(report))` loop stops at the first
+//! corrupted frame.
+
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::frame::{self, Checksum};
+use crate::{
+    MESSAGE_BYTES, Message, Report, Setpoint, deserialize_message_checked,
+    serialize_message_checked,
+};
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    /// No delimiter within `max` bytes, the frame is skipped up to the next delimiter
+    Oversized {
+        max: usize,
+    },
+    /// A complete frame failed to decode, e.g. garbage or a bad checksum
+    Decode(postcard::Error),
+    /// The message does not fit in a frame
+    Encode(postcard::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(err) => write!(f, "i/o error: {err}"),
+            CodecError::Oversized { max } => write!(f, "frame exceeds {max} bytes"),
+            CodecError::Decode(err) => write!(f, "failed to decode frame: {err}"),
+            CodecError::Encode(err) => write!(f, "failed to encode message: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+/// Frames [`Message`]s with the same postcard, checksum and COBS encoding as
+/// [`serialize_message_checked`]
+#[derive(Clone, Debug)]
+pub struct MessageCodec {
+    checksum: Checksum,
+    max_frame_bytes: usize,
+    /// Skipping the rest of an oversized frame
+    discarding: bool,
+}
+
+impl MessageCodec {
+    /// Accepts frames up to [`MESSAGE_BYTES`]
+    pub const fn new(checksum: Checksum) -> Self {
+        Self::with_max_frame_bytes(checksum, MESSAGE_BYTES)
+    }
+
+    pub const fn with_max_frame_bytes(checksum: Checksum, max_frame_bytes: usize) -> Self {
+        Self {
+            checksum,
+            max_frame_bytes,
+            discarding: false,
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        loop {
+            let Some(end) = src.iter().position(|&byte| byte == 0) else {
+                if self.discarding {
+                    discard_keeping_tail(src);
+                } else if src.len() > self.max_frame_bytes {
+                    discard_keeping_tail(src);
+                    self.discarding = true;
+                    return Err(CodecError::Oversized {
+                        max: self.max_frame_bytes,
+                    });
+                }
+                return Ok(None);
+            };
+
+            let mut frame = src.split_to(end + 1);
+            if self.discarding {
+                self.discarding = false;
+                // An emergency stop may have cut the oversized frame short
+                if !frame::is_emergency_stop(&frame) {
+                    continue;
+                }
+            }
+            if frame.len() == 1 {
+                // Back to back delimiters, nothing to decode
+                continue;
+            }
+            if end > self.max_frame_bytes && !frame::is_emergency_stop(&frame) {
+                return Err(CodecError::Oversized {
+                    max: self.max_frame_bytes,
+                });
+            }
+            return deserialize_message_checked(&mut frame, self.checksum)
+                .map(Some)
+                .map_err(CodecError::Decode);
+        }
+    }
+}
+
+/// Drop the bytes of an oversized frame, keeping enough of its end to still recognise an
+/// [`EMERGENCY_STOP_FRAME`](frame::EMERGENCY_STOP_FRAME) split across reads
+fn discard_keeping_tail(src: &mut BytesMut) {
+    let keep = frame::EMERGENCY_STOP_FRAME.len() - 1;
+    if src.len() > keep {
+        src.advance(src.len() - keep);
+    }
+}
+
+impl<T: Into<Message>> Encoder<T> for MessageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut buf = [0; MESSAGE_BYTES];
+        let frame = serialize_message_checked(item.into(), self.checksum, &mut buf)
+            .map_err(CodecError::Encode)?;
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// Host side [`MessageCodec`] that only yields [`Report`]s, other messages are skipped
+#[derive(Clone, Debug)]
+pub struct ReportCodec(MessageCodec);
+
+impl ReportCodec {
+    pub const fn new(checksum: Checksum) -> Self {
+        Self(MessageCodec::new(checksum))
+    }
+}
+
+impl Decoder for ReportCodec {
+    type Item = Report;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Report>, CodecError> {
+        while let Some(message) = self.0.decode(src)? {
+            if let Message::Report(report) = message {
+                return Ok(Some(report));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Setpoint> for ReportCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, setpoint: Setpoint, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.0.encode(setpoint, dst)
+    }
+}
+
+impl From<ReportCodec> for MessageCodec {
+    fn from(codec: ReportCodec) -> Self {
+        codec.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emergency_stop_split_across_reads_ends_oversized_frame() {
+        for checksum in [Checksum::None, Checksum::Crc16] {
+            let mut codec = MessageCodec::with_max_frame_bytes(checksum, 16);
+            let mut src = BytesMut::new();
+
+            src.extend_from_slice(&[0xAA; 20]);
+            assert!(matches!(
+                codec.decode(&mut src),
+                Err(CodecError::Oversized { max: 16 })
+            ));
+            src.extend_from_slice(&[0xAA; 30]);
+            assert!(matches!(codec.decode(&mut src), Ok(None)));
+
+            let (head, rest) = frame::EMERGENCY_STOP_FRAME.split_at(4);
+            src.extend_from_slice(head);
+            assert!(matches!(codec.decode(&mut src), Ok(None)));
+            src.extend_from_slice(rest);
+            assert!(matches!(
+                codec.decode(&mut src),
+                Ok(Some(Message::EmergencyStop))
+            ));
+            assert!(src.is_empty());
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Split async reader and writer for embassy firmware.
+//!
+//! UART drivers hand out separate rx and tx halves, [`MessageReader`] and [`MessageWriter`] frame
+//! [`Message`]s over each of them and can be connected to [`embassy_sync`] channels, so the
+//! application tasks only ever see decoded messages.
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embedded_io_async::{Read, Write};
+
+use super::{RxBuffer, TransportError, encode};
+use crate::accumulator::FrameAccumulator;
+use crate::frame::Checksum;
+use crate::{MESSAGE_BYTES, Message, Setpoint};
+
+/// Decodes [`Message`]s from an async byte stream, receiving frames of at most `N` bytes
+pub struct MessageReader<R, const N: usize = MESSAGE_BYTES> {
+    io: R,
+    rx: RxBuffer<N>,
+}
+
+impl<R: Read, const N: usize> MessageReader<R, N> {
+    pub const fn new(io: R, checksum: Checksum) -> Self {
+        Self {
+            io,
+            rx: RxBuffer::new(checksum),
+        }
+    }
+
+    pub fn accumulator(&self) -> &FrameAccumulator<N> {
+        self.rx.accumulator()
+    }
+
+    /// Wait for the next frame. A [`TransportError::Frame`] only means one frame was dropped, keep
+    /// reading
+    pub async fn read(&mut self) -> Result<Message, TransportError<R::Error>> {
+        loop {
+            if let Some(frame) = self.rx.next_frame() {
+                return frame;
+            }
+            let len = self
+                .io
+                .read(self.rx.chunk_mut())
+                .await
+                .map_err(TransportError::Io)?;
+            self.rx.filled(len)?;
+        }
+    }
+
+    /// Wait for the next frame that decodes, frames that do not are logged and skipped. Only fails
+    /// when the stream does
+    pub async fn read_valid(&mut self) -> Result<Message, TransportError<R::Error>> {
+        loop {
+            match self.read().await {
+                Err(TransportError::Frame(err)) => defmt::warn!("dropped frame: {}", err),
+                result => return result,
+            }
+        }
+    }
+
+    /// Send every received message into `sender`, waiting while the channel is full.
+    /// Runs until the stream fails, returns why
+    pub async fn forward<M: RawMutex, const CAP: usize>(
+        &mut self,
+        sender: Sender<'_, M, Message, CAP>,
+    ) -> TransportError<R::Error> {
+        loop {
+            match self.read_valid().await {
+                Ok(message) => sender.send(message).await,
+                Err(err) => return err,
+            }
+        }
+    }
+
+    /// Send every received [`Setpoint`] into `sender`, waiting while the channel is full, until
+    /// any other message arrives and is returned. Those need their own handling, in particular
+    /// [`Message::EmergencyStop`] and [`Command::EmergencyStop`](crate::Command::EmergencyStop),
+    /// call again afterwards to keep forwarding. Fails when the stream does
+    pub async fn forward_setpoints<M: RawMutex, const CAP: usize>(
+        &mut self,
+        sender: Sender<'_, M, Setpoint, CAP>,
+    ) -> Result<Message, TransportError<R::Error>> {
+        loop {
+            match self.read_valid().await? {
+                Message::Setpoint(setpoint) => sender.send(setpoint).await,
+                message => return Ok(message),
+            }
+        }
+    }
+}
+
+/// Encodes [`Message`]s onto an async byte stream
+pub struct MessageWriter<W> {
+    io: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub const fn new(io: W, checksum: Checksum) -> Self {
+        Self { io, checksum }
+    }
+
+    /// Send `message` as a single frame
+    pub async fn write(&mut self, message: &Message) -> Result<(), TransportError<W::Error>> {
+        let mut buf = [0; MESSAGE_BYTES];
+        let frame = encode(message, self.checksum, &mut buf)?;
+        self.io.write_all(frame).await.map_err(TransportError::Io)?;
+        self.io.flush().await.map_err(TransportError::Io)
+    }
+
+    /// Write every message received from `receiver`.
+    /// Runs until the stream fails, returns why
+    pub async fn forward_from<M: RawMutex, const CAP: usize>(
+        &mut self,
+        receiver: Receiver<'_, M, Message, CAP>,
+    ) -> TransportError<W::Error> {
+        loop {
+            let message = receiver.receive().await;
+            if let Err(err) = self.write(&message).await {
+                return err;
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Host side byte streams for [`Framed`]: an in-memory loopback pair, serial ports, TCP sockets
+//! and Unix ptys.
+
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use super::Framed;
+use crate::BAUDRATE;
+use crate::frame::Checksum;
+
+/// Adapts a [`std::io`] stream to the [`embedded_io`] traits
+pub struct FromStd<T>(pub T);
+
+impl<T> embedded_io::ErrorType for FromStd<T> {
+    type Error = io::Error;
+}
+
+impl<T: io::Read> embedded_io::Read for FromStd<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: io::Write> embedded_io::Write for FromStd<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct SharedPipe {
+    pipe: Mutex<Pipe>,
+    readable: Condvar,
+}
+
+impl SharedPipe {
+    fn close(&self) {
+        self.pipe.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of an in-memory byte stream, see [`loopback`]
+pub struct Loopback {
+    rx: Arc<SharedPipe>,
+    tx: Arc<SharedPipe>,
+    read_timeout: Option<Duration>,
+}
+
+impl Loopback {
+    /// Make reads fail with [`io::ErrorKind::TimedOut`] when nothing arrives within `timeout`, like
+    /// a serial port. `None`, the default, blocks until bytes arrive
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+impl embedded_io::ErrorType for Loopback {
+    type Error = io::Error;
+}
+
+impl embedded_io::Read for Loopback {
+    /// Blocks until bytes are available or the read timeout expires, returns 0 once the other end
+    /// is dropped
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut pipe = self.rx.pipe.lock().unwrap_or_else(|e| e.into_inner());
+        while pipe.bytes.is_empty() && !pipe.closed {
+            pipe = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    self.rx
+                        .readable
+                        .wait_timeout(pipe, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .rx
+                    .readable
+                    .wait(pipe)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        let len = buf.len().min(pipe.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.bytes.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl embedded_io::Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.tx.pipe.lock().unwrap_or_else(|e| e.into_inner());
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.bytes.extend(buf);
+        self.tx.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Two connected in-memory transports, whatever one sends the other receives. Use one end as a
+/// test double for the device
+pub fn loopback(checksum: Checksum) -> (Framed<Loopback>, Framed<Loopback>) {
+    let a = Arc::new(SharedPipe::default());
+    let b = Arc::new(SharedPipe::default());
+    (
+        Framed::new(
+            Loopback {
+                rx: a.clone(),
+                tx: b.clone(),
+                read_timeout: None,
+            },
+            checksum,
+        ),
+        Framed::new(
+            Loopback {
+                rx: b,
+                tx: a,
+                read_timeout: None,
+            },
+            checksum,
+        ),
+    )
+}
+
+/// Whether `err` only means a read timed out, the stream is still usable
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+pub type SerialTransport = Framed<FromStd<Box<dyn SerialPort>>>;
+
+/// Open the serial port at `path` at [`BAUDRATE`]. Receiving fails with a
+/// [`io::ErrorKind::TimedOut`] error when nothing arrives within `timeout`
+pub fn serial(
+    path: &str,
+    timeout: Duration,
+    checksum: Checksum,
+) -> serialport::Result<SerialTransport> {
+    let port = serialport::new(path, BAUDRATE).timeout(timeout).open()?;
+    Ok(Framed::new(FromStd(port), checksum))
+}
+
+pub type TcpTransport = Framed<FromStd<TcpStream>>;
+
+/// Connect to a device or simulator listening on `addr`
+pub fn tcp(addr: impl ToSocketAddrs, checksum: Checksum) -> io::Result<TcpTransport> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    Ok(Framed::new(FromStd(stream), checksum))
+}
+
+#[cfg(unix)]
+pub type PtyTransport = Framed<FromStd<serialport::TTYPort>>;
+
+/// A connected pseudo terminal pair. The second end has a device path (see
+/// [`serialport::SerialPort::name`]) that programs expecting a serial port can open, e.g. to
+/// run the host application against a simulator
+#[cfg(unix)]
+pub fn pty(checksum: Checksum) -> serialport::Result<(PtyTransport, PtyTransport)> {
+    let (master, slave) = serialport::TTYPort::pair()?;
+    Ok((
+        Framed::new(FromStd(master), checksum),
+        Framed::new(FromStd(slave), checksum),
+    ))
+}
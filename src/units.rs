@@ -0,0 +1,301 @@
+//! Hemodynamic quantities missing from [`uom::si`].
+//!
+//! Hydraulic resistance (pressure drop per flow) and compliance (volume change per pressure change)
+//! are plain `uom` quantities of the right dimension, so they fall out of `Pressure / VolumeRate`
+//! and `Volume / Pressure` and carry their units in the type. On the wire they are encoded like
+//! every other `uom` quantity: a single f32 in SI base units.
+//!
+//! `uom` only allows defining units for new dimensions inside its own crate, so conversion to the
+//! common clinical units goes through [`ResistanceUnit`] and [`ComplianceUnit`] instead.
+//! [`PressureUnit`], [`FlowUnit`] and [`FrequencyUnit`] select one of the `uom` units at runtime,
+//! e.g. for display.
+
+use core::marker::PhantomData;
+
+use defmt::Format;
+use uom::si::f32::{Frequency, Pressure, VolumeRate};
+use uom::si::{ISQ, Quantity, SI, frequency, pressure, volume_rate};
+use uom::typenum::{N1, N4, P1, P2, P4, Z0};
+
+/// Hydraulic resistance, L⁻⁴MT⁻¹ (base unit pascal second per cubic meter, Pa · s · m⁻³)
+pub type HydraulicResistance = Quantity<ISQ<N4, P1, N1, Z0, Z0, Z0, Z0>, SI<f32>, f32>;
+
+/// Hydraulic compliance, L⁴M⁻¹T² (base unit cubic meter per pascal, m³ · Pa⁻¹)
+pub type HydraulicCompliance = Quantity<ISQ<P4, N1, P2, Z0, Z0, Z0, Z0>, SI<f32>, f32>;
+
+/// Pascal per millimeter of mercury
+const MMHG: f32 = 133.322_39;
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+pub enum ResistanceUnit {
+    /// SI base unit, Pa · s / m³
+    #[default]
+    PascalSecondPerCubicMeter,
+    /// Peripheral resistance unit, mmHg · s / mL, common in lumped parameter models
+    MillimeterOfMercurySecondPerMilliliter,
+    /// Wood unit, mmHg · min / L, common in clinical practice
+    WoodUnit,
+    /// dyn · s / cm⁵
+    DyneSecondPerCentimeterToTheFifth,
+}
+
+impl ResistanceUnit {
+    /// Value of one of this unit in SI base units
+    pub const fn factor(self) -> f32 {
+        match self {
+            ResistanceUnit::PascalSecondPerCubicMeter => 1.0,
+            ResistanceUnit::MillimeterOfMercurySecondPerMilliliter => MMHG * 1.0e6,
+            ResistanceUnit::WoodUnit => MMHG * 60.0 * 1.0e3,
+            ResistanceUnit::DyneSecondPerCentimeterToTheFifth => 1.0e5,
+        }
+    }
+
+    pub const fn abbreviation(self) -> &'static str {
+        match self {
+            ResistanceUnit::PascalSecondPerCubicMeter => "Pa·s/m³",
+            ResistanceUnit::MillimeterOfMercurySecondPerMilliliter => "mmHg·s/mL",
+            ResistanceUnit::WoodUnit => "WU",
+            ResistanceUnit::DyneSecondPerCentimeterToTheFifth => "dyn·s/cm⁵",
+        }
+    }
+
+    /// Create a resistance of `value` in this unit
+    pub const fn quantity(self, value: f32) -> HydraulicResistance {
+        Quantity {
+            dimension: PhantomData,
+            units: PhantomData,
+            value: value * self.factor(),
+        }
+    }
+
+    /// Value of `resistance` expressed in this unit
+    pub const fn get(self, resistance: HydraulicResistance) -> f32 {
+        resistance.value / self.factor()
+    }
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+pub enum ComplianceUnit {
+    /// SI base unit, m³ / Pa
+    #[default]
+    CubicMeterPerPascal,
+    /// mL / mmHg, common in clinical practice and lumped parameter models
+    MilliliterPerMillimeterOfMercury,
+    /// mL / mbar
+    MilliliterPerMillibar,
+}
+
+impl ComplianceUnit {
+    /// Value of one of this unit in SI base units
+    pub const fn factor(self) -> f32 {
+        match self {
+            ComplianceUnit::CubicMeterPerPascal => 1.0,
+            ComplianceUnit::MilliliterPerMillimeterOfMercury => 1.0e-6 / MMHG,
+            ComplianceUnit::MilliliterPerMillibar => 1.0e-6 / 100.0,
+        }
+    }
+
+    pub const fn abbreviation(self) -> &'static str {
+        match self {
+            ComplianceUnit::CubicMeterPerPascal => "m³/Pa",
+            ComplianceUnit::MilliliterPerMillimeterOfMercury => "mL/mmHg",
+            ComplianceUnit::MilliliterPerMillibar => "mL/mbar",
+        }
+    }
+
+    /// Create a compliance of `value` in this unit
+    pub const fn quantity(self, value: f32) -> HydraulicCompliance {
+        Quantity {
+            dimension: PhantomData,
+            units: PhantomData,
+            value: value * self.factor(),
+        }
+    }
+
+    /// Value of `compliance` expressed in this unit
+    pub const fn get(self, compliance: HydraulicCompliance) -> f32 {
+        compliance.value / self.factor()
+    }
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+pub enum PressureUnit {
+    /// SI base unit
+    #[default]
+    Pascal,
+    Kilopascal,
+    /// Used by the pneumatic heart regulator
+    Millibar,
+    /// Common in clinical practice
+    MillimeterOfMercury,
+}
+
+impl PressureUnit {
+    pub const fn abbreviation(self) -> &'static str {
+        match self {
+            PressureUnit::Pascal => "Pa",
+            PressureUnit::Kilopascal => "kPa",
+            PressureUnit::Millibar => "mbar",
+            PressureUnit::MillimeterOfMercury => "mmHg",
+        }
+    }
+
+    /// Value of `pressure` expressed in this unit
+    pub fn get(self, pressure: Pressure) -> f32 {
+        match self {
+            PressureUnit::Pascal => pressure.get::<pressure::pascal>(),
+            PressureUnit::Kilopascal => pressure.get::<pressure::kilopascal>(),
+            PressureUnit::Millibar => pressure.get::<pressure::millibar>(),
+            PressureUnit::MillimeterOfMercury => pressure.get::<pressure::millimeter_of_mercury>(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+pub enum FlowUnit {
+    /// SI base unit, m³ / s
+    #[default]
+    CubicMeterPerSecond,
+    MilliliterPerSecond,
+    /// Cardiac output is usually given in L / min
+    LiterPerMinute,
+}
+
+impl FlowUnit {
+    pub const fn abbreviation(self) -> &'static str {
+        match self {
+            FlowUnit::CubicMeterPerSecond => "m³/s",
+            FlowUnit::MilliliterPerSecond => "mL/s",
+            FlowUnit::LiterPerMinute => "L/min",
+        }
+    }
+
+    /// Value of `flow` expressed in this unit
+    pub fn get(self, flow: VolumeRate) -> f32 {
+        match self {
+            FlowUnit::CubicMeterPerSecond => flow.get::<volume_rate::cubic_meter_per_second>(),
+            FlowUnit::MilliliterPerSecond => flow.get::<volume_rate::milliliter_per_second>(),
+            FlowUnit::LiterPerMinute => flow.get::<volume_rate::liter_per_minute>(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq, Eq, Default)]
+pub enum FrequencyUnit {
+    /// SI base unit
+    #[default]
+    Hertz,
+    /// Heart rate in beats per minute
+    PerMinute,
+}
+
+impl FrequencyUnit {
+    pub const fn abbreviation(self) -> &'static str {
+        match self {
+            FrequencyUnit::Hertz => "Hz",
+            FrequencyUnit::PerMinute => "1/min",
+        }
+    }
+
+    /// Value of `frequency` expressed in this unit
+    pub fn get(self, frequency: Frequency) -> f32 {
+        match self {
+            FrequencyUnit::Hertz => frequency.get::<frequency::hertz>(),
+            FrequencyUnit::PerMinute => frequency.get::<frequency::cycle_per_minute>(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::f32::Volume;
+    use uom::si::volume;
+
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1.0e-5,
+            "{actual} != {expected}"
+        );
+    }
+
+    #[test]
+    fn resistance_known_values() {
+        let wood = ResistanceUnit::WoodUnit.quantity(1.0);
+        assert_close(
+            ResistanceUnit::MillimeterOfMercurySecondPerMilliliter.get(wood),
+            0.06,
+        );
+        // 1 WU is 79.99 dyn·s/cm⁵, usually rounded to 80
+        assert_close(
+            ResistanceUnit::DyneSecondPerCentimeterToTheFifth.get(wood),
+            79.993_34,
+        );
+        assert_close(
+            ResistanceUnit::PascalSecondPerCubicMeter.get(wood),
+            7.999_343e6,
+        );
+
+        let pru = Pressure::new::<pressure::millimeter_of_mercury>(1.0)
+            / VolumeRate::new::<volume_rate::milliliter_per_second>(1.0);
+        assert_close(
+            ResistanceUnit::MillimeterOfMercurySecondPerMilliliter.factor(),
+            pru.value,
+        );
+        let dyn_s_cm5 = Pressure::new::<pressure::pascal>(0.1)
+            / VolumeRate::new::<volume_rate::cubic_centimeter_per_second>(1.0);
+        assert_close(
+            ResistanceUnit::DyneSecondPerCentimeterToTheFifth.factor(),
+            dyn_s_cm5.value,
+        );
+    }
+
+    #[test]
+    fn compliance_known_values() {
+        let clinical = Volume::new::<volume::milliliter>(1.0)
+            / Pressure::new::<pressure::millimeter_of_mercury>(1.0);
+        assert_close(
+            ComplianceUnit::MilliliterPerMillimeterOfMercury.factor(),
+            clinical.value,
+        );
+        assert_close(
+            ComplianceUnit::MilliliterPerMillimeterOfMercury.factor(),
+            7.500_617e-9,
+        );
+
+        let per_mbar =
+            Volume::new::<volume::milliliter>(1.0) / Pressure::new::<pressure::millibar>(1.0);
+        assert_close(
+            ComplianceUnit::MilliliterPerMillibar.factor(),
+            per_mbar.value,
+        );
+        assert_close(
+            ComplianceUnit::MilliliterPerMillimeterOfMercury.get(per_mbar),
+            MMHG / 100.0,
+        );
+    }
+
+    #[test]
+    fn units_round_trip() {
+        for unit in [
+            ResistanceUnit::PascalSecondPerCubicMeter,
+            ResistanceUnit::MillimeterOfMercurySecondPerMilliliter,
+            ResistanceUnit::WoodUnit,
+            ResistanceUnit::DyneSecondPerCentimeterToTheFifth,
+        ] {
+            for value in [0.0, 0.8, 1.0, 1200.0] {
+                assert_close(unit.get(unit.quantity(value)), value);
+            }
+        }
+        for unit in [
+            ComplianceUnit::CubicMeterPerPascal,
+            ComplianceUnit::MilliliterPerMillimeterOfMercury,
+            ComplianceUnit::MilliliterPerMillibar,
+        ] {
+            for value in [0.0, 0.5, 1.0, 2.3] {
+                assert_close(unit.get(unit.quantity(value)), value);
+            }
+        }
+    }
+}
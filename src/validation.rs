@@ -0,0 +1,529 @@
+//! Physical safety limits for setpoints.
+//!
+//! A [`Setpoint`] straight off the wire may hold anything, including negative heart rates or
+//! pressures that would damage the prototype. [`SetpointLimits::validate`] checks every field and
+//! only hands out a [`ValidatedSetpoint`] when all of them are in range.
+
+use core::fmt;
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    Dimension, Quantity, Units,
+    f32::{Frequency, Pressure},
+    frequency::hertz,
+    pressure::millibar,
+};
+
+use crate::frame::Checksum;
+use crate::units::{ComplianceUnit, HydraulicCompliance, HydraulicResistance, ResistanceUnit};
+use crate::{
+    HeartControllerSetpoint, Message, MockloopSetpoint, Setpoint, deserialize_message_checked,
+};
+
+/// Inclusive range a setpoint field must lie in
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, MaxSize)]
+pub struct Limit<T> {
+    pub min: T,
+    pub max: T,
+}
+
+impl<T: PartialOrd> Limit<T> {
+    pub const fn new(min: T, max: T) -> Self {
+        Self { min, max }
+    }
+
+    /// NaN is never contained
+    pub fn contains(&self, value: &T) -> bool {
+        self.min <= *value && *value <= self.max
+    }
+}
+
+impl<T: PartialOrd + Copy> Limit<T> {
+    /// The nearest value within the limit, `None` for NaN
+    pub fn clamp(&self, value: T) -> Option<T> {
+        if value < self.min {
+            Some(self.min)
+        } else if value > self.max {
+            Some(self.max)
+        } else if self.contains(&value) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Configurable limits for every [`Setpoint`] field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SetpointLimits {
+    pub heart_rate: Limit<Frequency>,
+    pub pressure: Limit<Pressure>,
+    pub systole_ratio: Limit<f32>,
+    pub systemic_resistance: Limit<HydraulicResistance>,
+    pub pulmonary_resistance: Limit<HydraulicResistance>,
+    pub systemic_afterload_compliance: Limit<HydraulicCompliance>,
+    pub pulmonary_afterload_compliance: Limit<HydraulicCompliance>,
+}
+
+impl Default for SetpointLimits {
+    /// Conservative limits for the pneumatic heart prototype
+    fn default() -> Self {
+        let wood_unit = ResistanceUnit::WoodUnit;
+        let ml_per_mmhg = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+        Self {
+            // 30 - 240 bpm
+            heart_rate: Limit::new(Frequency::new::<hertz>(0.5), Frequency::new::<hertz>(4.0)),
+            pressure: Limit::new(
+                Pressure::new::<millibar>(0.0),
+                Pressure::new::<millibar>(1000.0),
+            ),
+            systole_ratio: Limit::new(0.1, 0.9),
+            systemic_resistance: Limit::new(wood_unit.quantity(0.0), wood_unit.quantity(50.0)),
+            pulmonary_resistance: Limit::new(wood_unit.quantity(0.0), wood_unit.quantity(50.0)),
+            systemic_afterload_compliance: Limit::new(
+                ml_per_mmhg.quantity(0.0),
+                ml_per_mmhg.quantity(20.0),
+            ),
+            pulmonary_afterload_compliance: Limit::new(
+                ml_per_mmhg.quantity(0.0),
+                ml_per_mmhg.quantity(20.0),
+            ),
+        }
+    }
+}
+
+/// Setpoint field a [`Violation`] refers to
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, Eq, MaxSize)]
+pub enum Field {
+    HeartRate,
+    Pressure,
+    SystoleRatio,
+    SystemicResistance,
+    PulmonaryResistance,
+    SystemicAfterloadCompliance,
+    PulmonaryAfterloadCompliance,
+}
+
+impl Field {
+    pub const COUNT: usize = 7;
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Field::HeartRate => "heart_rate",
+            Field::Pressure => "pressure",
+            Field::SystoleRatio => "systole_ratio",
+            Field::SystemicResistance => "systemic_resistance",
+            Field::PulmonaryResistance => "pulmonary_resistance",
+            Field::SystemicAfterloadCompliance => "systemic_afterload_compliance",
+            Field::PulmonaryAfterloadCompliance => "pulmonary_afterload_compliance",
+        }
+    }
+
+    /// SI unit of the values in a [`Violation`] for this field
+    pub const fn unit(self) -> &'static str {
+        match self {
+            Field::HeartRate => "Hz",
+            Field::Pressure => "Pa",
+            Field::SystoleRatio => "",
+            Field::SystemicResistance | Field::PulmonaryResistance => "Pa·s/m³",
+            Field::SystemicAfterloadCompliance | Field::PulmonaryAfterloadCompliance => "m³/Pa",
+        }
+    }
+}
+
+/// A single out of range field, values are in the SI base unit of the field, see [`Field::unit`]
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, MaxSize)]
+pub struct Violation {
+    pub field: Field,
+    pub value: f32,
+    pub limit: Limit<f32>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = self.field.unit();
+        write!(
+            f,
+            "{} = {}{unit} outside [{}{unit}, {}{unit}]",
+            self.field.name(),
+            self.value,
+            self.limit.min,
+            self.limit.max,
+        )
+    }
+}
+
+/// Value in SI base units, used to report violations uniformly
+trait SiValue {
+    fn si(&self) -> f32;
+}
+
+impl SiValue for f32 {
+    fn si(&self) -> f32 {
+        *self
+    }
+}
+
+impl<D, U> SiValue for Quantity<D, U, f32>
+where
+    D: Dimension + ?Sized,
+    U: Units<f32> + ?Sized,
+{
+    fn si(&self) -> f32 {
+        self.value
+    }
+}
+
+/// Every limit violated by a setpoint
+#[derive(Deserialize, Serialize, Clone, Copy, Format, Debug, PartialEq, Default, MaxSize)]
+pub struct ValidationError {
+    pub(crate) violations: [Option<Violation>; Field::COUNT],
+}
+
+impl ValidationError {
+    pub fn violations(&self) -> impl Iterator<Item = &Violation> {
+        self.violations.iter().flatten()
+    }
+
+    pub fn is_violated(&self, field: Field) -> bool {
+        self.violations().any(|violation| violation.field == field)
+    }
+
+    /// Record a violation when `value` is out of `limit`, or pull it within `limit` when clamping
+    fn check<T: PartialOrd + Copy + SiValue>(
+        &mut self,
+        field: Field,
+        value: &mut T,
+        limit: &Limit<T>,
+        clamp: bool,
+    ) {
+        if limit.contains(value) {
+            return;
+        }
+        if let (true, Some(clamped)) = (clamp, limit.clamp(*value)) {
+            *value = clamped;
+            return;
+        }
+        if let Some(slot) = self.violations.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Violation {
+                field,
+                value: value.si(),
+                limit: Limit::new(limit.min.si(), limit.max.si()),
+            });
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.violations().next().is_none()
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "setpoint violates limits: ")?;
+        for (i, violation) in self.violations().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+/// A [`Setpoint`] that passed [`SetpointLimits::validate`] and is safe to apply
+#[derive(Clone, Debug, Format)]
+pub struct ValidatedSetpoint(Setpoint);
+
+impl ValidatedSetpoint {
+    pub fn get(&self) -> &Setpoint {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> Setpoint {
+        self.0
+    }
+}
+
+impl From<ValidatedSetpoint> for Setpoint {
+    fn from(validated: ValidatedSetpoint) -> Self {
+        validated.0
+    }
+}
+
+impl SetpointLimits {
+    pub fn validate(&self, setpoint: Setpoint) -> Result<ValidatedSetpoint, ValidationError> {
+        self.check(setpoint, false)
+    }
+
+    /// Like [`SetpointLimits::validate`], but pulls out of range fields to the nearest limit
+    /// instead of rejecting them. Only fields that cannot be clamped, i.e. NaN, are reported
+    pub fn clamp(&self, setpoint: Setpoint) -> Result<ValidatedSetpoint, ValidationError> {
+        self.check(setpoint, true)
+    }
+
+    fn check(
+        &self,
+        mut setpoint: Setpoint,
+        clamp: bool,
+    ) -> Result<ValidatedSetpoint, ValidationError> {
+        let mut err = ValidationError::default();
+
+        if let Some(sp) = &mut setpoint.heart_controller_setpoint {
+            err.check(
+                Field::HeartRate,
+                &mut sp.heart_rate,
+                &self.heart_rate,
+                clamp,
+            );
+            err.check(Field::Pressure, &mut sp.pressure, &self.pressure, clamp);
+            err.check(
+                Field::SystoleRatio,
+                &mut sp.systole_ratio,
+                &self.systole_ratio,
+                clamp,
+            );
+        }
+        if let Some(sp) = &mut setpoint.mockloop_setpoint {
+            err.check(
+                Field::SystemicResistance,
+                &mut sp.systemic_resistance,
+                &self.systemic_resistance,
+                clamp,
+            );
+            err.check(
+                Field::PulmonaryResistance,
+                &mut sp.pulmonary_resistance,
+                &self.pulmonary_resistance,
+                clamp,
+            );
+            err.check(
+                Field::SystemicAfterloadCompliance,
+                &mut sp.systemic_afterload_compliance,
+                &self.systemic_afterload_compliance,
+                clamp,
+            );
+            err.check(
+                Field::PulmonaryAfterloadCompliance,
+                &mut sp.pulmonary_afterload_compliance,
+                &self.pulmonary_afterload_compliance,
+                clamp,
+            );
+        }
+
+        match err.is_empty() {
+            true => Ok(ValidatedSetpoint(setpoint)),
+            false => Err(err),
+        }
+    }
+}
+
+/// Host side builder for setpoints that are checked before they are sent
+#[derive(Clone, Debug, Default)]
+pub struct SetpointBuilder {
+    setpoint: Setpoint,
+    limits: SetpointLimits,
+}
+
+impl SetpointBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn limits(mut self, limits: SetpointLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn heart_controller(mut self, setpoint: HeartControllerSetpoint) -> Self {
+        self.setpoint.heart_controller_setpoint = Some(setpoint);
+        self
+    }
+
+    pub fn mockloop(mut self, setpoint: MockloopSetpoint) -> Self {
+        self.setpoint.mockloop_setpoint = Some(setpoint);
+        self
+    }
+
+    pub fn build(self) -> Result<ValidatedSetpoint, ValidationError> {
+        self.limits.validate(self.setpoint)
+    }
+}
+
+#[derive(Clone, Debug, Format, PartialEq)]
+pub enum SetpointError {
+    Decode(postcard::Error),
+    /// The frame held a message other than a [`Setpoint`]
+    UnexpectedMessage,
+    Invalid(ValidationError),
+    /// The frame was the [`EMERGENCY_STOP_FRAME`](crate::frame::EMERGENCY_STOP_FRAME), stop immediately
+    EmergencyStop,
+}
+
+impl fmt::Display for SetpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetpointError::Decode(err) => write!(f, "failed to decode setpoint: {err}"),
+            SetpointError::UnexpectedMessage => write!(f, "frame does not hold a setpoint"),
+            SetpointError::Invalid(err) => write!(f, "{err}"),
+            SetpointError::EmergencyStop => write!(f, "emergency stop received"),
+        }
+    }
+}
+
+impl core::error::Error for SetpointError {}
+
+/// Firmware side: decode a setpoint frame and enforce `limits` before it can be applied.
+/// An emergency stop is reported as [`SetpointError::EmergencyStop`] whatever the `checksum`
+pub fn deserialize_validated_setpoint(
+    buf: &mut [u8],
+    checksum: Checksum,
+    limits: &SetpointLimits,
+) -> Result<ValidatedSetpoint, SetpointError> {
+    match deserialize_message_checked(buf, checksum).map_err(SetpointError::Decode)? {
+        Message::Setpoint(setpoint) => limits.validate(setpoint).map_err(SetpointError::Invalid),
+        Message::EmergencyStop => Err(SetpointError::EmergencyStop),
+        _ => Err(SetpointError::UnexpectedMessage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Heartbeat, MESSAGE_BYTES, serialize_message_checked};
+
+    fn heart(heart_rate: f32, pressure: f32, systole_ratio: f32) -> HeartControllerSetpoint {
+        HeartControllerSetpoint {
+            heart_rate: Frequency::new::<hertz>(heart_rate),
+            pressure: Pressure::new::<millibar>(pressure),
+            systole_ratio,
+        }
+    }
+
+    fn mockloop(resistance: f32, compliance: f32) -> MockloopSetpoint {
+        let wood_unit = ResistanceUnit::WoodUnit;
+        let ml_per_mmhg = ComplianceUnit::MilliliterPerMillimeterOfMercury;
+        MockloopSetpoint {
+            systemic_resistance: wood_unit.quantity(resistance),
+            pulmonary_resistance: wood_unit.quantity(resistance / 10.0),
+            systemic_afterload_compliance: ml_per_mmhg.quantity(compliance),
+            pulmonary_afterload_compliance: ml_per_mmhg.quantity(compliance),
+        }
+    }
+
+    #[test]
+    fn accepts_setpoint_within_limits() {
+        let validated = SetpointBuilder::new()
+            .heart_controller(heart(1.2, 200.0, 0.4))
+            .mockloop(mockloop(15.0, 1.5))
+            .build()
+            .unwrap();
+        assert_eq!(
+            validated.get().heart_controller_setpoint,
+            Some(heart(1.2, 200.0, 0.4))
+        );
+        // Nothing to check in an empty setpoint
+        assert!(
+            SetpointLimits::default()
+                .validate(Setpoint::default())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn reports_every_violation_in_si_units() {
+        let err = SetpointBuilder::new()
+            .heart_controller(heart(5.0, 200.0, 0.95))
+            .mockloop(mockloop(60.0, 1.5))
+            .build()
+            .unwrap_err();
+        assert_eq!(err.violations().count(), 3);
+        assert!(err.is_violated(Field::HeartRate));
+        assert!(err.is_violated(Field::SystoleRatio));
+        assert!(err.is_violated(Field::SystemicResistance));
+        assert!(!err.is_violated(Field::Pressure));
+
+        let heart_rate = err.violations().next().unwrap();
+        assert_eq!(heart_rate.value, 5.0);
+        assert_eq!(heart_rate.limit, Limit::new(0.5, 4.0));
+    }
+
+    #[test]
+    fn nan_is_never_valid() {
+        let setpoint = Setpoint {
+            heart_controller_setpoint: Some(heart(1.0, f32::NAN, 0.4)),
+            ..Setpoint::default()
+        };
+        let limits = SetpointLimits::default();
+        assert!(limits.validate(setpoint.clone()).is_err());
+        let err = limits.clamp(setpoint).unwrap_err();
+        assert!(err.is_violated(Field::Pressure));
+    }
+
+    #[test]
+    fn clamps_to_nearest_limit() {
+        let setpoint = Setpoint {
+            heart_controller_setpoint: Some(heart(0.1, 1500.0, 0.4)),
+            ..Setpoint::default()
+        };
+        let clamped = SetpointLimits::default().clamp(setpoint).unwrap();
+        assert_eq!(
+            clamped.get().heart_controller_setpoint,
+            Some(heart(0.5, 1000.0, 0.4))
+        );
+    }
+
+    #[test]
+    fn firmware_rejects_invalid_frames() {
+        let limits = SetpointLimits::default();
+        let mut buf = [0; MESSAGE_BYTES];
+
+        let valid = Setpoint {
+            heart_controller_setpoint: Some(heart(1.0, 100.0, 0.4)),
+            ..Setpoint::default()
+        };
+        let frame =
+            serialize_message_checked(Message::Setpoint(valid.clone()), Checksum::Crc16, &mut buf)
+                .unwrap();
+        let validated = deserialize_validated_setpoint(frame, Checksum::Crc16, &limits).unwrap();
+        assert_eq!(validated.into_inner(), valid);
+
+        let invalid = Setpoint {
+            heart_controller_setpoint: Some(heart(10.0, 100.0, 0.4)),
+            ..Setpoint::default()
+        };
+        let frame =
+            serialize_message_checked(Message::Setpoint(invalid), Checksum::Crc16, &mut buf)
+                .unwrap();
+        assert!(matches!(
+            deserialize_validated_setpoint(frame, Checksum::Crc16, &limits),
+            Err(SetpointError::Invalid(err)) if err.is_violated(Field::HeartRate)
+        ));
+
+        let heartbeat = Message::Heartbeat(Heartbeat {
+            counter: 0,
+            timestamp: 0,
+        });
+        let frame = serialize_message_checked(heartbeat, Checksum::Crc16, &mut buf).unwrap();
+        assert_eq!(
+            deserialize_validated_setpoint(frame, Checksum::Crc16, &limits).unwrap_err(),
+            SetpointError::UnexpectedMessage
+        );
+    }
+
+    #[test]
+    fn firmware_reports_emergency_stop() {
+        let limits = SetpointLimits::default();
+        for checksum in [Checksum::None, Checksum::Crc16, Checksum::Crc32] {
+            let mut frame = crate::frame::EMERGENCY_STOP_FRAME;
+            assert_eq!(
+                deserialize_validated_setpoint(&mut frame, checksum, &limits).unwrap_err(),
+                SetpointError::EmergencyStop
+            );
+        }
+    }
+}